@@ -0,0 +1,82 @@
+//! Measures commit latency and throughput with concurrent writers.
+//!
+//! Each writer repeatedly commits a small batch, synced on commit.
+//! With more writers the fs thread has more appends and syncs to group,
+//! so throughput should grow faster than latency.
+//!
+//! Run with `cargo bench --bench group_commit`.
+
+use anyhow::Result;
+use blocksy3 as db;
+use futures::executor::block_on;
+use futures::future;
+use std::time::{Duration, Instant};
+
+const COMMITS_PER_WRITER: usize = 50;
+
+fn main() -> Result<()> {
+    println!("{:>8} {:>12} {:>12} {:>12}", "writers", "commits/s", "p50", "p99");
+    for &writers in &[1, 4, 16, 64] {
+        let (elapsed, mut latencies) = block_on(run(writers))?;
+        latencies.sort();
+        let commits = latencies.len();
+        let throughput = commits as f64 / elapsed.as_secs_f64();
+        println!("{:>8} {:>12.0} {:>12?} {:>12?}",
+                 writers, throughput,
+                 percentile(&latencies, 50),
+                 percentile(&latencies, 99));
+    }
+    Ok(())
+}
+
+async fn run(writers: usize) -> Result<(Duration, Vec<Duration>)> {
+    let dir = std::env::temp_dir()
+        .join(format!("blocksy3-bench-group-commit-{}", writers));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let db = db::Db::open(db::DbConfig {
+        dir: Some(dir.clone()),
+        trees: vec!["t1".to_string(), "t2".to_string()],
+        log_format: db::LogFormat::Binary,
+        truncate_torn_writes: false,
+        log_segment_size: 1024 * 1024,
+        durability: db::Durability::SyncOnCommit,
+        mem_disk: None,
+        lock_timeout: None,
+        merge_operators: Default::default(),
+        rollback_to: None,
+        truncate_rollback: false,
+        index_checkpoint_interval: None,
+        subscription_capacity: 1024,
+    }).await?;
+
+    let start = Instant::now();
+    let writers = (0..writers).map(|writer| write(&db, writer));
+    let latencies = future::try_join_all(writers).await?;
+    let elapsed = start.elapsed();
+
+    drop(db);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    Ok((elapsed, latencies.into_iter().flatten().collect()))
+}
+
+async fn write(db: &db::Db, writer: usize) -> Result<Vec<Duration>> {
+    let mut latencies = vec![];
+    for i in 0..COMMITS_PER_WRITER {
+        let key = format!("{}-{}", writer, i);
+        let start = Instant::now();
+        let batch = db.write_batch().await?;
+        batch.tree("t1").write(key.as_bytes(), b"value").await?;
+        batch.tree("t2").write(key.as_bytes(), b"value").await?;
+        batch.commit().await?;
+        batch.close().await;
+        latencies.push(start.elapsed());
+    }
+    Ok(latencies)
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let idx = (sorted.len() * p / 100).min(sorted.len() - 1);
+    sorted[idx]
+}
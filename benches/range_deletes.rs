@@ -0,0 +1,113 @@
+//! Measures point reads and cursor scans as range deletes accumulate.
+//!
+//! Each round deletes more small ranges between the live keys,
+//! then reads every live key.
+//! Read time should grow much slower than the number of range deletes.
+//!
+//! The ranges are either disjoint, nested inside each other,
+//! or all overlapping each other.
+//! Time per delete should not grow with the number of range deletes
+//! however they overlap.
+//!
+//! Run with `cargo bench --bench range_deletes`.
+
+use anyhow::Result;
+use blocksy3 as db;
+use futures::executor::block_on;
+use std::time::{Duration, Instant};
+
+const KEYS: usize = 1000;
+
+#[derive(Debug, Copy, Clone)]
+enum Ranges {
+    Disjoint,
+    Nested,
+    Overlapping,
+}
+
+fn main() -> Result<()> {
+    println!("{:>12} {:>14} {:>12} {:>12} {:>12}", "ranges", "range deletes", "per delete", "per read", "per step");
+    for &ranges in &[Ranges::Disjoint, Ranges::Nested, Ranges::Overlapping] {
+        for &range_deletes in &[10, 100, 1000, 10000] {
+            let (delete, read, step) = block_on(run(ranges, range_deletes))?;
+            println!("{:>12} {:>14} {:>12?} {:>12?} {:>12?}", format!("{:?}", ranges), range_deletes, delete, read, step);
+        }
+    }
+    Ok(())
+}
+
+async fn run(ranges: Ranges, range_deletes: usize) -> Result<(Duration, Duration, Duration)> {
+    let db = db::Db::open(db::DbConfig {
+        dir: None,
+        trees: vec!["t1".to_string()],
+        log_format: db::LogFormat::Binary,
+        truncate_torn_writes: false,
+        log_segment_size: 1024 * 1024,
+        durability: db::Durability::Manual,
+        mem_disk: None,
+        lock_timeout: None,
+        merge_operators: Default::default(),
+        rollback_to: None,
+        truncate_rollback: false,
+        index_checkpoint_interval: None,
+        subscription_capacity: 1024,
+    }).await?;
+
+    let batch = db.write_batch().await?;
+    for i in 0..KEYS {
+        batch.tree("t1").write(live_key(i).as_bytes(), b"value").await?;
+    }
+    batch.commit().await?;
+    batch.close().await;
+
+    // Delete ranges of keys that were never written,
+    // so every range delete stays in the index
+    let start = Instant::now();
+    for i in 0..range_deletes {
+        let batch = db.write_batch().await?;
+        let (start, end) = match ranges {
+            Ranges::Disjoint => {
+                (format!("{}-a{:06}", live_key(i % KEYS), i),
+                 format!("{}-b{:06}", live_key(i % KEYS), i))
+            },
+            Ranges::Nested => {
+                (format!("{}-a{:06}", live_key(0), range_deletes - i),
+                 format!("{}-b{:06}", live_key(0), i))
+            },
+            Ranges::Overlapping => {
+                (format!("{}-a{:06}", live_key(0), i),
+                 format!("{}-b{:06}", live_key(0), i))
+            },
+        };
+        batch.tree("t1").delete_range(start.as_bytes(), end.as_bytes()).await?;
+        batch.commit().await?;
+        batch.close().await;
+    }
+    let delete = start.elapsed() / range_deletes as u32;
+
+    let view = db.read_view();
+    let tree = view.tree("t1");
+
+    let start = Instant::now();
+    for i in 0..KEYS {
+        assert!(tree.read(live_key(i).as_bytes()).await?.is_some());
+    }
+    let read = start.elapsed() / KEYS as u32;
+
+    let start = Instant::now();
+    let mut cursor = tree.cursor()?;
+    let mut steps = 0;
+    cursor.seek_first();
+    while cursor.valid() {
+        steps += 1;
+        cursor.next();
+    }
+    assert_eq!(steps, KEYS);
+    let step = start.elapsed() / KEYS as u32;
+
+    Ok((delete, read, step))
+}
+
+fn live_key(i: usize) -> String {
+    format!("k{:06}", i)
+}
@@ -0,0 +1,56 @@
+//! Check the logs of an on-disk database without opening it.
+//!
+//!     cargo run --example verify -- <dir> [<tree>...]
+//!
+//! Trees only need naming for databases from before the manifest.
+
+use std::env;
+use std::path::PathBuf;
+use anyhow::{Result, bail, anyhow};
+
+use blocksy3 as db;
+use futures::executor::block_on;
+
+fn main() -> Result<()> {
+    block_on(run())
+}
+
+async fn run() -> Result<()> {
+    env_logger::init();
+
+    let mut args = env::args().skip(1);
+    let dir = PathBuf::from(args.next().ok_or_else(|| anyhow!("usage: verify <dir> [<tree>...]"))?);
+    let trees = args.collect();
+
+    let binary_commits = db::raw::simple_log_file::segment_path(&dir, "commits", db::LogFormat::Binary, 0);
+    let log_format = if binary_commits.exists() {
+        db::LogFormat::Binary
+    } else {
+        db::LogFormat::Toml
+    };
+
+    let config = db::DbConfig {
+        dir: Some(dir),
+        trees,
+        log_format,
+        truncate_torn_writes: false,
+        log_segment_size: 1024 * 1024,
+        durability: db::Durability::Manual,
+        mem_disk: None,
+        lock_timeout: None,
+        merge_operators: Default::default(),
+        rollback_to: None,
+        truncate_rollback: false,
+        index_checkpoint_interval: None,
+        subscription_capacity: 1024,
+    };
+
+    let report = db::verify(&config).await?;
+    print!("{}", report);
+
+    if !report.is_ok() {
+        bail!("{} problems found", report.problems.len());
+    }
+
+    Ok(())
+}
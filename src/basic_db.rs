@@ -1,15 +1,24 @@
 use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
 use futures::lock::{Mutex, MutexGuard};
-use std::sync::Arc;
+use futures::future;
+use std::sync::{Arc, RwLock};
 use std::collections::BTreeMap;
 use std::path::PathBuf;
-use crate::tree::{self, Tree};
-use anyhow::{Result, Context, anyhow};
-use crate::types::{Batch, BatchCommit, Commit, Key, Value};
+use crate::compacting_tree::{self, CompactingTree};
+use crate::tree_logs::TreeLogs;
+use anyhow::{Result, Context, anyhow, bail};
+use crate::types::{Batch, BatchCommit, Commit, Key, KeyRange, Value};
 use crate::commit_log::{CommitLog, CommitCommand};
+use crate::manifest::Manifest;
 use crate::command::Command;
 use crate::log::Log;
 use crate::loader;
+use crate::index_checkpoint::{IndexCheckpoints, IndexCheckpoint, TreeCheckpoint};
+use crate::merge::MergeOperator;
+use crate::pending_writes::{self, PendingWrites};
+use crate::changes::{Change, ChangeBatch, Subscribers};
+use async_channel::Receiver;
+use futures::{Sink, SinkExt, StreamExt};
 use std::fmt;
 
 pub struct Db {
@@ -18,37 +27,117 @@ pub struct Db {
     next_batch_commit: Arc<AtomicU64>,
     next_commit: Arc<AtomicU64>,
     view_commit_limit: Arc<AtomicU64>,
+    /// The latest horizon of garbage collection,
+    /// before which views can't be created
+    gc_horizon: AtomicU64,
     commit_lock: Arc<Mutex<()>>,
-    trees: Arc<BTreeMap<String, Tree>>,
+    /// Replaced whole when trees are created or dropped,
+    /// so batches and views keep the trees they started with
+    trees: RwLock<Arc<Trees>>,
     commit_log: Arc<CommitLog>,
+    manifest: Manifest,
+    /// Locked while a checkpoint is taken and written
+    index_checkpoints: Mutex<IndexCheckpoints>,
+    live_views: Arc<LiveViews>,
+    subscribers: Arc<Subscribers>,
+}
+
+/// Each tree, and the first batch that may write to it.
+type Trees = BTreeMap<String, (Arc<CompactingTree>, Batch)>;
+
+/// The number of live views at each commit limit.
+type LiveViews = std::sync::Mutex<BTreeMap<Commit, usize>>;
+
+/// Counts a view as live until dropped.
+struct ViewRegistration {
+    commit_limit: Commit,
+    live_views: Arc<LiveViews>,
 }
 
 pub struct BatchWriter {
     batch: Batch,
-    batch_writers: BTreeMap<String, tree::BatchWriter>,
+    /// The trees that existed when the batch was created
+    trees: Arc<Trees>,
+    /// The trees written by the batch, opened on first use
+    tree_writers: Mutex<TreeWriters>,
     next_batch_commit: Arc<AtomicU64>,
     next_commit: Arc<AtomicU64>,
     view_commit_limit: Arc<AtomicU64>,
     commit_lock: Arc<Mutex<()>>,
     commit_log: Arc<CommitLog>,
+    subscribers: Arc<Subscribers>,
+}
+
+#[derive(Default)]
+struct TreeWriters {
+    writers: BTreeMap<String, Arc<compacting_tree::BatchWriter>>,
+    /// Save points pushed by the batch,
+    /// which are pushed to each tree as it is opened.
+    save_points: Vec<SavePoint>,
+    conditions: Vec<Condition>,
+    /// The batch's changes to each tree, for subscribers
+    changes: Vec<(String, Change)>,
+}
+
+/// The number of conditions and changes when a save point was pushed.
+struct SavePoint {
+    conditions: usize,
+    changes: usize,
+}
+
+/// A value a key must have when the batch commits.
+struct Condition {
+    tree: String,
+    key: Key,
+    expected: Option<Value>,
+}
+
+/// The keys a transaction read from a snapshot,
+/// which must not have changed by the time it commits.
+#[derive(Clone, Debug)]
+pub struct ReadSet {
+    /// The commit limit of the snapshot
+    pub snapshot: Commit,
+    pub trees: BTreeMap<String, Vec<KeyRange>>,
+}
+
+/// The error of a transaction whose reads were changed
+/// by a commit after its snapshot.
+#[derive(Debug)]
+pub struct Conflict {
+    tree: String,
+}
+
+/// The error of a batch whose conditional write found
+/// a different value than expected when it committed.
+#[derive(Debug)]
+pub struct Precondition {
+    tree: String,
+    key: Key,
 }
 
 #[derive(Clone)]
 pub struct ViewReader {
     commit_limit: Commit,
-    trees: Arc<BTreeMap<String, Tree>>,
+    trees: Arc<BTreeMap<String, compacting_tree::View>>,
+    registration: Arc<ViewRegistration>,
 }
 
 pub struct Cursor {
-    tree_cursor: tree::Cursor,
+    tree_cursor: pending_writes::Cursor,
+    /// Keeps the history the cursor reads from
+    _registration: Arc<ViewRegistration>,
 }
 
 impl Db {
-    pub fn new(tree_logs: BTreeMap<String, Log<Command>>, commit_log: Log<CommitCommand>) -> Db {
-        let trees = tree_logs.into_iter().map(|(tree_name, log)| {
-            (tree_name, Tree::new(log))
+    pub fn new(trees: BTreeMap<String, (CompactingTree, Batch)>,
+               commit_log: Log<CommitCommand>,
+               manifest: Manifest,
+               index_checkpoints: Log<IndexCheckpoint>,
+               subscription_capacity: usize) -> Db {
+        let trees = trees.into_iter().map(|(name, (tree, first_batch))| {
+            (name, (Arc::new(tree), first_batch))
         }).collect();
-        let trees = Arc::new(trees);
 
         let commit_log = Arc::new(CommitLog::new(commit_log));
 
@@ -58,21 +147,85 @@ impl Db {
             next_batch_commit: Arc::new(AtomicU64::new(0)),
             next_commit: Arc::new(AtomicU64::new(0)),
             view_commit_limit: Arc::new(AtomicU64::new(0)),
+            gc_horizon: AtomicU64::new(0),
             commit_lock: Arc::new(Mutex::new(())),
-            trees,
+            trees: RwLock::new(Arc::new(trees)),
             commit_log,
+            manifest,
+            index_checkpoints: Mutex::new(IndexCheckpoints::new(index_checkpoints)),
+            live_views: Arc::new(std::sync::Mutex::new(BTreeMap::new())),
+            subscribers: Arc::new(Subscribers::new(subscription_capacity)),
         }
     }
 
-    pub async fn init(&self) -> Result<()> {
+    /// Load the trees as of before `rollback_to`, or the latest commit,
+    /// and if `truncate` then discard the later commits from the logs.
+    pub async fn init(&self, rollback_to: Option<Commit>, truncate: bool) -> Result<()> {
         assert!(!self.initialized.load(Ordering::SeqCst));
 
-        let init_state = loader::load(&self.commit_log, &self.trees).await?;
+        let trees = self.trees();
+        let index_checkpoints = self.index_checkpoints.lock().await;
+
+        if let Some(commit_limit) = rollback_to {
+            for (name, (tree, _)) in trees.iter() {
+                if commit_limit < tree.history_start() {
+                    bail!("tree {} is compacted past commit {}", name, commit_limit.0);
+                }
+            }
+
+            if truncate {
+                // The checkpoint may be of later commits,
+                // or refer to the truncated ends of the logs.
+                index_checkpoints.discard().await?;
+
+                // The commit log is truncated first,
+                // so that if this is interrupted
+                // the trees' later batches are simply uncommitted.
+                let commits = self.commit_log.truncate(commit_limit).await?;
+                for (tree, _) in trees.values() {
+                    tree.truncate_logs(&commits, commit_limit).await?;
+                }
+            }
+        }
+
+        let checkpoint = match (index_checkpoints.load().await, rollback_to) {
+            (Some(checkpoint), Some(commit_limit)) if commit_limit < checkpoint.commit => None,
+            (checkpoint, _) => checkpoint,
+        };
+
+        let init_state = match checkpoint {
+            Some(checkpoint) => {
+                match loader::load(&self.commit_log, &trees, rollback_to, Some(checkpoint)).await {
+                    Ok(init_state) => init_state,
+                    Err(e) => {
+                        // A checkpoint is only an optimization,
+                        // so one that can't be loaded is discarded,
+                        // unless the database is opened read-only.
+                        log::warn!("replaying logs in full after failing to load index checkpoint: {}", e);
+                        if rollback_to.is_none() || truncate {
+                            index_checkpoints.discard().await?;
+                        }
+                        for (tree, _) in trees.values() {
+                            tree.reset_init();
+                        }
+                        loader::load(&self.commit_log, &trees, rollback_to, None).await?
+                    },
+                }
+            },
+            None => loader::load(&self.commit_log, &trees, rollback_to, None).await?,
+        };
+        drop(index_checkpoints);
+
         log::trace!("init state {:?}", init_state);
 
         let view_commit_limit = init_state.next_commit.0;
 
-        self.next_batch.store(init_state.next_batch.0, Ordering::SeqCst);
+        // A tree may have been created after the last batch was recorded,
+        // and its first batch number must not be given to another tree
+        let first_batches = trees.values().map(|(_, first_batch)| Batch(first_batch.0 + 1));
+        let next_batch = first_batches.fold(init_state.next_batch, Batch::max);
+
+        self.next_batch.store(next_batch.0, Ordering::SeqCst);
         self.next_batch_commit.store(init_state.next_batch_commit.0, Ordering::SeqCst);
         self.next_commit.store(init_state.next_commit.0, Ordering::SeqCst);
         self.view_commit_limit.store(view_commit_limit, Ordering::SeqCst);
@@ -82,45 +235,459 @@ impl Db {
         Ok(())
     }
 
+    fn trees(&self) -> Arc<Trees> {
+        self.trees.read().expect("lock").clone()
+    }
+
+    fn tree(&self, tree: &str) -> Result<Arc<CompactingTree>> {
+        self.trees().get(tree)
+            .map(|(tree, _)| tree.clone())
+            .ok_or_else(|| anyhow!("no tree named {}", tree))
+    }
+
+    pub fn tree_names(&self) -> Vec<String> {
+        self.trees().keys().cloned().collect()
+    }
+
+    pub fn index_entries(&self, tree: &str) -> Result<usize> {
+        Ok(self.tree(tree)?.index_entries())
+    }
+
     pub fn batch(&self) -> BatchWriter {
         assert!(self.initialized.load(Ordering::SeqCst));
 
-        let batch = Batch(self.next_batch.fetch_add(1, Ordering::SeqCst));
-        assert_ne!(batch.0, u64::max_value());
-
-        let batch_writers = self.trees.iter().map(|(name, tree)| {
-            (name.clone(), tree.batch(batch))
-        }).collect();
+        // Number the batch while holding the trees,
+        // so that it may write to exactly the trees
+        // whose first batch it is not before.
+        let (batch, trees) = {
+            let trees = self.trees.read().expect("lock");
+            let batch = Batch(self.next_batch.fetch_add(1, Ordering::SeqCst));
+            (batch, trees.clone())
+        };
+        assert_ne!(batch.0, u64::MAX);
 
         BatchWriter {
             batch,
-            batch_writers,
+            trees,
+            tree_writers: Mutex::new(TreeWriters::default()),
             next_batch_commit: self.next_batch_commit.clone(),
             next_commit: self.next_commit.clone(),
             view_commit_limit: self.view_commit_limit.clone(),
             commit_lock: self.commit_lock.clone(),
             commit_log: self.commit_log.clone(),
+            subscribers: self.subscribers.clone(),
         }
     }
 
     pub fn view(&self) -> ViewReader {
+        self.make_view(None).expect("view")
+    }
+
+    /// A view that sees the commits before `commit_limit`.
+    ///
+    /// Fails if the commit limit is past the latest commit,
+    /// or if compaction or garbage collection have
+    /// discarded the history the view would see.
+    pub fn view_at(&self, commit_limit: Commit) -> Result<ViewReader> {
+        self.make_view(Some(commit_limit))
+    }
+
+    /// A view at `commit_limit`, or at the latest commit if `None`.
+    fn make_view(&self, commit_limit: Option<Commit>) -> Result<ViewReader> {
         assert!(self.initialized.load(Ordering::SeqCst));
 
-        let commit_limit = Commit(self.view_commit_limit.load(Ordering::SeqCst));
+        // Hold every tree still while reading the commit limit,
+        // so that no tree is compacted past the limit
+        // before the view is created.
+        let trees = self.trees();
+        let view_locks: Vec<_> = trees.iter().map(|(name, (tree, _))| {
+            (name, tree.lock_view())
+        }).collect();
+
+        // Register the view while reading the commit limit,
+        // so that garbage collection sees either the view
+        // or a limit no later than it.
+        let registration = {
+            let mut live_views = self.live_views.lock().expect("lock");
+            let latest_commit_limit = Commit(self.view_commit_limit.load(Ordering::SeqCst));
+            let commit_limit = match commit_limit {
+                Some(commit_limit) => {
+                    if commit_limit > latest_commit_limit {
+                        bail!("view at commit {} after the latest commit", commit_limit.0);
+                    }
+                    let gc_horizon = Commit(self.gc_horizon.load(Ordering::SeqCst));
+                    let retained = view_locks.iter()
+                        .map(|(_, view_lock)| view_lock.history_start())
+                        .fold(gc_horizon, Commit::max);
+                    if commit_limit < retained {
+                        bail!("view at commit {} before the retained history at commit {}",
+                              commit_limit.0, retained.0);
+                    }
+                    commit_limit
+                },
+                None => latest_commit_limit,
+            };
+            *live_views.entry(commit_limit).or_insert(0) += 1;
+            ViewRegistration {
+                commit_limit,
+                live_views: self.live_views.clone(),
+            }
+        };
+        let commit_limit = registration.commit_limit;
 
-        ViewReader {
+        let trees = view_locks.iter().map(|(name, view_lock)| {
+            ((*name).clone(), view_lock.view(commit_limit))
+        }).collect();
+
+        drop(view_locks);
+
+        Ok(ViewReader {
             commit_limit,
-            trees: self.trees.clone(),
+            trees: Arc::new(trees),
+            registration: Arc::new(registration),
+        })
+    }
+
+    /// Subscribe to the changes of every later commit.
+    ///
+    /// With `from`, the changes of the earlier commits from `from` on
+    /// are first replayed from the logs.
+    /// Fails if any of those have been compacted.
+    pub async fn subscribe(&self, from: Option<Commit>) -> Result<(Vec<ChangeBatch>, Receiver<ChangeBatch>)> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        // Subscribe between commits,
+        // so that each is either replayed or received.
+        let (next_commit, receiver) = {
+            let _commit_lock = self.commit_lock.lock().await;
+            let next_commit = Commit(self.next_commit.load(Ordering::SeqCst));
+            (next_commit, self.subscribers.subscribe(next_commit))
+        };
+
+        let mut replayed = vec![];
+        if let Some(from) = from {
+            if from > next_commit {
+                bail!("subscription from commit {} after the latest commit", from.0);
+            }
+            self.replay_changes(from, next_commit, true, &mut replayed).await?;
         }
+
+        Ok((replayed, receiver))
     }
 
-    pub async fn sync(&self) -> Result<()> {
-        for (_, tree) in self.trees.iter() {
-            tree.sync().await?;
+    /// Send the changes of the commits from `from` on to `sink`,
+    /// returning the commit limit after them.
+    ///
+    /// Fails if any of them have been compacted,
+    /// or wrote to a tree that has since been dropped.
+    pub async fn changes_since<S>(&self, from: Commit, sink: &mut S) -> Result<Commit>
+    where S: Sink<ChangeBatch> + Unpin,
+          S::Error: std::error::Error + Send + Sync + 'static,
+    {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        let until = {
+            let _commit_lock = self.commit_lock.lock().await;
+            Commit(self.next_commit.load(Ordering::SeqCst))
+        };
+        if from > until {
+            bail!("changes since commit {} after the latest commit", from.0);
+        }
+
+        self.replay_changes(from, until, false, sink).await?;
+        Ok(until)
+    }
+
+    /// Send the changes of the commits from `from` to before `until`
+    /// to `sink` in commit order, each as soon as it is replayed.
+    ///
+    /// A commit's changes to trees that have since been dropped
+    /// are left out if `skip_dropped`, or else fail the replay
+    /// before anything is sent.
+    async fn replay_changes<S>(&self, from: Commit, until: Commit, skip_dropped: bool, sink: &mut S) -> Result<()>
+    where S: Sink<ChangeBatch> + Unpin,
+          S::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let trees = self.trees();
+        // Each commit, whether it lists the trees its batch wrote to,
+        // and those trees that still exist
+        let mut commits = vec![];
+        let mut tree_commits: BTreeMap<&str, BTreeMap<_, _>> = BTreeMap::new();
+
+        let mut commit_replay_stream = self.commit_log.replay();
+        while let Some(next_commit) = commit_replay_stream.next().await {
+            let next_commit = next_commit?;
+            if next_commit.commit >= until {
+                break;
+            }
+            if next_commit.commit < from {
+                continue;
+            }
+
+            if let (Some(written), false) = (&next_commit.trees, skip_dropped) {
+                for tree_name in written {
+                    let dropped = match trees.get(tree_name) {
+                        Some((_, first_batch)) => next_commit.batch < *first_batch,
+                        None => true,
+                    };
+                    if dropped {
+                        bail!("commit {} wrote to tree {}, which has since been dropped",
+                              next_commit.commit.0, tree_name);
+                    }
+                }
+            }
+
+            let mut commit_trees = vec![];
+            for (tree_name, (_, first_batch)) in trees.iter() {
+                if next_commit.batch < *first_batch {
+                    continue;
+                }
+                if let Some(trees) = &next_commit.trees {
+                    if !trees.iter().any(|t| t == tree_name) {
+                        continue;
+                    }
+                }
+                tree_commits.entry(tree_name.as_str()).or_default()
+                    .insert((next_commit.batch, next_commit.batch_commit), next_commit.commit);
+                commit_trees.push(tree_name.as_str());
+            }
+            commits.push((next_commit.commit, next_commit.trees.is_some(), commit_trees));
+        }
+
+        // Each tree's replayer, and the changes it has replayed
+        // ahead of their commits
+        let mut replayers = BTreeMap::new();
+        for (tree_name, commits) in tree_commits.iter() {
+            let (tree, _) = trees.get(*tree_name).expect("tree");
+            replayers.insert(*tree_name, (tree.change_replayer(commits)?, BTreeMap::new()));
+        }
+
+        for (commit, listed, commit_trees) in commits {
+            let mut batch = ChangeBatch {
+                commit,
+                trees: BTreeMap::new(),
+            };
+            for tree_name in commit_trees {
+                let (replayer, replayed) = replayers.get_mut(tree_name).expect("tree");
+                // Batches may be made ready in a different order than they commit
+                while !replayed.contains_key(&commit) {
+                    match replayer.next(&tree_commits[tree_name]).await? {
+                        Some((commit, changes)) => {
+                            replayed.insert(commit, changes);
+                        },
+                        None => break,
+                    }
+                }
+                match replayed.remove(&commit) {
+                    Some(changes) => {
+                        batch.trees.insert(tree_name.to_string(), changes);
+                    },
+                    // Without a list of trees the batch may not have written to this one
+                    None if !listed => { },
+                    None => bail!("changes of commit {} missing from tree {}", commit.0, tree_name),
+                }
+            }
+            sink.send(batch).await?;
         }
 
         Ok(())
     }
+
+    /// Copy the database as of the latest commit to new logs.
+    ///
+    /// Commits may continue during the copy,
+    /// and batches after the latest commit are copied
+    /// as if they never committed.
+    ///
+    /// Returns the commit limit of the copy.
+    pub async fn checkpoint(&self,
+                            manifest: Manifest,
+                            commit_log: Log<CommitCommand>,
+                            make_tree_logs: impl Fn(&str, Batch) -> Result<TreeLogs>) -> Result<Commit> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        // Take where the logs end between commits,
+        // so that up to there they contain every commit before the limit.
+        let (commit_limit, snapshots) = {
+            let _commit_lock = self.commit_lock.lock().await;
+            let commit_limit = Commit(self.next_commit.load(Ordering::SeqCst));
+            let mut snapshots = vec![];
+            for (name, (tree, first_batch)) in self.trees().iter() {
+                snapshots.push((name.clone(), *first_batch, tree.snapshot_logs().await));
+            }
+            (commit_limit, snapshots)
+        };
+
+        for (name, first_batch, snapshot) in snapshots {
+            // With no commits, an empty commit log skips replaying the trees,
+            // which must then be empty too.
+            if commit_limit > Commit(0) {
+                snapshot.copy_to(&make_tree_logs(&name, first_batch)?).await?;
+            }
+            manifest.create_tree(&name, first_batch).await?;
+        }
+
+        let dest = CommitLog::new(commit_log);
+        self.commit_log.copy_to(&dest, commit_limit).await?;
+
+        Ok(commit_limit)
+    }
+
+    /// Save the trees' indexes between commits,
+    /// so that opening the database only replays the logs after them.
+    ///
+    /// Returns the commit limit of the checkpoint.
+    pub async fn checkpoint_indexes(&self) -> Result<Commit> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        let index_checkpoints = self.index_checkpoints.lock().await;
+
+        // Between commits the indexes hold every commit before the limit,
+        // and the trees every command the open batches will replay.
+        let checkpoint = {
+            let _commit_lock = self.commit_lock.lock().await;
+            let mut tree_checkpoints = vec![];
+            for (name, (tree, first_batch)) in self.trees().iter() {
+                for (log, checkpoint) in tree.checkpoint().await {
+                    tree_checkpoints.push(TreeCheckpoint {
+                        tree: name.clone(),
+                        first_batch: *first_batch,
+                        log,
+                        checkpoint,
+                    });
+                }
+            }
+            IndexCheckpoint {
+                commit: Commit(self.next_commit.load(Ordering::SeqCst)),
+                next_batch: Batch(self.next_batch.load(Ordering::SeqCst)),
+                next_batch_commit: BatchCommit(self.next_batch_commit.load(Ordering::SeqCst)),
+                commit_log: self.commit_log.last(),
+                trees: tree_checkpoints,
+            }
+        };
+
+        // Only refer to commands that are durable
+        self.sync().await?;
+
+        let commit = checkpoint.commit;
+        index_checkpoints.write(checkpoint).await?;
+
+        Ok(commit)
+    }
+
+    /// Discard index history that no live view can see.
+    ///
+    /// Returns the number of versions and range deletes discarded.
+    pub fn collect_garbage(&self) -> usize {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        let horizon = {
+            let live_views = self.live_views.lock().expect("lock");
+            let view_commit_limit = Commit(self.view_commit_limit.load(Ordering::SeqCst));
+            let horizon = match live_views.keys().next() {
+                Some(oldest) => (*oldest).min(view_commit_limit),
+                None => view_commit_limit,
+            };
+            self.gc_horizon.fetch_max(horizon.0, Ordering::SeqCst);
+            horizon
+        };
+
+        self.trees().values().map(|(tree, _)| tree.collect_garbage(horizon)).sum()
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        // Sync the trees together so the fs thread can group them,
+        // and before the commit log so that it never
+        // durably refers to batches that aren't.
+        let trees = self.trees();
+        let syncs = trees.values().map(|(tree, _)| tree.sync());
+        future::try_join_all(syncs).await?;
+
+        self.commit_log.sync().await
+    }
+
+    pub async fn compact(&self, tree: &str) -> Result<bool> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        let tree = self.tree(tree)?;
+        tree.compact().await
+    }
+
+    /// Create a tree from the logs made for its first batch,
+    /// which may hold the remains of an earlier tree
+    /// of the same name and first batch.
+    pub async fn create_tree(&self,
+                             name: &str,
+                             make_logs: impl FnOnce(Batch) -> Result<TreeLogs>,
+                             merge: Option<MergeOperator>) -> Result<()> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        // Hold off commits until the tree is durable,
+        // so that no durable commit refers to a tree that isn't.
+        // This also serializes tree creation and dropping.
+        let commit_lock = self.commit_lock.lock().await;
+
+        if self.trees().contains_key(name) {
+            bail!("tree {} already exists", name);
+        }
+
+        // Taking a batch number for the tree keeps its logs apart
+        // from those of any dropped tree of the same name.
+        // Batches numbered before the tree is added just can't write to it.
+        let first_batch = Batch(self.next_batch.fetch_add(1, Ordering::SeqCst));
+        assert_ne!(first_batch.0, u64::MAX);
+
+        let logs = make_logs(first_batch)?;
+        logs.remove_all().await?;
+        let tree = CompactingTree::open(logs, false, merge).await?;
+        tree.skip_init();
+        let tree = Arc::new(tree);
+
+        {
+            let mut trees = self.trees.write().expect("lock");
+            let mut new_trees = (**trees).clone();
+            new_trees.insert(name.to_string(), (tree.clone(), first_batch));
+            *trees = Arc::new(new_trees);
+        }
+
+        let r = self.manifest.create_tree(name, first_batch).await;
+        if r.is_err() {
+            // Batches may already be writing to it,
+            // but none can commit before the commit lock is released.
+            tree.set_dropped();
+            self.remove_tree(name);
+        }
+
+        drop(commit_lock);
+
+        r
+    }
+
+    /// Drop a tree and delete its logs.
+    ///
+    /// Batches that wrote to the tree can no longer commit.
+    pub async fn drop_tree(&self, name: &str) -> Result<()> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        let commit_lock = self.commit_lock.lock().await;
+
+        let tree = self.tree(name)?;
+        self.manifest.drop_tree(name).await?;
+        // No batch commits to the tree after this
+        tree.set_dropped();
+        self.remove_tree(name);
+
+        drop(commit_lock);
+
+        tree.destroy().await
+    }
+
+    fn remove_tree(&self, name: &str) {
+        let mut trees = self.trees.write().expect("lock");
+        let mut new_trees = (**trees).clone();
+        new_trees.remove(name);
+        *trees = Arc::new(new_trees);
+    }
 }
 
 impl BatchWriter {
@@ -128,75 +695,231 @@ impl BatchWriter {
         self.batch
     }
 
-    pub async fn open(&self, tree: &str) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.open().await?)
+    /// The trees the batch has written to.
+    pub async fn opened_tree_names(&self) -> Vec<String> {
+        let tree_writers = self.tree_writers.lock().await;
+        tree_writers.writers.keys().cloned().collect()
     }
 
     pub async fn write(&self, tree: &str, key: Key, value: Value) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.write(key, value).await?)
+        let writer = self.tree_writer(tree).await?;
+        writer.write(key.clone(), value.clone()).await?;
+        self.add_change(tree, Change::Write { key: key.0, value: value.0 }).await;
+        Ok(())
     }
 
     pub async fn delete(&self, tree: &str, key: Key) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.delete(key).await?)
+        let writer = self.tree_writer(tree).await?;
+        writer.delete(key.clone()).await?;
+        self.add_change(tree, Change::Delete { key: key.0 }).await;
+        Ok(())
     }
 
     pub async fn delete_range(&self, tree: &str, start_key: Key, end_key: Key) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.delete_range(start_key, end_key).await?)
+        let writer = self.tree_writer(tree).await?;
+        writer.delete_range(start_key.clone(), end_key.clone()).await?;
+        self.add_change(tree, Change::DeleteRange { start_key: start_key.0, end_key: end_key.0 }).await;
+        Ok(())
+    }
+
+    pub async fn merge(&self, tree: &str, key: Key, operand: Value) -> Result<()> {
+        let writer = self.tree_writer(tree).await?;
+        writer.merge(key.clone(), operand.clone()).await?;
+        self.add_change(tree, Change::Merge { key: key.0, operand: operand.0 }).await;
+        Ok(())
+    }
+
+    /// Write a key if, when the batch commits,
+    /// its latest committed value is `expected`.
+    pub async fn write_if(&self, tree: &str, key: Key, expected: Option<Value>, value: Value) -> Result<()> {
+        self.write(tree, key.clone(), value).await?;
+        self.add_condition(tree, key, expected).await;
+        Ok(())
+    }
+
+    /// Delete a key if, when the batch commits,
+    /// its latest committed value is `expected`.
+    pub async fn delete_if(&self, tree: &str, key: Key, expected: Value) -> Result<()> {
+        self.delete(tree, key.clone()).await?;
+        self.add_condition(tree, key, Some(expected)).await;
+        Ok(())
+    }
+
+    async fn add_condition(&self, tree: &str, key: Key, expected: Option<Value>) {
+        let mut tree_writers = self.tree_writers.lock().await;
+        tree_writers.conditions.push(Condition {
+            tree: tree.to_string(),
+            key,
+            expected,
+        });
+    }
+
+    async fn add_change(&self, tree: &str, change: Change) {
+        let mut tree_writers = self.tree_writers.lock().await;
+        tree_writers.changes.push((tree.to_string(), change));
+    }
+
+    pub async fn push_save_point(&self) -> Result<()> {
+        let mut tree_writers = self.tree_writers.lock().await;
+        for writer in tree_writers.writers.values() {
+            writer.push_save_point().await?;
+        }
+        let save_point = SavePoint {
+            conditions: tree_writers.conditions.len(),
+            changes: tree_writers.changes.len(),
+        };
+        tree_writers.save_points.push(save_point);
+
+        Ok(())
+    }
+
+    pub async fn pop_save_point(&self) -> Result<()> {
+        let mut tree_writers = self.tree_writers.lock().await;
+        if tree_writers.save_points.is_empty() {
+            bail!("no save point to pop");
+        }
+        for writer in tree_writers.writers.values() {
+            writer.pop_save_point().await?;
+        }
+        tree_writers.save_points.pop();
+
+        Ok(())
     }
 
-    pub async fn push_save_point(&self, tree: &str) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.push_save_point().await?)
+    pub async fn rollback_save_point(&self) -> Result<()> {
+        let mut tree_writers = self.tree_writers.lock().await;
+        if tree_writers.save_points.is_empty() {
+            bail!("no save point to roll back");
+        }
+        for writer in tree_writers.writers.values() {
+            writer.rollback_save_point().await?;
+        }
+        let save_point = tree_writers.save_points.pop().expect("save point");
+        tree_writers.conditions.truncate(save_point.conditions);
+        tree_writers.changes.truncate(save_point.changes);
+
+        Ok(())
     }
 
-    pub async fn pop_save_point(&self, tree: &str) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.pop_save_point().await?)
+    /// Read a key from a view as if the batch's writes so far were committed.
+    pub async fn read(&self, view: &ViewReader, tree: &str, key: &Key) -> Result<Option<Value>> {
+        let pending = self.pending(tree).await;
+        pending.read(view.tree(tree)?, key).await
     }
 
-    pub async fn rollback_save_point(&self, tree: &str) -> Result<()> {
-        let writer = self.tree_writer(tree);
-        Ok(writer.rollback_save_point().await?)
+    /// A cursor over a view as if the batch's writes so far were committed.
+    ///
+    /// Later writes by the batch are not seen by the cursor.
+    pub async fn cursor(&self, view: &ViewReader, tree: &str) -> Result<Cursor> {
+        let pending = self.pending(tree).await;
+        view.cursor_with(tree, pending)
+    }
+
+    async fn pending(&self, tree: &str) -> PendingWrites {
+        let tree_writers = self.tree_writers.lock().await;
+        match tree_writers.writers.get(tree) {
+            Some(writer) => PendingWrites::new(writer.clone()),
+            None => PendingWrites::none(),
+        }
     }
 
     pub fn new_batch_commit_number(&self) -> BatchCommit {
         // Take a new batch_commit number
         let batch_commit = BatchCommit(self.next_batch_commit.fetch_add(1, Ordering::SeqCst));
-        assert_ne!(batch_commit.0, u64::max_value());
+        assert_ne!(batch_commit.0, u64::MAX);
         batch_commit
     }
 
     pub async fn ready_commit(&self, tree: &str, batch_commit: BatchCommit) -> Result<()> {
-        let writer = self.tree_writer(tree);
+        let writer = self.opened_tree_writer(tree).await?;
         Ok(writer.ready_commit(batch_commit).await?)
     }
 
     pub async fn abort_commit(&self, tree: &str, batch_commit: BatchCommit) -> Result<()> {
-        let writer = self.tree_writer(tree);
+        let writer = self.opened_tree_writer(tree).await?;
         Ok(writer.abort_commit(batch_commit).await?)
     }
 
-    pub async fn commit(&self, batch_commit: BatchCommit) -> Result<()> {
+    /// Commit the writes to the trees in `trees`,
+    /// each of which must be ready.
+    ///
+    /// If `reads` are given then the commit fails with [`Conflict`]
+    /// if any of them were changed after their snapshot.
+    ///
+    /// The commit fails with [`Precondition`] if any conditional write
+    /// doesn't find the value it expects.
+    ///
+    /// If `sync` then the commit is durable when this returns.
+    ///
+    /// With `number` the commit takes that number,
+    /// skipping those before it, and fails if it has already been taken.
+    ///
+    /// Returns the commit number.
+    pub async fn commit(&self,
+                        trees: &[String],
+                        batch_commit: BatchCommit,
+                        sync: bool,
+                        reads: Option<&ReadSet>,
+                        number: Option<Commit>) -> Result<Commit> {
+        let mut writers = Vec::with_capacity(trees.len());
+        for tree in trees {
+            writers.push(self.opened_tree_writer(tree).await?);
+        }
+
+        // The batch must be durable before the commit log refers to it.
+        // This is outside the commit lock so that concurrent commits
+        // can share syncs.
+        if sync {
+            let syncs = writers.iter().map(|writer| writer.sync());
+            future::try_join_all(syncs).await?;
+        }
+
         // Next steps are under the commit lock in order
         // to keep commit numbers stored monotonically
         let commit_lock = self.commit_lock.lock().await;
 
-        // Take a new commit number
-        let commit = Commit(self.next_commit.fetch_add(1, Ordering::SeqCst));
-        assert_ne!(commit.0, u64::max_value());
+        let next_commit = Commit(self.next_commit.load(Ordering::SeqCst));
+        if let Some(number) = number {
+            if number < next_commit {
+                bail!("commit {} is before the next commit {}", number.0, next_commit.0);
+            }
+        }
+
+        // A tree can't be dropped between this check and the commit
+        for (tree, writer) in trees.iter().zip(writers.iter()) {
+            if writer.is_dropped() {
+                bail!("tree {} was dropped", tree);
+            }
+        }
+
+        // No commit can change the reads between this check and the commit
+        if let Some(reads) = reads {
+            self.check_conflicts(&commit_lock, reads)?;
+        }
+        self.check_conditions(&commit_lock).await?;
+
+        // Nobody can subscribe until the commit lock is released
+        let changes = if self.subscribers.is_empty() {
+            None
+        } else {
+            Some(self.changes(trees).await)
+        };
+
+        // Take a new commit number.
+        // Any numbers skipped are recorded by the commit itself,
+        // and views don't see them until it is written.
+        let commit = number.unwrap_or(next_commit);
+        assert_ne!(commit.0, u64::MAX);
+        self.next_commit.store(commit.0 + 1, Ordering::SeqCst);
 
         // Write the master commit.
         // This is the only source of failure in the commit method,
         // and if this fails then the commit is effectively aborted;
         // if this succeeds then the remaining commit process must succeed.
-        self.write_commit(&commit_lock, batch_commit, commit).await?;
+        self.write_commit(&commit_lock, batch_commit, commit, trees.to_vec()).await?;
 
         // Infallably promote each tree's writes to its index.
-        for (tree, writer) in self.batch_writers.iter() {
+        for writer in writers.iter() {
             writer.commit_to_index(batch_commit, commit)
         }
 
@@ -205,36 +928,212 @@ impl BatchWriter {
         let old_commit_limit = self.view_commit_limit.swap(new_commit_limit, Ordering::SeqCst);
         assert!(old_commit_limit < new_commit_limit);
 
-        Ok(())
+        // Queue in commit order, to send once the lock is released
+        if let Some(trees) = changes {
+            self.subscribers.queue(ChangeBatch { commit, trees });
+        }
+
+        drop(commit_lock);
+
+        self.subscribers.flush();
+
+        // The commit is visible before it is durable,
+        // but not to anybody that outlives a crash.
+        if sync {
+            self.commit_log.sync().await?;
+        }
+
+        Ok(commit)
+    }
+
+    /// The batch's changes to each of `trees`.
+    async fn changes(&self, trees: &[String]) -> BTreeMap<String, Vec<Change>> {
+        let tree_writers = self.tree_writers.lock().await;
+        let mut changes: BTreeMap<_, _> = trees.iter().map(|tree| (tree.clone(), vec![])).collect();
+        for (tree, change) in tree_writers.changes.iter() {
+            if let Some(tree_changes) = changes.get_mut(tree) {
+                tree_changes.push(change.clone());
+            }
+        }
+        changes
     }
 
     /// NB: This must be called after the batch is committed
     pub async fn close(&self, tree: &str) -> Result<()> {
-        let writer = self.tree_writer(tree);
+        let writer = self.opened_tree_writer(tree).await?;
         Ok(writer.close().await?)
     }
 
-    fn tree_writer(&self, tree: &str) -> &tree::BatchWriter {
-        self.batch_writers.get(tree).expect("tree")
+    /// Get the writer for a tree, opening the batch on it
+    /// with the save points it would have seen.
+    async fn tree_writer(&self, tree: &str) -> Result<Arc<compacting_tree::BatchWriter>> {
+        let mut tree_writers = self.tree_writers.lock().await;
+        let (compacting_tree, _) = self.trees.get(tree).ok_or_else(|| anyhow!("no tree named {}", tree))?;
+        if compacting_tree.is_dropped() {
+            bail!("tree {} was dropped", tree);
+        }
+        if let Some(writer) = tree_writers.writers.get(tree) {
+            return Ok(writer.clone());
+        }
+
+        let writer = compacting_tree.batch(self.batch).await;
+        writer.open().await?;
+        for _ in 0..tree_writers.save_points.len() {
+            writer.push_save_point().await?;
+        }
+
+        let writer = Arc::new(writer);
+        tree_writers.writers.insert(tree.to_string(), writer.clone());
+
+        Ok(writer)
     }
 
-    async fn write_commit(&self, _commit_lock: &MutexGuard<'_, ()>, batch_commit: BatchCommit, commit: Commit) -> Result<()> {
-        Ok(self.commit_log.commit(self.batch, batch_commit, commit).await?)
+    async fn opened_tree_writer(&self, tree: &str) -> Result<Arc<compacting_tree::BatchWriter>> {
+        let tree_writers = self.tree_writers.lock().await;
+        tree_writers.writers.get(tree).cloned()
+            .ok_or_else(|| anyhow!("batch {} not open on tree {}", self.batch.0, tree))
+    }
+
+    fn check_conflicts(&self, _commit_lock: &MutexGuard<'_, ()>, reads: &ReadSet) -> Result<()> {
+        for (tree, ranges) in reads.trees.iter() {
+            // A tree dropped since the snapshot has certainly changed
+            let written = match self.trees.get(tree) {
+                Some((compacting_tree, _)) => {
+                    ranges.iter().any(|range| compacting_tree.written_since(reads.snapshot, range))
+                },
+                None => true,
+            };
+            if written {
+                return Err(anyhow!(Conflict { tree: tree.clone() }));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn check_conditions(&self, _commit_lock: &MutexGuard<'_, ()>) -> Result<()> {
+        let tree_writers = self.tree_writers.lock().await;
+        // Every commit so far is visible, and no other can be made
+        let commit_limit = Commit(self.view_commit_limit.load(Ordering::SeqCst));
+
+        for condition in tree_writers.conditions.iter() {
+            let (compacting_tree, _) = self.trees.get(&condition.tree).expect("tree");
+            let view = compacting_tree.lock_view().view(commit_limit);
+            if view.read(&condition.key).await? != condition.expected {
+                return Err(anyhow!(Precondition {
+                    tree: condition.tree.clone(),
+                    key: condition.key.clone(),
+                }));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write_commit(&self, _commit_lock: &MutexGuard<'_, ()>, batch_commit: BatchCommit, commit: Commit, trees: Vec<String>) -> Result<()> {
+        self.commit_log.commit(self.batch, batch_commit, commit, trees).await
     }
 }
 
 impl ViewReader {
+    /// Reads see every commit before this one.
+    pub fn commit_limit(&self) -> Commit {
+        self.commit_limit
+    }
+
     pub async fn read(&self, tree: &str, key: &Key) -> Result<Option<Value>> {
-        let tree = self.trees.get(tree).expect("tree");
-        Ok(tree.read(self.commit_limit, key).await?)
+        let tree = self.tree(tree)?;
+        tree.read(key).await
+    }
+
+    pub fn cursor(&self, tree: &str) -> Result<Cursor> {
+        self.cursor_with(tree, PendingWrites::none())
     }
 
-    pub fn cursor(&self, tree: &str) -> Cursor {
-        let tree = self.trees.get(tree).expect("tree");
-        let tree_cursor = tree.cursor(self.commit_limit);
+    pub async fn history(&self, tree: &str, key: &Key) -> Result<Vec<(Commit, Option<Value>)>> {
+        let tree = self.tree(tree)?;
+        tree.history(key).await
+    }
 
-        Cursor {
+    fn cursor_with(&self, tree: &str, pending: PendingWrites) -> Result<Cursor> {
+        let tree = self.tree(tree)?;
+        let tree_cursor = pending_writes::Cursor::new(tree.clone(), pending);
+
+        Ok(Cursor {
             tree_cursor,
+            _registration: self.registration.clone(),
+        })
+    }
+
+    fn tree(&self, tree: &str) -> Result<&compacting_tree::View> {
+        self.trees.get(tree).ok_or_else(|| anyhow!("no tree named {}", tree))
+    }
+}
+
+impl ReadSet {
+    pub fn new(snapshot: Commit) -> ReadSet {
+        ReadSet {
+            snapshot,
+            trees: BTreeMap::new(),
+        }
+    }
+
+    /// Record a read, returning its index among the tree's reads.
+    pub fn insert(&mut self, tree: &str, range: KeyRange) -> usize {
+        let ranges = self.trees.entry(tree.to_string()).or_default();
+        ranges.push(range);
+        ranges.len() - 1
+    }
+
+    /// Widen an earlier read to cover `range` too.
+    pub fn extend(&mut self, tree: &str, index: usize, range: KeyRange) {
+        let ranges = self.trees.get_mut(tree).expect("tree");
+        ranges[index].extend(range);
+    }
+}
+
+impl Conflict {
+    /// The tree whose reads were changed.
+    pub fn tree(&self) -> &str {
+        &self.tree
+    }
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transaction conflict in tree {}", self.tree)
+    }
+}
+
+impl std::error::Error for Conflict { }
+
+impl Precondition {
+    /// The tree of the conditional write.
+    pub fn tree(&self) -> &str {
+        &self.tree
+    }
+
+    /// The key of the conditional write.
+    pub fn key(&self) -> &[u8] {
+        &self.key.0
+    }
+}
+
+impl fmt::Display for Precondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "precondition failed for key {:?} in tree {}", self.key.0, self.tree)
+    }
+}
+
+impl std::error::Error for Precondition { }
+
+impl Drop for ViewRegistration {
+    fn drop(&mut self) {
+        let mut live_views = self.live_views.lock().expect("lock");
+        let count = live_views.get_mut(&self.commit_limit).expect("view");
+        *count -= 1;
+        if *count == 0 {
+            live_views.remove(&self.commit_limit);
         }
     }
 }
@@ -1,5 +1,6 @@
 use std::collections::BTreeMap;
 use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
 use crate::command::Command;
 use crate::types::{Address, Key, Batch, BatchCommit};
 
@@ -11,6 +12,18 @@ struct BatchData {
     commands: Vec<SimpleCommand>,
 }
 
+/// The commands of an open batch, as saved in an index checkpoint.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+pub struct BatchSnapshot {
+    batch: Batch,
+    commands: Vec<SimpleCommand>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+#[derive(Debug)]
+#[serde(tag = "type")]
 enum SimpleCommand {
     Write {
         key: Key,
@@ -25,6 +38,10 @@ enum SimpleCommand {
         end_key: Key,
         address: Address,
     },
+    Merge {
+        key: Key,
+        address: Address,
+    },
     PushSavePoint,
     PopSavePoint,
     RollbackSavePoint,
@@ -50,6 +67,10 @@ pub enum IndexOp {
         end_key: Key,
         address: Address
     },
+    Merge {
+        key: Key,
+        address: Address
+    },
 }
 
 impl BatchPlayer {
@@ -90,6 +111,13 @@ impl BatchPlayer {
                     address,
                 });
             },
+            Command::Merge { batch, key, .. } => {
+                let mut batch_data = batches.get_mut(batch).expect("batch");
+                batch_data.commands.push(SimpleCommand::Merge {
+                    key: key.clone(),
+                    address,
+                });
+            },
             Command::PushSavePoint { batch } => {
                 let mut batch_data = batches.get_mut(batch).expect("batch");
                 batch_data.commands.push(SimpleCommand::PushSavePoint);
@@ -130,58 +158,120 @@ impl BatchPlayer {
     pub fn replay(&self, batch: Batch, batch_commit: BatchCommit) -> impl Iterator<Item = IndexOp> {
         let mut batches = self.batches.lock().expect("lock");
         let batch_data = batches.get(&batch).expect("batch");
-        let mut ops = vec![];
-        let mut save_point_indexes = vec![];
-        for cmd in &batch_data.commands {
-            match cmd {
-                SimpleCommand::Write { key, address } => {
-                    ops.push(IndexOp::Write {
-                        key: key.clone(),
-                        address: *address,
-                    });
-                },
-                SimpleCommand::Delete { key, address } => {
-                    ops.push(IndexOp::Delete {
-                        key: key.clone(),
-                        address: *address,
-                    });
-                },
-                SimpleCommand::DeleteRange { start_key, end_key, address } => {
-                    ops.push(IndexOp::DeleteRange {
-                        start_key: start_key.clone(),
-                        end_key: end_key.clone(),
-                        address: *address,
-                    });
-                },
-                SimpleCommand::PushSavePoint => {
-                    save_point_indexes.push(ops.len());
-                },
-                SimpleCommand::PopSavePoint => {
-                    save_point_indexes.pop();
-                },
-                SimpleCommand::RollbackSavePoint => {
-                    if let Some(save_point) = save_point_indexes.pop() {
-                        assert!(save_point <= ops.len());
-                        ops.truncate(save_point);
-                    } else {
-                        panic!("rollback without save point");
-                    }
-                },
-                SimpleCommand::ReadyCommit { batch_commit: bc } => {
-                    if batch_commit == *bc {
-                        return ops.into_iter();
-                    }
-                },
-                SimpleCommand::AbortCommit { batch_commit: bc }=> {
-                    if batch_commit == *bc {
-                        ops.clear();
-                        return ops.into_iter();
-                    }
-                },                    
-            }
+        if let Some(ops) = play(&batch_data.commands, Some(batch_commit)) {
+            ops.into_iter()
+        } else {
+            panic!("uncommitted/unaborted batch replay");
+        }
+    }
+
+    /// The commands of every open batch.
+    pub fn snapshot(&self) -> Vec<BatchSnapshot> {
+        let batches = self.batches.lock().expect("lock");
+        batches.iter().map(|(batch, batch_data)| BatchSnapshot {
+            batch: *batch,
+            commands: batch_data.commands.clone(),
+        }).collect()
+    }
+
+    /// Add an open batch from a snapshot.
+    pub fn restore(&self, snapshot: BatchSnapshot) {
+        let mut batches = self.batches.lock().expect("lock");
+        assert!(!batches.contains_key(&snapshot.batch));
+        batches.insert(snapshot.batch, BatchData {
+            commands: snapshot.commands,
+        });
+    }
+
+    /// All of a batch's ops so far, regardless of commits,
+    /// as the next commit would replay them.
+    pub fn pending(&self, batch: Batch) -> Vec<IndexOp> {
+        let batches = self.batches.lock().expect("lock");
+        let batch_data = batches.get(&batch).expect("batch");
+        play(&batch_data.commands, None).expect("ops")
+    }
+}
+
+impl BatchSnapshot {
+    pub fn batch(&self) -> Batch {
+        self.batch
+    }
+
+    /// The batch commits the batch has readied or aborted.
+    pub fn batch_commits(&self) -> Vec<BatchCommit> {
+        self.commands.iter().filter_map(|cmd| match cmd {
+            SimpleCommand::ReadyCommit { batch_commit }
+            | SimpleCommand::AbortCommit { batch_commit } => Some(*batch_commit),
+            _ => None,
+        }).collect()
+    }
+}
+
+/// Play a batch's commands up to the commit or abort of `until`,
+/// or to the end if `None`.
+///
+/// Returns `None` if `until` was not reached.
+fn play(commands: &[SimpleCommand], until: Option<BatchCommit>) -> Option<Vec<IndexOp>> {
+    let mut ops = vec![];
+    let mut save_point_indexes = vec![];
+    for cmd in commands {
+        match cmd {
+            SimpleCommand::Write { key, address } => {
+                ops.push(IndexOp::Write {
+                    key: key.clone(),
+                    address: *address,
+                });
+            },
+            SimpleCommand::Delete { key, address } => {
+                ops.push(IndexOp::Delete {
+                    key: key.clone(),
+                    address: *address,
+                });
+            },
+            SimpleCommand::DeleteRange { start_key, end_key, address } => {
+                ops.push(IndexOp::DeleteRange {
+                    start_key: start_key.clone(),
+                    end_key: end_key.clone(),
+                    address: *address,
+                });
+            },
+            SimpleCommand::Merge { key, address } => {
+                ops.push(IndexOp::Merge {
+                    key: key.clone(),
+                    address: *address,
+                });
+            },
+            SimpleCommand::PushSavePoint => {
+                save_point_indexes.push(ops.len());
+            },
+            SimpleCommand::PopSavePoint => {
+                save_point_indexes.pop();
+            },
+            SimpleCommand::RollbackSavePoint => {
+                if let Some(save_point) = save_point_indexes.pop() {
+                    assert!(save_point <= ops.len());
+                    ops.truncate(save_point);
+                } else {
+                    panic!("rollback without save point");
+                }
+            },
+            SimpleCommand::ReadyCommit { batch_commit: bc } => {
+                if until == Some(*bc) {
+                    return Some(ops);
+                }
+            },
+            SimpleCommand::AbortCommit { batch_commit: bc }=> {
+                if until == Some(*bc) {
+                    ops.clear();
+                    return Some(ops);
+                }
+            },
         }
-        panic!("uncommitted/unaborted batch replay");
-        ops.clear();
-        ops.into_iter()
+    }
+
+    if until.is_none() {
+        Some(ops)
+    } else {
+        None
     }
 }
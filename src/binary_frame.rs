@@ -0,0 +1,103 @@
+//! A compact, checksummed, write log format
+//!
+//! Each frame is a little-endian `u32` body length,
+//! a little-endian `u32` CRC-32 of the length,
+//! a little-endian `u32` CRC-32 of the body,
+//! then the body, a CBOR-encoded command.
+//!
+//! The length has its own checksum so that a damaged length
+//! is never trusted to say how much to read.
+
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, anyhow};
+use std::io::{self, Read, Write};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The frame ends past the end of the bytes it was read from,
+/// as if its write was interrupted.
+#[derive(Debug)]
+pub struct Truncated;
+
+pub fn write<Io, Cmd>(io: &mut Io, cmd: &Cmd) -> Result<()>
+where Io: Write,
+      Cmd: Serialize,
+{
+    let body = serde_cbor::to_vec(cmd)?;
+    let length = u32::try_from(body.len()).map_err(|_| anyhow!("frame too long"))?;
+    let length = length.to_le_bytes();
+    let length_checksum = crc32fast::hash(&length);
+    let checksum = crc32fast::hash(&body);
+
+    let mut frame = Vec::with_capacity(HEADER_LENGTH + body.len());
+    frame.extend_from_slice(&length);
+    frame.extend_from_slice(&length_checksum.to_le_bytes());
+    frame.extend_from_slice(&checksum.to_le_bytes());
+    frame.extend_from_slice(&body);
+
+    io.write_all(&frame)?;
+
+    Ok(())
+}
+
+/// Read a frame from the next `limit` bytes.
+pub fn read<Io, Cmd>(io: &mut Io, limit: u64) -> Result<Cmd>
+where Io: Read,
+      Cmd: for <'de> Deserialize<'de>,
+{
+    let mut header = [0; HEADER_LENGTH];
+    if limit < HEADER_LENGTH as u64 {
+        return Err(anyhow!(Truncated));
+    }
+    match io.read_exact(&mut header) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(anyhow!(Truncated));
+        },
+        r => r?,
+    }
+
+    let mut length = [0; 4];
+    let mut length_checksum = [0; 4];
+    let mut checksum = [0; 4];
+    length.copy_from_slice(&header[..4]);
+    length_checksum.copy_from_slice(&header[4..8]);
+    checksum.copy_from_slice(&header[8..]);
+
+    if crc32fast::hash(&length) != u32::from_le_bytes(length_checksum) {
+        return Err(anyhow!("frame length checksum mismatch"));
+    }
+
+    let length = u32::from_le_bytes(length);
+    let checksum = u32::from_le_bytes(checksum);
+
+    if u64::from(length) > limit - HEADER_LENGTH as u64 {
+        return Err(anyhow!(Truncated));
+    }
+
+    let body_length = usize::try_from(length).expect("usize");
+    let mut body = vec![0; body_length];
+    match io.read_exact(&mut body) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(anyhow!(Truncated));
+        },
+        r => r?,
+    }
+
+    if crc32fast::hash(&body) != checksum {
+        return Err(anyhow!("frame checksum mismatch"));
+    }
+
+    let cmd: Cmd = serde_cbor::from_slice(&body)?;
+
+    Ok(cmd)
+}
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "truncated frame")
+    }
+}
+
+impl std::error::Error for Truncated { }
+
+const HEADER_LENGTH: usize = 12;
@@ -0,0 +1,131 @@
+//! The changes made by commits, published to subscribers.
+//!
+//! Changes are collected from each batch's own writes,
+//! queued in commit order under the commit lock,
+//! and sent after it is released.
+//! Subscribers resuming from an earlier commit
+//! first replay the changes still in the logs.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use async_channel::{Sender, Receiver};
+use crate::batch_player::IndexOp;
+use crate::types::{Address, Commit, Key, Value};
+
+/// The changes made to each tree by one commit.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug)]
+#[derive(Eq, PartialEq)]
+pub struct ChangeBatch {
+    pub commit: Commit,
+    pub trees: BTreeMap<String, Vec<Change>>,
+}
+
+/// A change to a tree, in the order the batch made it.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug)]
+#[derive(Eq, PartialEq)]
+pub enum Change {
+    Write {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    DeleteRange {
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+    },
+    Merge {
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    },
+}
+
+pub struct Subscribers {
+    /// The number of batches each subscriber buffers
+    capacity: usize,
+    /// Each subscriber, and the first commit it receives
+    senders: Mutex<Vec<(Commit, Sender<ChangeBatch>)>>,
+    /// Batches queued in commit order, not yet sent
+    queue: Mutex<VecDeque<ChangeBatch>>,
+}
+
+impl Subscribers {
+    pub fn new(capacity: usize) -> Subscribers {
+        assert!(capacity > 0);
+        Subscribers {
+            capacity,
+            senders: Mutex::new(Vec::new()),
+            queue: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Subscribe to the batches of `from` and later commits.
+    pub fn subscribe(&self, from: Commit) -> Receiver<ChangeBatch> {
+        let (tx, rx) = async_channel::bounded(self.capacity);
+        self.senders.lock().expect("lock").push((from, tx));
+        rx
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.lock().expect("lock").is_empty()
+    }
+
+    /// Queue a batch to be sent by [`Subscribers::flush`].
+    ///
+    /// Batches must be queued in commit order.
+    pub fn queue(&self, batch: ChangeBatch) {
+        self.queue.lock().expect("lock").push_back(batch);
+    }
+
+    /// Send the queued batches to every subscriber, in commit order.
+    ///
+    /// A subscriber whose buffer is full has fallen behind,
+    /// and is ended instead of being sent more,
+    /// as are those that have gone away.
+    pub fn flush(&self) {
+        // Holding the senders keeps concurrent flushes in order
+        let mut senders = self.senders.lock().expect("lock");
+        loop {
+            let batch = match self.queue.lock().expect("lock").pop_front() {
+                Some(batch) => batch,
+                None => break,
+            };
+            senders.retain(|(from, tx)| {
+                batch.commit < *from || tx.try_send(batch.clone()).is_ok()
+            });
+        }
+    }
+}
+
+/// Read the values of a batch's ops from the log they were written to.
+pub async fn resolve<F, Fut>(ops: Vec<IndexOp>, read_value: F) -> Result<Vec<Change>>
+where F: Fn(Key, Address) -> Fut,
+      Fut: std::future::Future<Output = Result<Value>>,
+{
+    let mut changes = Vec::with_capacity(ops.len());
+    for op in ops {
+        let change = match op {
+            IndexOp::Write { key, address } => {
+                let value = read_value(key.clone(), address).await?;
+                Change::Write { key: key.0, value: value.0 }
+            },
+            IndexOp::Delete { key, .. } => {
+                Change::Delete { key: key.0 }
+            },
+            IndexOp::DeleteRange { start_key, end_key, .. } => {
+                Change::DeleteRange { start_key: start_key.0, end_key: end_key.0 }
+            },
+            IndexOp::Merge { key, address } => {
+                let operand = read_value(key.clone(), address).await?;
+                Change::Merge { key: key.0, operand: operand.0 }
+            },
+        };
+        changes.push(change);
+    }
+    Ok(changes)
+}
@@ -0,0 +1,54 @@
+//! Checkpoints a database's indexes in the background,
+//! every so many commits.
+
+use log::error;
+use std::sync::{Mutex, mpsc};
+use std::thread;
+use anyhow::Result;
+use crate::types::Commit;
+
+#[derive(Debug)]
+pub struct Checkpointer {
+    tx: Mutex<mpsc::Sender<Commit>>,
+    interval: u64,
+}
+
+impl Checkpointer {
+    /// Start the checkpoint thread.
+    ///
+    /// `checkpoint` returns `None` once the database is gone,
+    /// and the thread also exits when the `Checkpointer` is dropped.
+    pub fn start<F>(interval: u64, mut checkpoint: F) -> Checkpointer
+    where F: FnMut() -> Option<Result<Commit>> + Send + 'static
+    {
+        assert!(interval > 0);
+        let (tx, rx) = mpsc::channel::<Commit>();
+
+        thread::spawn(move || {
+            while let Ok(commit) = rx.recv() {
+                // Any other requests are covered by this checkpoint
+                while rx.try_recv().is_ok() { }
+
+                match checkpoint() {
+                    Some(Ok(_)) => { },
+                    Some(Err(e)) => {
+                        error!("error checkpointing indexes after commit {}: {}", commit.0, e);
+                    },
+                    None => break,
+                }
+            }
+        });
+
+        Checkpointer {
+            tx: Mutex::new(tx),
+            interval,
+        }
+    }
+
+    /// Checkpoint after every interval's worth of commits.
+    pub fn committed(&self, commit: Commit) {
+        if commit.0 % self.interval == self.interval - 1 {
+            let _ = self.tx.lock().expect("lock").send(commit);
+        }
+    }
+}
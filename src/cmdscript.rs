@@ -33,6 +33,15 @@ pub enum Command {
     Iterate {
         tree: String,
     },
+    Compact {
+        tree: String,
+    },
+    CreateTree {
+        tree: String,
+    },
+    DropTree {
+        tree: String,
+    },
 
     BatchOpen {
         batch: String,
@@ -88,15 +97,36 @@ pub enum Command {
     ViewClose {
         view: String,
     },
+
+    Sync,
+    Crash,
+    FailAppend {
+        after: u64,
+    },
+    FailSync {
+        fail: bool,
+    },
 }
 
 pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
     let config = db::DbConfig {
         dir: path,
         trees: vec!["t1".to_string(), "t2".to_string()],
+        log_format: db::LogFormat::Toml,
+        truncate_torn_writes: false,
+        log_segment_size: 1024 * 1024,
+        durability: db::Durability::Manual,
+        mem_disk: Some(db::MemDisk::new()),
+        lock_timeout: None,
+        merge_operators: Default::default(),
+        rollback_to: None,
+        truncate_rollback: false,
+        index_checkpoint_interval: None,
+        subscription_capacity: 1024,
     };
 
-    let db = db::Db::open(config).await?;
+    let faults = db::raw::faulty_log_file::Faults::new();
+    let mut db = db::Db::open_with_faults(config.clone(), faults.clone()).await?;
 
     let mut batches: HashMap<String, db::WriteBatch> = HashMap::new();
     let mut views: HashMap<String, db::ReadView> = HashMap::new();
@@ -156,7 +186,7 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
             Command::Iterate { tree } => {
                 let view = db.read_view();
                 let tree = view.tree(&tree);
-                let mut cursor = tree.cursor();
+                let mut cursor = tree.cursor()?;
                 cursor.seek_first();
                 while cursor.valid() {
                     let key = String::from_utf8(cursor.key()).expect("utf8");
@@ -165,6 +195,15 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
                     cursor.next();
                 }
             },
+            Command::Compact { tree } => {
+                db.compact(&tree).await?;
+            },
+            Command::CreateTree { tree } => {
+                db.create_tree(&tree).await?;
+            },
+            Command::DropTree { tree } => {
+                db.drop_tree(&tree).await?;
+            },
 
             Command::BatchOpen { batch } => {
                 let batch_ = db.write_batch().await?;
@@ -228,7 +267,7 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
             Command::ViewIterate { view, tree } => {
                 let view = views.get(&view).expect("view");
                 let tree = view.tree(&tree);
-                let mut cursor = tree.cursor();
+                let mut cursor = tree.cursor()?;
                 cursor.seek_first();
                 while cursor.valid() {
                     let key = String::from_utf8(cursor.key()).expect("utf8");
@@ -241,6 +280,23 @@ pub async fn exec(path: Option<PathBuf>, commands: Vec<Command>) -> Result<()> {
                 views.remove(&view);
             },
 
+            Command::Sync => {
+                db.sync().await?;
+            },
+            Command::Crash => {
+                faults.crash().await?;
+                batches.clear();
+                views.clear();
+                drop(db);
+                db = db::Db::open_with_faults(config.clone(), faults.clone()).await?;
+            },
+            Command::FailAppend { after } => {
+                faults.fail_append(faults.appends() + after);
+            },
+            Command::FailSync { fail } => {
+                faults.fail_sync(fail);
+            },
+
             _ => panic!(),
         }
     }
@@ -306,6 +362,18 @@ pub fn parse_commands(iter: impl Iterator<Item = String>) -> Result<(Option<Path
                     let tree = parse_tree(iter)?;
                     Command::Iterate { tree }
                 },
+                "compact" => {
+                    let tree = parse_tree(iter)?;
+                    Command::Compact { tree }
+                },
+                "create-tree" => {
+                    let tree = parse_tree(iter)?;
+                    Command::CreateTree { tree }
+                },
+                "drop-tree" => {
+                    let tree = parse_tree(iter)?;
+                    Command::DropTree { tree }
+                },
 
                 "batch-open" => {
                     let batch = parse_batch(iter)?;
@@ -376,6 +444,26 @@ pub fn parse_commands(iter: impl Iterator<Item = String>) -> Result<(Option<Path
                     Command::ViewClose { view }
                 },
 
+                "sync" => {
+                    Command::Sync
+                },
+                "crash" => {
+                    Command::Crash
+                },
+                "fail-append" => {
+                    let after = iter.next().ok_or_else(|| anyhow!("expected append count"))?;
+                    Command::FailAppend { after: after.parse()? }
+                },
+                "fail-sync" => {
+                    let fail = iter.next().ok_or_else(|| anyhow!("expected on or off"))?;
+                    let fail = match fail.as_ref() {
+                        "on" => true,
+                        "off" => false,
+                        _ => bail!("expected on or off"),
+                    };
+                    Command::FailSync { fail }
+                },
+
                 _ => {
                     bail!("unknown command '{}'", next_command);
                 }
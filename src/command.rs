@@ -24,6 +24,12 @@ pub enum Command {
         start_key: Key,
         end_key: Key,
     },
+    /// An operand for the tree's merge operator to fold into the key's value.
+    Merge {
+        batch: Batch,
+        key: Key,
+        operand: Value,
+    },
     PushSavePoint {
         batch: Batch,
     },
@@ -54,6 +60,7 @@ impl Command {
             | Write { batch, .. }
             | Delete { batch, .. }
             | DeleteRange { batch, .. }
+            | Merge { batch, .. }
             | PushSavePoint { batch, .. }
             | PopSavePoint { batch, .. }
             | RollbackSavePoint { batch, .. }
@@ -1,11 +1,15 @@
 use serde::{Serialize, Deserialize};
 use crate::log::Log;
-use crate::types::{Commit, BatchCommit, Batch};
+use crate::types::{Address, Commit, BatchCommit, Batch};
 use futures::{Stream, StreamExt};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
 use anyhow::Result;
 
 pub struct CommitLog {
     log: Log<CommitCommand>,
+    /// The address of the last commit, for index checkpoints
+    last: Arc<Mutex<Option<Address>>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -14,11 +18,18 @@ pub struct CommitCommand {
     pub batch: Batch,
     pub batch_commit: BatchCommit,
     pub commit: Commit,
+    /// The trees the batch wrote to,
+    /// or every tree if not recorded.
+    #[serde(default)]
+    pub trees: Option<Vec<String>>,
 }
 
 impl CommitLog {
     pub fn new(log: Log<CommitCommand>) -> CommitLog {
-        CommitLog { log }
+        CommitLog {
+            log,
+            last: Arc::new(Mutex::new(None)),
+        }
     }
 
     pub async fn is_empty(&self) -> Result<bool> {
@@ -29,11 +40,67 @@ impl CommitLog {
         self.log.replay().map(|r| r.map(|(cmd, _)| cmd))
     }
 
-    pub async fn commit(&self, batch: Batch, batch_commit: BatchCommit, commit: Commit) -> Result<()> {
-        self.log.append(CommitCommand {
-            batch, batch_commit, commit
+    /// Replay the commits after the one at `last`, or all of them,
+    /// keeping the address of the last one read.
+    pub fn replay_after(&self, last: Option<Address>) -> impl Stream<Item = Result<CommitCommand>> + Unpin {
+        let replay = match last {
+            Some(last) => self.log.replay_after(last).left_stream(),
+            None => self.log.replay().right_stream(),
+        };
+        let self_last = self.last.clone();
+        *self_last.lock().expect("lock") = last;
+        replay.map(move |r| r.map(|(cmd, addr)| {
+            *self_last.lock().expect("lock") = Some(addr);
+            cmd
+        }))
+    }
+
+    /// The address of the last commit appended or replayed.
+    pub fn last(&self) -> Option<Address> {
+        *self.last.lock().expect("lock")
+    }
+
+    pub async fn commit(&self, batch: Batch, batch_commit: BatchCommit, commit: Commit, trees: Vec<String>) -> Result<()> {
+        let address = self.log.append(CommitCommand {
+            batch, batch_commit, commit,
+            trees: Some(trees),
         }).await?;
+        *self.last.lock().expect("lock") = Some(address);
 
         Ok(())
     }
+
+    /// Discard the commits from `commit_limit` on,
+    /// returning the batch commits of those before it.
+    pub async fn truncate(&self, commit_limit: Commit) -> Result<BTreeSet<(Batch, BatchCommit)>> {
+        let mut commits = BTreeSet::new();
+        let mut replay = self.log.replay();
+        while let Some(cmd) = replay.next().await {
+            let (cmd, addr) = cmd?;
+            if cmd.commit >= commit_limit {
+                drop(replay);
+                self.log.truncate(addr).await?;
+                break;
+            }
+            commits.insert((cmd.batch, cmd.batch_commit));
+        }
+        Ok(commits)
+    }
+
+    /// Append the commits before `commit_limit` to `dest`.
+    pub async fn copy_to(&self, dest: &CommitLog, commit_limit: Commit) -> Result<()> {
+        let mut replay = self.replay();
+        while let Some(cmd) = replay.next().await {
+            let cmd = cmd?;
+            if cmd.commit >= commit_limit {
+                break;
+            }
+            dest.log.append(cmd).await?;
+        }
+        dest.sync().await
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        self.log.sync().await
+    }
 }
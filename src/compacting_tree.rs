@@ -12,9 +12,12 @@
 //!
 //!   The tree that is being compacted.
 //!   There may be outstanding write batches or read views
-//!   attached to this at the time compaction is requested.
-//!   Compaction will not actually begin until all batches
-//!   against it are closed, making the tree "done".
+//!   attached to the active tree at the time compaction is requested.
+//!   The active tree does not become the compacting tree
+//!   until all batches against it are closed, making the tree "done".
+//!   Until then new batches are not opened on it,
+//!   but wait to be opened on the next active tree,
+//!   so that a steady stream of batches can't hold off compaction.
 //!
 //!   This is the second tree searched for reads.
 //!
@@ -38,61 +41,365 @@
 //!   the time a compaction finished.
 //!
 //!   They are waiting to be deleted.
+//!
+//! Because the compacting tree is done before the active tree is
+//! written to, every commit in the active tree is later than every
+//! commit in the compacting and compacted trees, and reads can
+//! simply take the value from the first tree that knows about a key.
 
 // stylistic choice of explicit drops in this file
 #![allow(dropping_references)]
 
-use anyhow::Result;
+use anyhow::{Result, bail};
 use async_channel::{self, Sender, Receiver};
-use std::sync::{RwLock, Mutex, Arc, RwLockWriteGuard};
+use futures::future;
+use std::sync::{RwLock, Mutex, Arc, RwLockReadGuard};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use crate::tree::{self, Tree};
-use crate::types::{Commit, Batch, BatchCommit, Key, Value};
+use crate::tree_logs::{TreeLogs, LogName};
+use crate::index::Lookup;
+use crate::batch_player::IndexOp;
+use crate::merge::{self, MergeOperator};
+use crate::changes::{self, Change};
+use crate::types::{Address, Commit, Batch, BatchCommit, Key, KeyRange, Value};
 
 /// Just one batch number in compacted logs
 const COMPACTED_BATCH_NUM: Batch = Batch(0);
 const COMPACTED_BATCH_COMMIT_NUM: BatchCommit = BatchCommit(0);
+/// The commit that compacted logs are indexed at
+const COMPACTED_COMMIT_NUM: Commit = Commit(0);
 
 pub struct CompactingTree {
+    logs: Arc<TreeLogs>,
     trees: Arc<RwLock<Trees>>,
     compact_state: Arc<Mutex<CompactState>>,
+    /// The number of batches writing to the active tree
+    open_batches: Arc<AtomicUsize>,
+    merge: Option<MergeOperator>,
+    /// Set once the tree is dropped, after which batches can't write to it
+    dropped: Arc<AtomicBool>,
+}
+
+#[derive(Clone)]
+struct NamedTree {
+    name: LogName,
+    tree: Arc<Tree>,
 }
 
 enum Trees {
     Initial {
-        active: Tree,
+        active: NamedTree,
     },
     InitialCompacting {
-        active: Tree,
-        compacting: Tree,
-        compacted_wip: Tree,
+        active: NamedTree,
+        compacting: NamedTree,
     },
     Normal {
-        active: Tree,
-        compacted: Tree,
-        trash: Vec<Tree>,
+        active: NamedTree,
+        compacted: NamedTree,
+        trash: Vec<NamedTree>,
     },
     Compacting {
-        active: Tree,
-        compacting: Tree,
-        compacted: Tree,
-        compacted_wip: Tree,
-        trash: Vec<Tree>,
+        active: NamedTree,
+        compacting: NamedTree,
+        compacted: NamedTree,
+        trash: Vec<NamedTree>,
     }
 }
 
 enum CompactState {
     NotCompacting,
+    /// Waiting for the batches open on the active tree to close.
+    ///
+    /// The last batch to close moves the trees for compaction
+    /// and reports whether there was anything to compact.
+    ///
+    /// New batches wait for this state to end,
+    /// which closes the `ended` channel.
+    WaitingForBatches {
+        started: Sender<bool>,
+        ended: (Sender<()>, Receiver<()>),
+    },
     Compacting,
 }
 
+/// Resets the compaction state when `compact` returns or is abandoned.
+struct CompactStateGuard<'tree>(&'tree Mutex<CompactState>);
+
 pub struct BatchWriter {
+    writer: tree::BatchWriter,
+    logs: Arc<TreeLogs>,
+    trees: Arc<RwLock<Trees>>,
+    compact_state: Arc<Mutex<CompactState>>,
+    open_batches: Arc<AtomicUsize>,
+    merge: Option<MergeOperator>,
+    dropped: Arc<AtomicBool>,
+}
+
+/// Prevents compaction from changing the trees while views are created.
+pub struct ViewLock<'tree> {
+    trees: RwLockReadGuard<'tree, Trees>,
+    merge: &'tree Option<MergeOperator>,
+}
+
+/// A consistent set of trees to read from.
+#[derive(Clone)]
+pub struct View {
+    layers: Vec<Layer>,
+    merge: Option<MergeOperator>,
+}
+
+#[derive(Clone)]
+struct Layer {
+    tree: Arc<Tree>,
+    commit_limit: Commit,
+    /// For a compacted tree, the commit it was compacted up to
+    compacted: Option<Commit>,
+}
+
+/// The logs of a compacting tree at one time,
+/// kept from deletion by compaction until dropped.
+pub struct LogsSnapshot {
+    /// Each tree, and the address its log ended at
+    trees: Vec<(NamedTree, Option<Address>)>,
+}
+
+pub struct InitTrees {
+    trees: Vec<(Commit, Arc<Tree>)>,
+}
+
+pub struct InitReplayer<'trees> {
+    players: Vec<(Commit, tree::InitReplayer<'trees>)>,
+}
+
+/// Replays the changes of chosen commits from each writable log in turn.
+pub struct ChangeReplayer {
+    /// The trees not yet replayed, the next last
+    trees: Vec<Arc<Tree>>,
+    current: Option<(Arc<Tree>, tree::CommitReplayer)>,
 }
 
 pub struct Cursor {
+    layers: Vec<Layer>,
+    merge: Option<MergeOperator>,
     trees: Vec<tree::Cursor>,
     current: Option<usize>,
 }
 
+impl CompactingTree {
+    /// Open the logs of a tree, discarding any left over
+    /// from compactions that finished or were interrupted.
+    ///
+    /// The compacted tree is loaded here;
+    /// the active trees must still be initialized
+    /// by replaying them against the commit log.
+    ///
+    /// Merges into the tree are folded by `merge`,
+    /// and fail if it is `None`.
+    pub async fn open(logs: TreeLogs,
+                      truncate_torn_writes: bool,
+                      merge: Option<MergeOperator>) -> Result<CompactingTree> {
+        let names = logs.list().await?;
+
+        let mut compacted = None;
+        let mut obsolete = vec![];
+
+        for name in names.iter().rev() {
+            if let LogName::Compacted(_) = name {
+                if compacted.is_some() {
+                    obsolete.push(*name);
+                    continue;
+                }
+
+                let tree = Tree::new(logs.open(*name));
+                let complete = tree.init_single_batch(COMPACTED_BATCH_NUM,
+                                                      COMPACTED_BATCH_COMMIT_NUM,
+                                                      COMPACTED_COMMIT_NUM).await?;
+                if complete {
+                    compacted = Some(NamedTree {
+                        name: *name,
+                        tree: Arc::new(tree),
+                    });
+                } else {
+                    log::warn!("discarding incomplete compacted log {:?}", name);
+                    obsolete.push(*name);
+                }
+            }
+        }
+
+        let base_commit = match compacted {
+            Some(NamedTree { name: LogName::Compacted(commit), .. }) => commit,
+            _ => Commit(0),
+        };
+
+        let mut active_starts = vec![];
+        for name in names {
+            if let LogName::Active(start) = name {
+                if start < base_commit {
+                    obsolete.push(name);
+                } else {
+                    active_starts.push(start);
+                }
+            }
+        }
+
+        if active_starts.is_empty() {
+            active_starts.push(base_commit);
+        }
+
+        if active_starts[0] != base_commit {
+            bail!("missing tree log for commits from {}", base_commit.0);
+        }
+
+        if active_starts.len() > 2 {
+            bail!("unexpected tree logs for commits from {:?}", active_starts);
+        }
+
+        for name in obsolete {
+            logs.remove(name).await?;
+        }
+
+        // Incomplete compacted logs are discarded above,
+        // but active logs may hold commits.
+        let mut active_trees = vec![];
+        for start in active_starts {
+            let name = LogName::Active(start);
+            let log = logs.open(name);
+            log.recover(truncate_torn_writes).await?;
+            active_trees.push(NamedTree {
+                name,
+                tree: Arc::new(Tree::starting_at(log, start)),
+            });
+        }
+
+        let active = active_trees.pop().expect("active");
+        let compacting = active_trees.pop();
+
+        let trees = match (compacting, compacted) {
+            (None, None) => Trees::Initial { active },
+            (Some(compacting), None) => Trees::InitialCompacting { active, compacting },
+            (None, Some(compacted)) => Trees::Normal { active, compacted, trash: vec![] },
+            (Some(compacting), Some(compacted)) => Trees::Compacting { active, compacting, compacted, trash: vec![] },
+        };
+
+        Ok(CompactingTree {
+            logs: Arc::new(logs),
+            trees: Arc::new(RwLock::new(trees)),
+            compact_state: Arc::new(Mutex::new(CompactState::NotCompacting)),
+            open_batches: Arc::new(AtomicUsize::new(0)),
+            merge,
+            dropped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Commits before this have been compacted together.
+    pub fn history_start(&self) -> Commit {
+        let trees = self.trees.read().expect("lock");
+        trees.history_start()
+    }
+
+    /// Discard the batches after `commits` from the logs,
+    /// before the trees are initialized.
+    ///
+    /// An active log started after `commit_limit`
+    /// only holds later batches, so is removed,
+    /// and the log it would have been compacted from is active again.
+    pub async fn truncate_logs(&self,
+                               commits: &BTreeSet<(Batch, BatchCommit)>,
+                               commit_limit: Commit) -> Result<()> {
+        assert!(commit_limit >= self.history_start());
+
+        let (removed, writable) = {
+            let mut trees = self.trees.write().expect("lock");
+            let after_limit = match trees.active().name {
+                LogName::Active(start) => start > commit_limit,
+                LogName::Compacted(_) => panic!("compacted log active"),
+            };
+            let new_trees = match &*trees {
+                Trees::InitialCompacting { active, compacting } if after_limit => {
+                    Some((active.clone(), Trees::Initial {
+                        active: compacting.clone(),
+                    }))
+                },
+                Trees::Compacting { active, compacting, compacted, trash } if after_limit => {
+                    Some((active.clone(), Trees::Normal {
+                        active: compacting.clone(),
+                        compacted: compacted.clone(),
+                        trash: trash.clone(),
+                    }))
+                },
+                _ => None,
+            };
+            let removed = new_trees.map(|(removed, new_trees)| {
+                *trees = new_trees;
+                removed
+            });
+            (removed, trees.writable())
+        };
+
+        if let Some(removed) = removed {
+            self.logs.remove(removed.name).await?;
+        }
+
+        for tree in writable {
+            tree.tree.truncate_log(commits).await?;
+        }
+
+        self.logs.sync().await
+    }
+
+    pub fn init_trees(&self) -> InitTrees {
+        let trees = self.trees.read().expect("lock");
+        let trees = trees.writable().into_iter().map(|tree| {
+            match tree.name {
+                LogName::Active(start) => (start, tree.tree.clone()),
+                LogName::Compacted(_) => panic!("writable compacted tree"),
+            }
+        }).collect();
+
+        InitTrees { trees }
+    }
+
+    pub fn skip_init(&self) {
+        let trees = self.trees.read().expect("lock");
+        for tree in trees.writable() {
+            tree.tree.skip_init();
+        }
+    }
+
+    /// Discard what a failed init loaded into the writable trees,
+    /// so that they can be initialized again.
+    pub fn reset_init(&self) {
+        for (start, tree) in self.init_trees().trees {
+            tree.reset_init(start);
+        }
+    }
+
+    /// Stop batches from writing to the tree,
+    /// so that its logs can be destroyed.
+    ///
+    /// Writes and commits by batches still open on the tree fail,
+    /// and their other commands are skipped.
+    pub fn set_dropped(&self) {
+        self.dropped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_dropped(&self) -> bool {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    /// Delete every log of the tree, and its directory.
+    ///
+    /// Views still using the tree will fail.
+    pub async fn destroy(&self) -> Result<()> {
+        assert!(self.is_dropped());
+
+        self.logs.remove_all().await?;
+        self.logs.remove_dir().await
+    }
+}
+
 impl CompactingTree {
     /// Compacts the tree, removing any stale data.
     ///
@@ -101,182 +408,889 @@ impl CompactingTree {
     /// (and so probably should not be awaited),
     /// and it does significant CPU work between IO work.
     ///
+    /// Compaction does not begin until every batch
+    /// writing to the active tree has been closed.
+    ///
     /// Returns `true` if a compaction was performed.
-    /// Returns `false` if a compaction was already in progress.
+    /// Returns `false` if a compaction was already in progress,
+    /// or nothing has been committed since the last compaction.
     pub async fn compact(&self) -> Result<bool> {
 
-        // Claim the compaction routine for this tree
-        {
+        self.try_empty_trash().await?;
+
+        // Claim the compaction routine for this tree,
+        // and set up trees for compaction mode.
+        let waiting_for_batches = {
             let mut compact_state = self.compact_state.lock().expect("lock");
 
             match *compact_state {
-                CompactState::NotCompacting => {
-                    *compact_state = CompactState::Compacting;
-                },
-                CompactState::Compacting => {
+                CompactState::NotCompacting => { },
+                CompactState::WaitingForBatches { .. } | CompactState::Compacting => {
                     return Ok(false);
                 },
             }
 
+            let mut trees = self.trees.write().expect("lock");
+
+            let waiting_for_batches = if trees.is_compacting() {
+                // A previous compaction was interrupted
+                *compact_state = CompactState::Compacting;
+                None
+            } else if self.open_batches.load(Ordering::SeqCst) == 0 {
+                if !move_trees_for_compaction(&self.logs, &mut trees) {
+                    return Ok(false);
+                }
+                *compact_state = CompactState::Compacting;
+                None
+            } else {
+                let (tx, rx) = async_channel::bounded(1);
+                *compact_state = CompactState::WaitingForBatches {
+                    started: tx,
+                    ended: async_channel::bounded(1),
+                };
+                Some(rx)
+            };
+
+            drop(trees);
             drop(compact_state);
-        }
 
-        let compaction_result: Result<_> = async {
-            // Set up trees for compaction mode
-            {
-                let mut trees = self.trees.write().expect("lock");
+            waiting_for_batches
+        };
 
-                // FIXME holding lock across await
-                self.move_trees_for_compaction(&mut trees).await?;
+        let _compact_state_guard = CompactStateGuard(&self.compact_state);
 
-                drop(trees);
+        if let Some(rx) = waiting_for_batches {
+            let moved = self.wait_for_all_writes_to_active_tree(rx).await?;
+            if !moved {
+                return Ok(false);
             }
+        }
 
-            // Open a cursor for the compacting tree,
-            // and the compacted tree, and a writer
-            // for the compacted_wip tree.
-            let (cursor, writer) = {
-                let last_commit = self.wait_for_all_writes_to_compacting_tree().await?;
-                let commit_limit = Commit(last_commit.0.checked_add(1).expect("overflow"));
-
-                let trees = self.trees.read().expect("lock");
-                let (tree_cursors, compacted_wip_writer) = match &*trees {
-                    Trees::InitialCompacting { active, compacting, compacted_wip } => {
-                        drop(active);
-                        let compacting_cursor = compacting.cursor(commit_limit);
-                        let compacted_wip_writer = compacted_wip.batch(COMPACTED_BATCH_NUM);
-                        (vec![compacting_cursor], compacted_wip_writer)
-                    },
-                    Trees::Compacting { active, compacting, compacted, compacted_wip, trash } => {
-                        drop(active);
-                        drop(trash);
-                        let compacting_cursor = compacting.cursor(commit_limit);
-                        let compacted_cursor = compacted.cursor(commit_limit);
-                        let compacted_wip_writer = compacted_wip.batch(COMPACTED_BATCH_NUM);
-                        (vec![compacting_cursor, compacted_cursor], compacted_wip_writer)
-                    },
-                    _ => {
-                        panic!("invalid state during compaction");
-                    }
-                };
+        let compacted = self.write_compacted().await?;
 
-                drop(trees);
+        // Move trees around to end compaction
+        {
+            let mut trees = self.trees.write().expect("lock");
+            move_trees_for_end_compaction(&mut trees, compacted);
+            drop(trees);
+        }
 
-                let cursor = Cursor {
-                    trees: tree_cursors,
-                    current: None,
-                };
+        self.try_empty_trash().await?;
+
+        Ok(true)
+    }
 
-                (cursor, compacted_wip_writer)
+    async fn wait_for_all_writes_to_active_tree(&self, rx: Receiver<bool>) -> Result<bool> {
+        Ok(rx.recv().await?)
+    }
+
+    /// Write the live data in the compacting and compacted trees
+    /// to a new compacted tree.
+    async fn write_compacted(&self) -> Result<NamedTree> {
+        let (view, name) = {
+            let trees = self.trees.read().expect("lock");
+            let (layers, active) = match &*trees {
+                Trees::InitialCompacting { active, compacting } => {
+                    (vec![frozen_layer(compacting)], active)
+                },
+                Trees::Compacting { active, compacting, compacted, trash } => {
+                    drop(trash);
+                    (vec![frozen_layer(compacting), frozen_layer(compacted)], active)
+                },
+                _ => {
+                    panic!("invalid state during compaction");
+                }
+            };
+            let name = match active.name {
+                LogName::Active(start) => LogName::Compacted(start),
+                LogName::Compacted(_) => panic!("compacted active tree"),
             };
 
-            {
-                let mut cursor = cursor;
-                let writer = writer;
+            drop(trees);
 
-                cursor.seek_first();
-                writer.open().await?;
+            let view = View {
+                layers,
+                merge: self.merge.clone(),
+            };
 
-                while cursor.valid() {
-                    let key = cursor.key();
-                    let value = cursor.value().await?;
-                    writer.write(key, value).await?;
-                }
+            (view, name)
+        };
+
+        // Start from an empty log in case an earlier compaction
+        // was interrupted while writing it.
+        self.logs.remove(name).await?;
 
-                writer.ready_commit(COMPACTED_BATCH_COMMIT_NUM).await?;
-                writer.close().await?;
+        let compacted = NamedTree {
+            name,
+            tree: Arc::new(Tree::new(self.logs.open(name))),
+        };
+        compacted.tree.skip_init();
+
+        {
+            let mut cursor = view.cursor();
+            let writer = compacted.tree.batch(COMPACTED_BATCH_NUM);
+
+            cursor.seek_first();
+            writer.open().await?;
+
+            while cursor.valid() {
+                let key = cursor.key();
+                let value = cursor.value().await?;
+                writer.write(key, value).await?;
+                cursor.next();
             }
 
-            Ok(())
-        }.await;
+            writer.ready_commit(COMPACTED_BATCH_COMMIT_NUM).await?;
+            writer.commit_to_index(COMPACTED_BATCH_COMMIT_NUM, COMPACTED_COMMIT_NUM);
 
-        // Move trees around to end compaction
-        let end_compaction_result = async {
-            match compaction_result {
-                Ok(_) => {
-                    let mut trees = self.trees.write().expect("lock");
+            // The close marks the log as complete,
+            // so everything before it must be durable first.
+            compacted.tree.sync().await?;
+            writer.close().await?;
+            compacted.tree.sync().await?;
+            self.logs.sync().await?;
+        }
 
-                    // FIXME holding lock across await
-                    self.move_trees_for_end_compaction(&mut trees).await?;
-                }
-                Err(e) => {
-                    todo!()
+        Ok(compacted)
+    }
+
+    async fn try_empty_trash(&self) -> Result<()> {
+        // Trees only referenced by the trash have no views left.
+        let unused = {
+            let mut trees = self.trees.write().expect("lock");
+            match &mut *trees {
+                Trees::Normal { trash, .. } | Trees::Compacting { trash, .. } => {
+                    let (unused, used) = trash.drain(..).partition(|tree| {
+                        Arc::strong_count(&tree.tree) == 1
+                    });
+                    *trash = used;
+                    unused
+                },
+                Trees::Initial { .. } | Trees::InitialCompacting { .. } => {
+                    vec![]
+                },
+            }
+        };
+
+        for tree in unused {
+            let name = tree.name;
+            drop(tree);
+            self.logs.remove(name).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl CompactingTree {
+    /// Open a batch on the active tree.
+    ///
+    /// While a compaction waits for the batches on the active tree to close
+    /// this waits for the next active tree.
+    pub async fn batch(&self, batch: Batch) -> BatchWriter {
+        loop {
+            let ended = {
+                let compact_state = self.compact_state.lock().expect("lock");
+                match &*compact_state {
+                    CompactState::WaitingForBatches { ended: (_, rx), .. } => rx.clone(),
+                    _ => {
+                        let trees = self.trees.read().expect("lock");
+                        self.open_batches.fetch_add(1, Ordering::SeqCst);
+
+                        return BatchWriter {
+                            writer: trees.active().tree.batch(batch),
+                            logs: self.logs.clone(),
+                            trees: self.trees.clone(),
+                            compact_state: self.compact_state.clone(),
+                            open_batches: self.open_batches.clone(),
+                            merge: self.merge.clone(),
+                            dropped: self.dropped.clone(),
+                        };
+                    },
                 }
+            };
+
+            // Only ever closed
+            let _ = ended.recv().await;
+        }
+    }
+
+    pub fn lock_view(&self) -> ViewLock<'_> {
+        ViewLock {
+            trees: self.trees.read().expect("lock"),
+            merge: &self.merge,
+        }
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        let writable = {
+            let trees = self.trees.read().expect("lock");
+            trees.writable()
+        };
+
+        let syncs = writable.iter().map(|tree| tree.tree.sync());
+        future::try_join_all(syncs).await?;
+
+        self.logs.sync().await
+    }
+
+    /// Whether any key in `range` was changed by a commit at or after `since`.
+    ///
+    /// Compaction discards the history of the commits it compacts,
+    /// so if any of those are at or after `since`
+    /// then the range is assumed to have been changed.
+    pub fn written_since(&self, since: Commit, range: &KeyRange) -> bool {
+        let (writable, history_start) = {
+            let trees = self.trees.read().expect("lock");
+            (trees.writable(), trees.history_start())
+        };
+
+        if since < history_start {
+            return true;
+        }
+
+        writable.iter().any(|tree| tree.tree.written_since(since, range))
+    }
+
+    /// The tree's logs, and where they end now.
+    pub async fn snapshot_logs(&self) -> LogsSnapshot {
+        let all = self.trees.read().expect("lock").all();
+        let mut trees = Vec::with_capacity(all.len());
+        for tree in all {
+            let last = tree.tree.last_appended().await;
+            trees.push((tree, last));
+        }
+        LogsSnapshot { trees }
+    }
+
+    /// The index checkpoints of the writable trees,
+    /// by the commit their logs start from.
+    ///
+    /// This must be taken between commits.
+    pub async fn checkpoint(&self) -> BTreeMap<Commit, tree::Checkpoint> {
+        let writable = {
+            let trees = self.trees.read().expect("lock");
+            trees.writable()
+        };
+
+        let mut checkpoints = BTreeMap::new();
+        for tree in writable {
+            match tree.name {
+                LogName::Active(start) => {
+                    checkpoints.insert(start, tree.tree.checkpoint().await);
+                },
+                LogName::Compacted(_) => panic!("writable compacted tree"),
             }
+        }
 
-            Ok(true)
-        }.await;
+        checkpoints
+    }
+
+    /// Replay the changes of each of `commits` to this tree
+    /// from the logs of the writable trees.
+    ///
+    /// Fails if any of the commits have been compacted.
+    pub fn change_replayer(&self, commits: &BTreeMap<(Batch, BatchCommit), Commit>) -> Result<ChangeReplayer> {
+        // Holding the trees keeps them from being deleted by compaction
+        let (writable, history_start) = {
+            let trees = self.trees.read().expect("lock");
+            (trees.writable(), trees.history_start())
+        };
 
-        if let Err(e) = end_compaction_result {
-            todo!()
+        if let Some(commit) = commits.values().find(|commit| **commit < history_start) {
+            bail!("changes of commit {} have been compacted", commit.0);
         }
 
-        {
-            let mut compact_state = self.compact_state.lock().expect("lock");
-            *compact_state = CompactState::NotCompacting;
+        Ok(ChangeReplayer {
+            trees: writable.into_iter().rev().map(|tree| tree.tree).collect(),
+            current: None,
+        })
+    }
+
+    /// Discard index history that no read at `horizon` or later can see.
+    ///
+    /// Only the writable trees are collected:
+    /// the compacted tree has a single version of each key,
+    /// and the trash will be deleted.
+    /// Deletes are only discarded from the oldest tree,
+    /// where they no longer hide anything.
+    pub fn collect_garbage(&self, horizon: Commit) -> usize {
+        let (all, writable) = {
+            let trees = self.trees.read().expect("lock");
+            (trees.all(), trees.writable())
+        };
+
+        writable.iter().map(|tree| {
+            let bottom = Arc::ptr_eq(&tree.tree, &all[0].tree);
+            tree.tree.collect_garbage(horizon, bottom)
+        }).sum()
+    }
+
+    /// The number of versions and range deletes in the indexes of each tree.
+    pub fn index_entries(&self) -> usize {
+        let all = {
+            let trees = self.trees.read().expect("lock");
+            trees.all()
+        };
+
+        all.iter().map(|tree| tree.tree.index_entries()).sum()
+    }
+}
+
+impl Trees {
+    fn active(&self) -> &NamedTree {
+        match self {
+            Trees::Initial { active }
+            | Trees::InitialCompacting { active, .. }
+            | Trees::Normal { active, .. }
+            | Trees::Compacting { active, .. } => {
+                active
+            }
         }
+    }
 
-        self.try_empty_trash().await?;
+    fn is_compacting(&self) -> bool {
+        match self {
+            Trees::InitialCompacting { .. } | Trees::Compacting { .. } => true,
+            Trees::Initial { .. } | Trees::Normal { .. } => false,
+        }
+    }
 
-        end_compaction_result
+    /// The first commit whose history is kept by the writable trees.
+    fn history_start(&self) -> Commit {
+        match self {
+            Trees::Initial { .. } | Trees::InitialCompacting { .. } => {
+                Commit(0)
+            },
+            Trees::Normal { compacted, .. } | Trees::Compacting { compacted, .. } => {
+                match compacted.name {
+                    LogName::Compacted(start) => start,
+                    LogName::Active(_) => panic!("active log compacted"),
+                }
+            },
+        }
     }
 
-    async fn move_trees_for_compaction(&self, trees: &mut RwLockWriteGuard<'_, Trees>) -> Result<()> {
-        // Move active to compacting
-        // Create compacted_wip
-        match &**trees {
-            Trees::Initial { active } => {
-                todo!()
+    /// The trees that hold the tree's data, oldest first.
+    fn all(&self) -> Vec<NamedTree> {
+        match self {
+            Trees::Normal { compacted, .. } | Trees::Compacting { compacted, .. } => {
+                Some(compacted.clone()).into_iter().chain(self.writable()).collect()
             },
-            Trees::Normal { active, compacted, trash } => {
-                todo!()
+            Trees::Initial { .. } | Trees::InitialCompacting { .. } => {
+                self.writable()
+            },
+        }
+    }
+
+    /// The trees that have been written by batches, oldest first.
+    fn writable(&self) -> Vec<NamedTree> {
+        match self {
+            Trees::Initial { active } | Trees::Normal { active, .. } => {
+                vec![active.clone()]
+            },
+            Trees::InitialCompacting { active, compacting }
+            | Trees::Compacting { active, compacting, .. } => {
+                vec![compacting.clone(), active.clone()]
             },
-            Trees::InitialCompacting { .. } | Trees::Compacting { .. } => {
-                panic!("already compacting");
+        }
+    }
+}
+
+/// Move active to compacting, and create a new active tree.
+///
+/// Returns `false` without moving anything if
+/// the active tree has no commits to compact.
+fn move_trees_for_compaction(logs: &TreeLogs, trees: &mut Trees) -> bool {
+    let active = trees.active();
+    let next_commit = active.tree.next_commit();
+
+    if active.name == LogName::Active(next_commit) {
+        return false;
+    }
+
+    let name = LogName::Active(next_commit);
+    let new_active = NamedTree {
+        name,
+        tree: Arc::new(Tree::starting_at(logs.open(name), next_commit)),
+    };
+    new_active.tree.skip_init();
+
+    let new_trees = match &*trees {
+        Trees::Initial { active } => {
+            Trees::InitialCompacting {
+                active: new_active,
+                compacting: active.clone(),
+            }
+        },
+        Trees::Normal { active, compacted, trash } => {
+            Trees::Compacting {
+                active: new_active,
+                compacting: active.clone(),
+                compacted: compacted.clone(),
+                trash: trash.clone(),
+            }
+        },
+        Trees::InitialCompacting { .. } | Trees::Compacting { .. } => {
+            panic!("already compacting");
+        }
+    };
+
+    *trees = new_trees;
+
+    true
+}
+
+/// Move compacting and compacted to trash,
+/// and the newly compacted tree to compacted.
+fn move_trees_for_end_compaction(trees: &mut Trees, new_compacted: NamedTree) {
+    let new_trees = match &*trees {
+        Trees::InitialCompacting { active, compacting } => {
+            Trees::Normal {
+                active: active.clone(),
+                compacted: new_compacted,
+                trash: vec![compacting.clone()],
+            }
+        },
+        Trees::Compacting { active, compacting, compacted, trash } => {
+            let mut trash = trash.clone();
+            trash.push(compacting.clone());
+            trash.push(compacted.clone());
+            Trees::Normal {
+                active: active.clone(),
+                compacted: new_compacted,
+                trash,
+            }
+        },
+        Trees::Initial { .. } | Trees::Normal { .. } => {
+            panic!("not compacting");
+        }
+    };
+
+    *trees = new_trees;
+}
+
+/// Read a key from the first layer that wrote or deleted it,
+/// merging in the operands of it and the layers before it,
+/// followed by `operands`.
+async fn read_layers(layers: &[Layer],
+                     merge: Option<&MergeOperator>,
+                     key: &Key,
+                     operands: Vec<Value>) -> Result<Option<Value>> {
+    // Newest first
+    let mut merged: Vec<Value> = operands.into_iter().rev().collect();
+    let mut base = None;
+
+    for layer in layers {
+        let mut lookup = layer.tree.lookup(layer.commit_limit, key);
+
+        if let Lookup::Merged { operands, base } = lookup {
+            for addr in operands.into_iter().rev() {
+                merged.push(layer.tree.read_value(key, addr).await?);
             }
+            lookup = *base;
+        }
+
+        match lookup {
+            Lookup::Written(addr) => {
+                base = Some(layer.tree.read_value(key, addr).await?);
+                break;
+            },
+            Lookup::Deleted => {
+                break;
+            },
+            Lookup::Missing => { },
+            Lookup::Merged { .. } => {
+                panic!("merge into merge");
+            },
         }
     }
 
-    async fn move_trees_for_end_compaction(&self, trees: &mut RwLockWriteGuard<'_, Trees>) -> Result<()> {
-        // Move compacted to trash
-        // Move compacted_wip to compacted
-        match &**trees {
-            Trees::InitialCompacting { active, compacting, compacted_wip } => {
-                todo!()
+    if merged.is_empty() {
+        return Ok(base);
+    }
+
+    merged.reverse();
+    Ok(Some(merge::merge(merge, key, base.as_ref(), &merged)?))
+}
+
+/// A layer for a tree that is no longer written to,
+/// all of which is visible.
+fn frozen_layer(tree: &NamedTree) -> Layer {
+    let compacted = match tree.name {
+        LogName::Compacted(start) => Some(start),
+        LogName::Active(_) => None,
+    };
+    Layer {
+        tree: tree.tree.clone(),
+        commit_limit: tree.tree.next_commit(),
+        compacted,
+    }
+}
+
+impl<'tree> Drop for CompactStateGuard<'tree> {
+    fn drop(&mut self) {
+        let mut compact_state = self.0.lock().expect("lock");
+        *compact_state = CompactState::NotCompacting;
+    }
+}
+
+impl BatchWriter {
+    pub async fn open(&self) -> Result<()> {
+        self.check_dropped()?;
+        self.writer.open().await
+    }
+
+    pub async fn write(&self, key: Key, value: Value) -> Result<()> {
+        self.check_dropped()?;
+        self.writer.write(key, value).await
+    }
+
+    pub async fn delete(&self, key: Key) -> Result<()> {
+        self.check_dropped()?;
+        self.writer.delete(key).await
+    }
+
+    pub async fn delete_range(&self, start_key: Key, end_key: Key) -> Result<()> {
+        self.check_dropped()?;
+        self.writer.delete_range(start_key, end_key).await
+    }
+
+    pub async fn merge(&self, key: Key, operand: Value) -> Result<()> {
+        self.check_dropped()?;
+        if self.merge.is_none() {
+            bail!("merge into tree with no merge operator");
+        }
+        self.writer.merge(key, operand).await
+    }
+
+    pub async fn push_save_point(&self) -> Result<()> {
+        if self.is_dropped() {
+            return Ok(());
+        }
+        self.writer.push_save_point().await
+    }
+
+    pub async fn pop_save_point(&self) -> Result<()> {
+        if self.is_dropped() {
+            return Ok(());
+        }
+        self.writer.pop_save_point().await
+    }
+
+    pub async fn rollback_save_point(&self) -> Result<()> {
+        if self.is_dropped() {
+            return Ok(());
+        }
+        self.writer.rollback_save_point().await
+    }
+
+    pub async fn ready_commit(&self, batch_commit: BatchCommit) -> Result<()> {
+        self.check_dropped()?;
+        self.writer.ready_commit(batch_commit).await
+    }
+
+    pub async fn abort_commit(&self, batch_commit: BatchCommit) -> Result<()> {
+        if self.is_dropped() {
+            return Ok(());
+        }
+        self.writer.abort_commit(batch_commit).await
+    }
+
+    pub fn commit_to_index(&self, batch_commit: BatchCommit, commit: Commit) {
+        self.writer.commit_to_index(batch_commit, commit)
+    }
+
+    /// The batch's writes so far, with rolled back save points undone.
+    pub fn pending(&self) -> Vec<IndexOp> {
+        self.writer.pending()
+    }
+
+    /// Read the value of one of the batch's writes.
+    pub async fn read_value(&self, key: &Key, addr: Address) -> Result<Value> {
+        self.writer.read_value(key, addr).await
+    }
+
+    /// NB: This must only be called after the batch is committed
+    pub async fn close(&self) -> Result<()> {
+        if self.is_dropped() {
+            return Ok(());
+        }
+        self.writer.close().await
+    }
+
+    /// Sync the log this batch is written to.
+    pub async fn sync(&self) -> Result<()> {
+        if self.is_dropped() {
+            return Ok(());
+        }
+        self.writer.sync().await
+    }
+
+    /// Whether the tree was dropped after the batch was opened on it.
+    pub fn is_dropped(&self) -> bool {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    fn check_dropped(&self) -> Result<()> {
+        if self.is_dropped() {
+            bail!("tree dropped");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BatchWriter {
+    fn drop(&mut self) {
+        let open_batches = self.open_batches.fetch_sub(1, Ordering::SeqCst);
+        if open_batches != 1 {
+            return;
+        }
+
+        // This was the last batch writing to the active tree.
+        // If a compaction is waiting for it, start the compaction.
+        let mut compact_state = self.compact_state.lock().expect("lock");
+        let started = if let CompactState::WaitingForBatches { started: tx, .. } = &*compact_state {
+            let mut trees = self.trees.write().expect("lock");
+            // Another batch may have been opened in the meantime
+            if self.open_batches.load(Ordering::SeqCst) == 0 {
+                let moved = move_trees_for_compaction(&self.logs, &mut trees);
+                // If nothing was moved, or the compaction was abandoned,
+                // then there is no compaction in progress,
+                // and the next compaction will resume any moved trees.
+                Some(tx.try_send(moved).is_ok() && moved)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        match started {
+            Some(true) => *compact_state = CompactState::Compacting,
+            Some(false) => *compact_state = CompactState::NotCompacting,
+            None => { },
+        }
+    }
+}
+
+impl LogsSnapshot {
+    /// Copy each log, as far as it was written when snapshotted,
+    /// to the same name in `logs`.
+    pub async fn copy_to(&self, logs: &TreeLogs) -> Result<()> {
+        for (tree, last) in &self.trees {
+            tree.tree.copy_log(&logs.open(tree.name), *last).await?;
+        }
+        logs.sync().await
+    }
+}
+
+impl ChangeReplayer {
+    /// The next of `commits` in the logs, and its changes,
+    /// or `None` at the end of the logs.
+    ///
+    /// Commits come in the order their batches were made ready,
+    /// which may differ from commit order.
+    pub async fn next(&mut self,
+                      commits: &BTreeMap<(Batch, BatchCommit), Commit>) -> Result<Option<(Commit, Vec<Change>)>> {
+        loop {
+            if self.current.is_none() {
+                self.current = match self.trees.pop() {
+                    Some(tree) => {
+                        let replayer = tree.commit_replayer();
+                        Some((tree, replayer))
+                    },
+                    None => return Ok(None),
+                };
+            }
+
+            let (tree, replayer) = self.current.as_mut().expect("tree");
+            match replayer.next(commits).await? {
+                Some((commit, ops)) => {
+                    let changes = changes::resolve(ops, |key, addr| {
+                        let tree = tree.clone();
+                        async move { tree.read_value(&key, addr).await }
+                    }).await?;
+                    return Ok(Some((commit, changes)));
+                },
+                None => {
+                    self.current = None;
+                },
+            }
+        }
+    }
+}
+
+impl<'tree> ViewLock<'tree> {
+    /// Views with earlier commit limits can't be created,
+    /// as the commits before this have been compacted together.
+    pub fn history_start(&self) -> Commit {
+        self.trees.history_start()
+    }
+
+    pub fn view(&self, commit_limit: Commit) -> View {
+        let layers = match &*self.trees {
+            Trees::Initial { active } => {
+                vec![active]
+            },
+            Trees::InitialCompacting { active, compacting } => {
+                vec![active, compacting]
+            },
+            Trees::Normal { active, compacted, trash } => {
+                drop(trash);
+                vec![active, compacted]
             },
-            Trees::Compacting { active, compacting, compacted, compacted_wip, trash } => {
-                todo!()
+            Trees::Compacting { active, compacting, compacted, trash } => {
+                drop(trash);
+                vec![active, compacting, compacted]
             },
-            Trees::Initial { .. } | Trees::Normal { .. } => {
-                panic!("not compacting");
+        };
+
+        let mut layers = layers.into_iter();
+        let active = layers.next().expect("active");
+        let active = Layer {
+            tree: active.tree.clone(),
+            commit_limit,
+            compacted: None,
+        };
+
+        View {
+            layers: Some(active).into_iter().chain(layers.map(frozen_layer)).collect(),
+            merge: self.merge.clone(),
+        }
+    }
+}
+
+impl View {
+    pub async fn read(&self, key: &Key) -> Result<Option<Value>> {
+        read_layers(&self.layers, self.merge.as_ref(), key, vec![]).await
+    }
+
+    /// Read a key with `operands`, oldest first, merged into its value.
+    pub async fn read_merged(&self, key: &Key, operands: Vec<Value>) -> Result<Option<Value>> {
+        read_layers(&self.layers, self.merge.as_ref(), key, operands).await
+    }
+
+    /// Each commit that changed the key, oldest first,
+    /// and the key's value after it.
+    ///
+    /// Compacted commits are folded into the last of them.
+    pub async fn history(&self, key: &Key) -> Result<Vec<(Commit, Option<Value>)>> {
+        let mut commits = vec![];
+        for layer in &self.layers {
+            let versions = layer.tree.versions(layer.commit_limit, key);
+            match layer.compacted {
+                Some(start) => {
+                    if !versions.is_empty() {
+                        commits.push(Commit(start.0 - 1));
+                    }
+                },
+                None => {
+                    commits.extend(versions);
+                },
             }
         }
+        commits.sort();
+        commits.dedup();
+
+        let mut history = Vec::with_capacity(commits.len());
+        for commit in commits {
+            // The compacted layer is indexed at commit 0, so is always visible
+            let commit_limit = Commit(commit.0 + 1);
+            let layers: Vec<Layer> = self.layers.iter().map(|layer| {
+                Layer {
+                    commit_limit: layer.commit_limit.min(commit_limit),
+                    .. layer.clone()
+                }
+            }).collect();
+            let value = read_layers(&layers, self.merge.as_ref(), key, vec![]).await?;
+            history.push((commit, value));
+        }
+
+        Ok(history)
     }
 
-    async fn wait_for_all_writes_to_compacting_tree(&self) -> Result<Commit> {
-        todo!()
+    pub fn merge_operator(&self) -> Option<&MergeOperator> {
+        self.merge.as_ref()
     }
 
-    async fn try_empty_trash(&self) -> Result<()> {
-        todo!()
+    pub fn cursor(&self) -> Cursor {
+        let trees = self.layers.iter().map(|layer| {
+            layer.tree.cursor(layer.commit_limit)
+        }).collect();
+
+        Cursor {
+            layers: self.layers.clone(),
+            merge: self.merge.clone(),
+            trees,
+            current: None,
+        }
     }
 }
 
-impl CompactingTree {
-    pub fn batch(&self, batch: Batch) -> BatchWriter {
-        todo!()
+impl InitTrees {
+    pub fn init_replayer(&self) -> InitReplayer<'_> {
+        let players = self.trees.iter().map(|(start, tree)| {
+            (*start, tree.init_replayer())
+        }).collect();
+
+        InitReplayer { players }
+    }
+
+    /// Like `init_replayer`, but resuming the trees
+    /// whose logs have checkpoints taken at `commit_limit`.
+    ///
+    /// Also returns whether every tree was resumed.
+    pub fn resume_replayer(&self,
+                           mut checkpoints: BTreeMap<Commit, tree::Checkpoint>,
+                           commit_limit: Commit) -> (InitReplayer<'_>, bool) {
+        let mut resumed_all = true;
+        let players = self.trees.iter().map(|(start, tree)| {
+            let player = match checkpoints.remove(start) {
+                Some(checkpoint) => tree.resume_replayer(checkpoint, commit_limit),
+                None => {
+                    resumed_all = false;
+                    tree.init_replayer()
+                },
+            };
+            (*start, player)
+        }).collect();
+
+        (InitReplayer { players }, resumed_all)
     }
+}
 
-    pub async fn read(&self, commit_limit: Commit, key: &Key) -> Result<Option<Value>> {
-        todo!()
+impl<'trees> InitReplayer<'trees> {
+    pub async fn replay_commit(&mut self,
+                               batch: Batch,
+                               batch_commit: BatchCommit,
+                               commit: Commit) -> Result<()> {
+        let player = self.players.iter_mut().rev().find(|(start, _)| *start <= commit);
+        if let Some((_, player)) = player {
+            player.replay_commit(batch, batch_commit, commit).await
+        } else {
+            // Already in the compacted tree
+            Ok(())
+        }
     }
 
-    pub fn cursor(&self, commit_limit: Commit) -> Cursor {
-        todo!()
+    pub async fn replay_rest(&mut self) -> Result<(Option<Batch>, Option<BatchCommit>)> {
+        let mut max_batch = None;
+        let mut max_batch_commit = None;
+
+        for (_, player) in self.players.iter_mut() {
+            let (tree_max_batch, tree_max_batch_commit) = player.replay_rest().await?;
+            max_batch = max_batch.max(tree_max_batch);
+            max_batch_commit = max_batch_commit.max(tree_max_batch_commit);
+        }
+
+        Ok((max_batch, max_batch_commit))
     }
 
-    pub fn sync(&self) -> Result<()> {
-        todo!()
+    pub fn init_success(self) {
+        for (_, player) in self.players {
+            player.init_success();
+        }
     }
 }
 
@@ -293,113 +1307,116 @@ impl Cursor {
 
     pub async fn value(&mut self) -> Result<Value> {
         let idx = self.current.expect("invalid cursor");
-        let tree = &mut self.trees[idx];
-        tree.value().await
+        let key = self.key();
+        // The key is visible in the current tree,
+        // and not in any tree before it.
+        let value = read_layers(&self.layers[idx..], self.merge.as_ref(), &key, vec![]).await?;
+        Ok(value.expect("value"))
     }
 
     pub fn next(&mut self) {
         assert!(self.valid());
-        let current_idx = self.current.expect("valid");
-        let current_key = self.trees[current_idx].key();
-        panic!()
+        let current_key = self.key();
+        for tree in self.trees.iter_mut() {
+            tree.seek_key(current_key.clone());
+            if tree.valid() && tree.key() == current_key {
+                tree.next();
+            }
+        }
+        self.settle(Direction::Forward);
     }
 
     pub fn prev(&mut self) {
         assert!(self.valid());
-        let current_idx = self.current.expect("valid");
-        let current_key = self.trees[current_idx].key();
-        panic!()
+        let current_key = self.key();
+        for tree in self.trees.iter_mut() {
+            tree.seek_key_rev(current_key.clone());
+            if tree.valid() && tree.key() == current_key {
+                tree.prev();
+            }
+        }
+        self.settle(Direction::Backward);
     }
 
     pub fn seek_first(&mut self) {
-        let mut key_idx = None;
-        for (new_idx, tree) in self.trees.iter_mut().enumerate() {
+        for tree in self.trees.iter_mut() {
             tree.seek_first();
-            if tree.valid() {
-                let new_key = tree.key();
-                if let Some((ref old_key, _)) = key_idx {
-                    if new_key < *old_key {
-                        key_idx = Some((new_key, new_idx));
-                    } else {
-                        /* pass */
-                    }
-                } else {
-                    key_idx = Some((new_key, new_idx));
-                }
-            }
-        }
-
-        if let Some((_, idx)) = key_idx {
-            self.current = Some(idx);
         }
+        self.settle(Direction::Forward);
     }
 
     pub fn seek_last(&mut self) {
-        let mut key_idx = None;
-        for (new_idx, tree) in self.trees.iter_mut().enumerate() {
+        for tree in self.trees.iter_mut() {
             tree.seek_last();
-            if tree.valid() {
-                let new_key = tree.key();
-                if let Some((ref old_key, _)) = key_idx {
-                    if new_key > *old_key {
-                        key_idx = Some((new_key, new_idx));
-                    } else {
-                        /* pass */
-                    }
-                } else {
-                    key_idx = Some((new_key, new_idx));
-                }
-            }
-        }
-
-        if let Some((_, idx)) = key_idx {
-            self.current = Some(idx);
         }
+        self.settle(Direction::Backward);
     }
 
     pub fn seek_key(&mut self, key: Key) {
-        let mut key_idx = None;
-        for (new_idx, tree) in self.trees.iter_mut().enumerate() {
+        for tree in self.trees.iter_mut() {
             tree.seek_key(key.clone());
-            if tree.valid() {
-                let new_key = tree.key();
-                if let Some((ref old_key, _)) = key_idx {
-                    if new_key < *old_key {
-                        key_idx = Some((new_key, new_idx));
-                    } else {
-                        /* pass */
-                    }
-                } else {
-                    key_idx = Some((new_key, new_idx));
-                }
-            }
-        }
-
-        if let Some((_, idx)) = key_idx {
-            self.current = Some(idx);
         }
+        self.settle(Direction::Forward);
     }
 
     pub fn seek_key_rev(&mut self, key: Key) {
-        let mut key_idx = None;
-        for (new_idx, tree) in self.trees.iter_mut().enumerate() {
+        for tree in self.trees.iter_mut() {
             tree.seek_key_rev(key.clone());
-            if tree.valid() {
-                let new_key = tree.key();
-                if let Some((ref old_key, _)) = key_idx {
-                    if new_key > *old_key {
-                        key_idx = Some((new_key, new_idx));
+        }
+        self.settle(Direction::Backward);
+    }
+
+    /// Point at the nearest key in `direction` that is not
+    /// deleted by a tree earlier than the one it was found in.
+    fn settle(&mut self, direction: Direction) {
+        loop {
+            let mut key_idx: Option<(Key, usize)> = None;
+            for (new_idx, tree) in self.trees.iter().enumerate() {
+                if tree.valid() {
+                    let new_key = tree.key();
+                    if let Some((ref old_key, _)) = key_idx {
+                        let nearer = match direction {
+                            Direction::Forward => new_key < *old_key,
+                            Direction::Backward => new_key > *old_key,
+                        };
+                        if nearer {
+                            key_idx = Some((new_key, new_idx));
+                        } else {
+                            /* pass */
+                        }
                     } else {
-                        /* pass */
+                        key_idx = Some((new_key, new_idx));
                     }
-                } else {
-                    key_idx = Some((new_key, new_idx));
                 }
             }
-        }
 
-        if let Some((_, idx)) = key_idx {
-            self.current = Some(idx);
+            if let Some((key, idx)) = key_idx {
+                let deleted = self.layers[..idx].iter().any(|layer| {
+                    matches!(layer.tree.lookup(layer.commit_limit, &key), Lookup::Deleted)
+                });
+
+                if !deleted {
+                    self.current = Some(idx);
+                    return;
+                }
+
+                for tree in self.trees.iter_mut() {
+                    if tree.valid() && tree.key() == key {
+                        match direction {
+                            Direction::Forward => tree.next(),
+                            Direction::Backward => tree.prev(),
+                        }
+                    }
+                }
+            } else {
+                self.current = None;
+                return;
+            }
         }
     }
 }
+
+enum Direction {
+    Forward,
+    Backward,
+}
@@ -1,10 +1,100 @@
 use crate::pretty as imp;
 
 pub use anyhow::{self, Result};
+use std::path::Path;
+use futures::{Sink, Stream};
+
+/// The number of a commit, counting up from 0.
+pub use imp::Commit;
 
 /// Configuration for a database.
 pub type DbConfig = imp::DbConfig;
 
+/// The on-disk encoding of a database's logs.
+pub type LogFormat = imp::LogFormat;
+
+/// When commits are synced to disk.
+pub type Durability = imp::Durability;
+
+/// Storage for in-memory databases that survives reopening,
+/// and can simulate a crash by discarding unsynced writes.
+pub type MemDisk = imp::MemDisk;
+
+/// The error of a [`Transaction`] that read keys
+/// changed by another commit since its snapshot.
+///
+/// Find it with `anyhow::Error::downcast_ref`.
+/// Its `tree` method names the tree whose reads changed.
+pub type Conflict = imp::Conflict;
+
+/// The error of a [`WriteBatch`] commit whose [`WriteTree::write_if`]
+/// or [`WriteTree::delete_if`] found a value other than expected.
+///
+/// Find it with `anyhow::Error::downcast_ref`.
+/// Its `tree` and `key` methods name the key whose value was unexpected.
+pub type Precondition = imp::Precondition;
+
+/// A tree's function for folding the operands of [`WriteTree::merge`]
+/// into a key's value.
+///
+/// It is called with the key, the key's value before the merges,
+/// if it has one, and the merged operands, oldest first,
+/// and returns the key's new value.
+/// It is called on every read of a merged key until the tree is compacted,
+/// so must always give the same value for the same arguments.
+pub type MergeOperator = imp::MergeOperator;
+
+/// The changes made by one commit, as received from [`Db::subscribe`].
+///
+/// Its `commit` field is the commit's number,
+/// and its `trees` field maps each tree the commit wrote to
+/// to the changes made to it.
+pub type ChangeBatch = imp::ChangeBatch;
+
+/// One write, delete, range delete, or merge of a [`ChangeBatch`].
+///
+/// A tree's changes are in the order they were made,
+/// without those undone by rolled back save points.
+pub type Change = imp::Change;
+
+/// The stream of [`ChangeBatch`]es of a subscription, in commit order.
+pub type ChangeStream = imp::ChangeStream;
+
+/// The findings of [`verify`].
+///
+/// Its `problems` field lists each inconsistency found,
+/// and it displays as a readable report.
+pub type VerifyReport = imp::VerifyReport;
+
+/// An inconsistency between or within a database's logs.
+pub type VerifyProblem = imp::VerifyProblem;
+
+/// Check the logs of the database configured by `config`
+/// without opening it or changing them.
+///
+/// Every commit must have been readied or aborted
+/// in each tree it wrote to, and be numbered after the one before,
+/// and each tree log's batches must be opened, closed,
+/// and push save points before popping them.
+/// Logs that can't be read to the end are reported too.
+///
+/// Problems with the logs are reported, not returned as errors.
+/// A database that has crashed may have unclosed batches,
+/// which are harmless.
+///
+/// Fails if there is no database in the directory,
+/// a tree's directory is missing,
+/// or a log is missing any of its segments.
+pub async fn verify(config: &DbConfig) -> Result<VerifyReport> { imp::verify(config).await }
+
+/// Whether a lock taken by [`WriteTree::lock`] may be shared.
+pub type LockMode = imp::LockMode;
+
+/// The error of a [`WriteTree::lock`] that timed out or would deadlock.
+///
+/// Find it with `anyhow::Error::downcast_ref`.
+pub type LockError = imp::LockError;
+
 /// A key-value data store with
 /// multiple trees,
 /// batch commits,
@@ -28,18 +118,162 @@ pub struct ReadTree<'view>(imp::ReadTree<'view>);
 /// A cursor over the keys and values of a `ReadTree`.
 pub struct Cursor(imp::Cursor);
 
+/// A write batch whose reads are from a consistent snapshot,
+/// and which only commits if they haven't changed since.
+///
+/// Reads don't see the transaction's own writes.
+pub struct Transaction(imp::Transaction);
+
+/// A read and write handle to a single tree in a `Transaction`.
+pub struct TransactionTree<'tx>(imp::TransactionTree<'tx>);
+
 impl Db {
     /// Open a new or existing database.
     pub async fn open(config: DbConfig) -> Result<Db> { imp::Db::open(config).await.map(Db) }
 
+    /// Open a database whose logs fail as directed.
+    #[doc(hidden)]
+    pub async fn open_with_faults(config: DbConfig, faults: crate::raw::faulty_log_file::Faults) -> Result<Db> { imp::Db::open_with_faults(config, faults).await.map(Db) }
+
     /// Create a write batch ([`WriteBatch`]).
     pub async fn write_batch(&self) -> Result<WriteBatch> { Ok(WriteBatch(self.0.write_batch().await?)) }
 
     /// Create a read view ([`ReadView`]).
     pub fn read_view(&self) -> ReadView { ReadView(self.0.read_view()) }
 
+    /// Create a read view of the database as it was before `commit`,
+    /// as reported by [`ReadView::commit`].
+    ///
+    /// Fails if `commit` is after the latest commit,
+    /// or if its history has been discarded by compaction
+    /// or garbage collection.
+    pub fn read_view_at(&self, commit: Commit) -> Result<ReadView> { self.0.read_view_at(commit).map(ReadView) }
+
+    /// Subscribe to the changes made by every later commit.
+    ///
+    /// Up to [`DbConfig`]'s `subscription_capacity` commits
+    /// are buffered until received.
+    /// A subscription that falls further behind is ended,
+    /// and can be resumed with [`Db::subscribe_from`]
+    /// after the last commit received.
+    pub async fn subscribe(&self) -> Result<ChangeStream> { self.0.subscribe().await }
+
+    /// Subscribe to the changes made by `commit` and every later commit.
+    ///
+    /// The changes of past commits are replayed from the logs,
+    /// so the stream can be resumed after the last commit received.
+    /// Changes to trees that have since been dropped are not replayed.
+    ///
+    /// Fails if `commit` is after the latest commit,
+    /// or if compaction has discarded the changes of any commit since.
+    pub async fn subscribe_from(&self, commit: Commit) -> Result<ChangeStream> { self.0.subscribe_from(commit).await }
+
+    /// Send the changes of the commits after those seen by
+    /// a read view at `commit` to `sink`, in commit order,
+    /// each as it is read from the logs.
+    ///
+    /// `commit` may be the commit of an earlier backup or [`Db::checkpoint`],
+    /// and the returned commit is the one to pass to the next backup.
+    /// [`ChangeBatch`] can be serialized for storing backups.
+    ///
+    /// Fails if any of the commits have been compacted,
+    /// or wrote to a tree that has since been dropped,
+    /// in which case nothing is sent.
+    pub async fn backup_since<S>(&self, commit: Commit, sink: S) -> Result<Commit> where S: Sink<ChangeBatch> + Unpin, S::Error: std::error::Error + Send + Sync + 'static { self.0.backup_since(commit, sink).await }
+
+    /// Commit the [`ChangeBatch`]es of a backup, in order,
+    /// creating any trees they write to that don't exist.
+    ///
+    /// Each batch is committed with the same number as in the backed up database,
+    /// so must come after every commit already in this one.
+    /// The numbers skipped before a batch are skipped by its commit,
+    /// so a restore that stops partway can be continued,
+    /// even after reopening the database.
+    /// Returns the commit of a read view of the restored changes.
+    ///
+    /// A batch that fails to restore is aborted,
+    /// and the batches before it remain committed.
+    ///
+    /// Fails if any [`WriteBatch`] or [`Transaction`] is open,
+    /// and none can be created until the restore returns.
+    pub async fn restore<S>(&self, batches: S) -> Result<Commit> where S: Stream<Item = ChangeBatch> + Unpin { self.0.restore(batches).await }
+
+    /// Create a transaction ([`Transaction`]).
+    pub async fn transaction(&self) -> Result<Transaction> { Ok(Transaction(self.0.transaction().await?)) }
+
     /// Sync file system to disk.
+    ///
+    /// Commits are only durable after a sync,
+    /// which may be done automatically according to [`Durability`].
     pub async fn sync(&self) -> Result<()> { self.0.sync().await }
+
+    /// Copy the database to an empty directory, as of the latest commit.
+    ///
+    /// The copy opens as a database with the same trees and log format,
+    /// whose read views see what [`Db::read_view_at`] sees
+    /// at the returned commit.
+    /// Batches may keep committing during the copy,
+    /// and it works the same for in-memory databases.
+    pub async fn checkpoint(&self, dir: &Path) -> Result<Commit> { self.0.checkpoint(dir).await }
+
+    /// Save the indexes of the trees as of the latest commit,
+    /// so that opening the database only replays the logs after it.
+    ///
+    /// Each checkpoint replaces the last,
+    /// and is also taken in the background
+    /// every [`DbConfig`]'s `index_checkpoint_interval` commits.
+    /// A checkpoint that can't be read or loaded is ignored,
+    /// replaying the logs in full.
+    /// Returns the commit limit of the checkpoint.
+    pub async fn checkpoint_indexes(&self) -> Result<Commit> { self.0.checkpoint_indexes().await }
+
+    /// Discard all unsynced writes, as if the machine crashed, and close the database.
+    #[doc(hidden)]
+    pub async fn simulate_crash(self) -> Result<()> { self.0.simulate_crash().await }
+
+    /// Rewrite a tree's live data to a new log, discarding stale data.
+    ///
+    /// Compaction begins once the write batches already writing to the tree
+    /// are closed, so this must not be awaited while holding an open [`WriteBatch`].
+    /// Batches that first write to the tree in the meantime wait for compaction to begin.
+    /// Batches and read views continue to work during compaction.
+    ///
+    /// Returns `false` if the tree was already being compacted,
+    /// or has had no commits since it was last compacted.
+    pub async fn compact(&self, tree: &str) -> Result<bool> { self.0.compact(tree).await }
+
+    /// Discard the in-memory history of old versions
+    /// that no live [`ReadView`] can see.
+    ///
+    /// Each overwrite, delete, and range delete is remembered
+    /// for the sake of older read views until this is called.
+    ///
+    /// Returns the number of versions and range deletes discarded.
+    pub fn collect_garbage(&self) -> usize { self.0.collect_garbage() }
+
+    /// The number of versions and range deletes held in memory for a tree.
+    #[doc(hidden)]
+    pub fn index_entries(&self, tree: &str) -> Result<usize> { self.0.index_entries(tree) }
+
+    /// Create an empty tree.
+    ///
+    /// The tree is recorded on disk, and is opened with the database
+    /// whether or not it is listed in [`DbConfig`]'s `trees`.
+    /// Write batches and read views created before the tree
+    /// can't see it.
+    pub async fn create_tree(&self, tree: &str) -> Result<()> { self.0.create_tree(tree).await }
+
+    /// Drop a tree and delete its data.
+    ///
+    /// Write batches created before the tree was dropped
+    /// fail to write to it, and fail to commit if they wrote to it,
+    /// even once a tree of the same name is created again.
+    /// Read views created before the tree was dropped
+    /// may fail when using it.
+    pub async fn drop_tree(&self, tree: &str) -> Result<()> { self.0.drop_tree(tree).await }
+
+    /// The names of the trees that exist.
+    pub fn tree_names(&self) -> Vec<String> { self.0.tree_names() }
 }
 
 impl WriteBatch {
@@ -49,7 +283,7 @@ impl WriteBatch {
     pub async fn push_save_point(&self) -> Result<()> { self.0.push_save_point().await }
     pub async fn pop_save_point(&self) -> Result<()> { self.0.pop_save_point().await }
     pub async fn rollback_save_point(&self) -> Result<()> { self.0.rollback_save_point().await }
-    pub async fn commit(&self) -> Result<()> { self.0.commit().await }
+    pub async fn commit(&self) -> Result<Commit> { self.0.commit().await }
     pub async fn abort(&self) { self.0.abort().await }
     pub async fn close(self) { self.0.close().await }
 }
@@ -57,17 +291,112 @@ impl WriteBatch {
 impl ReadView {
     /// Get a read handle to a single tree ([`ReadTree`]).
     pub fn tree<'view>(&'view self, tree: &str) -> ReadTree<'view> { ReadTree(self.0.tree(tree)) }
+
+    /// The view sees every commit before this one, and none after.
+    ///
+    /// A view created just after a commit reports the next commit number.
+    pub fn commit(&self) -> Commit { self.0.commit() }
 }
 
 impl<'batch> WriteTree<'batch> {
     pub async fn write(&self, key: &[u8], value: &[u8]) -> Result<()> { self.0.write(key, value).await }
     pub async fn delete(&self, key: &[u8]) -> Result<()> { self.0.delete(key).await }
     pub async fn delete_range(&self, start_key: &[u8], end_key: &[u8]) -> Result<()> { self.0.delete_range(start_key, end_key).await }
+
+    /// Write a key if its latest committed value is `expected`
+    /// when the batch commits, where `None` means the key has no value.
+    ///
+    /// Otherwise the commit fails with [`Precondition`] and the batch is aborted.
+    /// The batch's own writes are not checked against.
+    pub async fn write_if(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<()> { self.0.write_if(key, expected, value).await }
+
+    /// Delete a key if its latest committed value is `expected`
+    /// when the batch commits, as [`WriteTree::write_if`].
+    pub async fn delete_if(&self, key: &[u8], expected: &[u8]) -> Result<()> { self.0.delete_if(key, expected).await }
+
+    /// Merge `operand` into the key's value with the tree's [`MergeOperator`],
+    /// without reading the value.
+    ///
+    /// Fails if the tree has no merge operator.
+    pub async fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> { self.0.merge(key, operand).await }
+
+    /// Read a key from `view` as if the batch's writes so far were committed,
+    /// with any rolled back save points undone.
+    pub async fn read(&self, view: &ReadView, key: &[u8]) -> Result<Option<Vec<u8>>> { self.0.read(&view.0, key).await }
+
+    /// Create a cursor over `view` as if the batch's writes so far were committed,
+    /// with any rolled back save points undone.
+    ///
+    /// The cursor doesn't see writes the batch makes after it is created.
+    pub async fn cursor(&self, view: &ReadView) -> Result<Cursor> { self.0.cursor(&view.0).await.map(Cursor) }
+
+    /// Lock a key until the batch commits, aborts, or closes,
+    /// waiting for conflicting locks held by other batches.
+    ///
+    /// Fails with [`LockError`] if the wait outlasts
+    /// [`DbConfig`]'s `lock_timeout`,
+    /// or if batches waiting on each other would never wake.
+    /// Locks only exclude other locks, not reads or writes.
+    pub async fn lock(&self, key: &[u8], mode: LockMode) -> Result<()> { self.0.lock(key, mode).await }
+
+    /// Lock the keys from `start_key` up to but not including `end_key`,
+    /// like [`WriteTree::lock`].
+    pub async fn lock_range(&self, start_key: &[u8], end_key: &[u8], mode: LockMode) -> Result<()> { self.0.lock_range(start_key, end_key, mode).await }
 }
 
 impl<'view> ReadTree<'view> {
     pub async fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { self.0.read(key).await }
-    pub fn cursor(&self) -> Cursor { Cursor(self.0.cursor()) }
+    pub fn cursor(&self) -> Result<Cursor> { self.0.cursor().map(Cursor) }
+
+    /// Each commit seen by the view that wrote, deleted,
+    /// range deleted or merged a key, oldest first,
+    /// with the key's value after it, or `None` if it was deleted.
+    ///
+    /// Only the history retained by garbage collection is returned.
+    /// The versions folded together by compaction are returned as one,
+    /// at the last commit compacted.
+    pub async fn history(&self, key: &[u8]) -> Result<Vec<(Commit, Option<Vec<u8>>)>> { self.0.history(key).await }
+}
+
+impl Transaction {
+    /// Get a handle to a single tree ([`TransactionTree`]).
+    pub fn tree<'tx>(&'tx self, tree: &str) -> TransactionTree<'tx> { TransactionTree(self.0.tree(tree)) }
+
+    pub async fn push_save_point(&self) -> Result<()> { self.0.push_save_point().await }
+    pub async fn pop_save_point(&self) -> Result<()> { self.0.pop_save_point().await }
+    pub async fn rollback_save_point(&self) -> Result<()> { self.0.rollback_save_point().await }
+
+    /// Commit the transaction's writes,
+    /// failing with [`Conflict`] if any key it read
+    /// has been changed by a commit since its snapshot,
+    /// in which case the transaction is aborted.
+    ///
+    /// A transaction can only be committed once.
+    pub async fn commit(&self) -> Result<Commit> { self.0.commit().await }
+
+    pub async fn abort(&self) { self.0.abort().await }
+    pub async fn close(self) { self.0.close().await }
+}
+
+impl<'tx> TransactionTree<'tx> {
+    /// Read a key from the snapshot with the transaction's writes so far,
+    /// recording it for conflict detection.
+    pub async fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { self.0.read(key).await }
+
+    /// Create a cursor over the snapshot with the transaction's writes so far,
+    /// recording the range of keys it moves over for conflict detection.
+    ///
+    /// The cursor doesn't see writes the transaction makes after it is created.
+    pub async fn cursor(&self) -> Result<Cursor> { self.0.cursor().await.map(Cursor) }
+
+    pub async fn write(&self, key: &[u8], value: &[u8]) -> Result<()> { self.0.write(key, value).await }
+    pub async fn delete(&self, key: &[u8]) -> Result<()> { self.0.delete(key).await }
+    pub async fn delete_range(&self, start_key: &[u8], end_key: &[u8]) -> Result<()> { self.0.delete_range(start_key, end_key).await }
+
+    /// Merge `operand` into the key's value, as [`WriteTree::merge`].
+    ///
+    /// This is not a read, so does not conflict with other commits.
+    pub async fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> { self.0.merge(key, operand).await }
 }
 
 impl Cursor {
@@ -0,0 +1,236 @@
+//! A log file wrapper that injects faults, for testing recovery.
+//!
+//! Every log wrapped with the same [`Faults`] shares its settings,
+//! so one `Faults` can control all the logs of a database.
+//!
+//! A crash truncates each log to its last sync,
+//! and fails every later operation on the logs that crashed.
+//! Logs wrapped after the crash,
+//! as by reopening the database,
+//! work normally.
+
+use crate::types::Address;
+use anyhow::{Result, anyhow, bail};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use crate::log_file::LogFile;
+use serde::{Serialize, Deserialize};
+use futures::future::BoxFuture;
+
+#[derive(Clone, Default)]
+pub struct Faults {
+    state: Arc<Mutex<FaultState>>,
+}
+
+#[derive(Default)]
+struct FaultState {
+    /// The number of appends to every log so far
+    appends: u64,
+    /// The number of the append to fail
+    fail_append: Option<u64>,
+    fail_sync: bool,
+    corrupt: BTreeSet<(String, Address)>,
+    /// Truncate each log to its last sync
+    crash_hooks: Vec<Weak<dyn CrashHook>>,
+}
+
+trait CrashHook: Send + Sync {
+    fn crash(&self) -> BoxFuture<'static, Result<()>>;
+}
+
+struct State<Cmd> where Cmd: Serialize + for <'de> Deserialize<'de> {
+    name: String,
+    inner: LogFile<Cmd>,
+    faults: Faults,
+    log_state: Mutex<LogState>,
+}
+
+#[derive(Default)]
+struct LogState {
+    /// Appended since the last sync started
+    unsynced: BTreeSet<Address>,
+    crashed: bool,
+}
+
+impl Faults {
+    pub fn new() -> Faults {
+        Faults::default()
+    }
+
+    /// The number of appends to every log so far.
+    pub fn appends(&self) -> u64 {
+        self.state.lock().expect("lock").appends
+    }
+
+    /// Fail an append, numbered from 0 across every log.
+    pub fn fail_append(&self, append: u64) {
+        self.state.lock().expect("lock").fail_append = Some(append);
+    }
+
+    /// Fail every sync until turned off.
+    pub fn fail_sync(&self, fail: bool) {
+        self.state.lock().expect("lock").fail_sync = fail;
+    }
+
+    /// Fail reads of one command in a log as if it were corrupt.
+    pub fn corrupt(&self, log: &str, addr: Address) {
+        self.state.lock().expect("lock").corrupt.insert((log.to_string(), addr));
+    }
+
+    /// Discard everything appended to every log since it was last synced.
+    pub async fn crash(&self) -> Result<()> {
+        let hooks = std::mem::take(&mut self.state.lock().expect("lock").crash_hooks);
+        for hook in hooks {
+            if let Some(hook) = hook.upgrade() {
+                hook.crash().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Faults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Faults").finish()
+    }
+}
+
+pub fn wrap<Cmd>(name: String, inner: LogFile<Cmd>, faults: Faults) -> LogFile<Cmd>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    let state1 = Arc::new(State {
+        name,
+        inner,
+        faults: faults.clone(),
+        log_state: Mutex::new(LogState::default()),
+    });
+
+    let hook: Arc<dyn CrashHook> = state1.clone();
+    faults.state.lock().expect("lock").crash_hooks.push(Arc::downgrade(&hook));
+
+    let state2 = state1.clone();
+    let state3 = state1.clone();
+    let state4 = state1.clone();
+    let state5 = state1.clone();
+    let state6 = state1.clone();
+
+    LogFile {
+        is_empty: Box::new(move || Box::pin(is_empty(state1.clone()))),
+        append: Box::new(move |cmd| Box::pin(append(state2.clone(), cmd))),
+        read_at: Box::new(move |addr| Box::pin(read_at(state3.clone(), addr))),
+        sync: Box::new(move || Box::pin(sync(state4.clone()))),
+        recover: Box::new(move |truncate| Box::pin(recover(state5.clone(), truncate))),
+        truncate: Box::new(move |addr| Box::pin(truncate(state6.clone(), addr))),
+    }
+}
+
+impl<Cmd> CrashHook for State<Cmd>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    fn crash(&self) -> BoxFuture<'static, Result<()>> {
+        let first_unsynced = {
+            let mut log_state = self.log_state.lock().expect("lock");
+            log_state.crashed = true;
+            log_state.unsynced.iter().next().copied()
+        };
+        let future = first_unsynced.map(|addr| (self.inner.truncate)(addr));
+        Box::pin(async move {
+            if let Some(future) = future {
+                future.await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<Cmd> State<Cmd>
+where Cmd: Serialize + for <'de> Deserialize<'de>
+{
+    fn check_crashed(&self) -> Result<()> {
+        if self.log_state.lock().expect("lock").crashed {
+            bail!("injected crash");
+        }
+        Ok(())
+    }
+}
+
+async fn is_empty<Cmd>(state: Arc<State<Cmd>>) -> Result<bool>
+where Cmd: Serialize + for <'de> Deserialize<'de>
+{
+    state.check_crashed()?;
+    state.inner.is_empty().await
+}
+
+async fn append<Cmd>(state: Arc<State<Cmd>>, cmd: Cmd) -> Result<Address>
+where Cmd: Serialize + for <'de> Deserialize<'de>
+{
+    state.check_crashed()?;
+
+    {
+        let mut faults = state.faults.state.lock().expect("lock");
+        let append = faults.appends;
+        faults.appends += 1;
+        if faults.fail_append == Some(append) {
+            bail!("injected append failure");
+        }
+    }
+
+    let addr = state.inner.append(cmd).await?;
+    state.log_state.lock().expect("lock").unsynced.insert(addr);
+    Ok(addr)
+}
+
+async fn read_at<Cmd>(state: Arc<State<Cmd>>, addr: Address) -> Result<(Cmd, Option<Address>)>
+where Cmd: Serialize + for <'de> Deserialize<'de>
+{
+    state.check_crashed()?;
+
+    let corrupt = {
+        let faults = state.faults.state.lock().expect("lock");
+        faults.corrupt.contains(&(state.name.clone(), addr))
+    };
+    if corrupt {
+        return Err(anyhow!("injected corruption at {:?} of {}", addr, state.name));
+    }
+
+    state.inner.read_at(addr).await
+}
+
+async fn sync<Cmd>(state: Arc<State<Cmd>>) -> Result<()>
+where Cmd: Serialize + for <'de> Deserialize<'de>
+{
+    state.check_crashed()?;
+
+    if state.faults.state.lock().expect("lock").fail_sync {
+        bail!("injected sync failure");
+    }
+
+    // Appends that finish during the sync may not be covered by it
+    let syncing = state.log_state.lock().expect("lock").unsynced.clone();
+    state.inner.sync().await?;
+
+    let mut log_state = state.log_state.lock().expect("lock");
+    log_state.unsynced = log_state.unsynced.difference(&syncing).copied().collect();
+
+    Ok(())
+}
+
+async fn recover<Cmd>(state: Arc<State<Cmd>>, truncate: bool) -> Result<()>
+where Cmd: Serialize + for <'de> Deserialize<'de>
+{
+    state.check_crashed()?;
+    state.inner.recover(truncate).await
+}
+
+async fn truncate<Cmd>(state: Arc<State<Cmd>>, addr: Address) -> Result<()>
+where Cmd: Serialize + for <'de> Deserialize<'de>
+{
+    state.check_crashed()?;
+    state.inner.truncate(addr).await?;
+
+    let mut log_state = state.log_state.lock().expect("lock");
+    log_state.unsynced = log_state.unsynced.range(..addr).copied().collect();
+
+    Ok(())
+}
@@ -86,6 +86,13 @@ where Io: Read + BufRead,
     Ok(cmd)
 }
 
+/// Whether another frame starts after the first line of `bytes`.
+pub fn has_later_frame(bytes: &[u8]) -> bool {
+    let marker = format!("\n{}\n", FRAME_HEADER_MARKER);
+    let marker = marker.as_bytes();
+    bytes.windows(marker.len()).any(|window| window == marker)
+}
+
 static FRAME_HEADER_MARKER: &'static str = "[[frames]] # HEADER";
 static FRAME_BODY_MARKER: &'static str = "# BODY";
 
@@ -1,32 +1,60 @@
-use log::debug;
+//! A thread for blocking file system operations.
+//!
+//! Appends and syncs are queued separately from other operations
+//! so that the thread can group them:
+//! concurrent appends to the same file become one write,
+//! and concurrent syncs of the same file share one `sync_all`.
+//!
+//! The thread also tracks how much of each file has been synced,
+//! so that tests can simulate a crash by discarding the rest.
+
+use log::{debug, trace};
 use std::collections::btree_map::Entry;
 use log::error;
 use std::collections::BTreeMap;
 use std::future::Future;
 use std::path::{Path, PathBuf};
 use std::fs::{File, OpenOptions};
-use anyhow::Result;
+use std::io::{Seek, SeekFrom, Write};
+use anyhow::{Result, anyhow};
 use std::thread::{self, JoinHandle};
 use async_channel::{self, Sender, Receiver, TrySendError};
 use futures::executor::{LocalPool, block_on};
 use std::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct FsThread {
     handle: JoinHandle<()>,
     tx: Sender<Message>,
+    /// The number of bytes appended to all files
+    appended: Arc<AtomicU64>,
 }
 
 pub struct FsThreadContext {
     append_handles: BTreeMap<PathBuf, File>,
     read_handles: BTreeMap<PathBuf, File>,
+    /// The synced length of every file opened
+    durable: BTreeMap<PathBuf, u64>,
 }
 
 enum Message {
     Run(Box<dyn FnOnce(&mut FsThreadContext) + Send>),
+    Append(PathBuf, Vec<u8>, Sender<Result<u64>>),
+    Sync(PathBuf, Sender<Result<()>>),
     Shutdown(mpsc::Sender<()>),
 }
 
+/// Appends and syncs received since the last other operation.
+#[derive(Default)]
+struct Group {
+    appends: BTreeMap<PathBuf, Vec<PendingAppend>>,
+    syncs: BTreeMap<PathBuf, Vec<Sender<Result<()>>>>,
+}
+
+type PendingAppend = (Vec<u8>, Sender<Result<u64>>);
+
 impl Drop for FsThread {
     fn drop(&mut self) {
         self.shutdown();
@@ -38,23 +66,50 @@ impl FsThread {
         let (tx, rx) = async_channel::unbounded();
         let handle = thread::spawn(move || {
             let mut context = FsThreadContext::new();
+            let mut group = Group::default();
             loop {
                 let msg = block_on(rx.recv()).expect("recv");
-                match msg {
-                    Message::Run(f) => {
-                        f(&mut context);
-                    },
-                    Message::Shutdown(rsp_tx) => {
-                        context.shutdown();
-                        rsp_tx.send(()).expect("send");
-                        break;
+                let mut msgs = vec![msg];
+                // Take everything else that is waiting,
+                // so that it can be grouped.
+                while let Ok(msg) = rx.try_recv() {
+                    msgs.push(msg);
+                }
+
+                let mut shutdown = None;
+                for msg in msgs {
+                    match msg {
+                        Message::Run(f) => {
+                            // Keep the other operations in order
+                            group.finish(&mut context);
+                            f(&mut context);
+                        },
+                        Message::Append(path, bytes, rsp_tx) => {
+                            group.appends.entry(path).or_default().push((bytes, rsp_tx));
+                        },
+                        Message::Sync(path, rsp_tx) => {
+                            group.syncs.entry(path).or_default().push(rsp_tx);
+                        },
+                        Message::Shutdown(rsp_tx) => {
+                            shutdown = Some(rsp_tx);
+                            break;
+                        }
                     }
                 }
+
+                group.finish(&mut context);
+
+                if let Some(rsp_tx) = shutdown {
+                    context.shutdown();
+                    rsp_tx.send(()).expect("send");
+                    break;
+                }
             }
         });
 
         Ok(FsThread {
-            handle, tx
+            handle, tx,
+            appended: Arc::new(AtomicU64::new(0)),
         })
     }
 
@@ -76,6 +131,86 @@ impl FsThread {
             rsp_rx.recv().await.expect("recv")
         }
     }
+
+    /// Append bytes to a file, returning the offset they were written at.
+    pub fn append(&self, path: PathBuf, bytes: Vec<u8>) -> impl Future<Output = Result<u64>> {
+        let (rsp_tx, rsp_rx) = async_channel::bounded(1);
+
+        self.appended.fetch_add(bytes.len() as u64, Ordering::SeqCst);
+
+        self.tx.try_send(Message::Append(path, bytes, rsp_tx)).expect("send");
+
+        async {
+            let rsp_rx = rsp_rx;
+            rsp_rx.recv().await.expect("recv")
+        }
+    }
+
+    /// The count of bytes appended to all files so far.
+    pub fn appended_bytes(&self) -> Arc<AtomicU64> {
+        self.appended.clone()
+    }
+
+    /// Sync a file to disk.
+    pub fn sync(&self, path: PathBuf) -> impl Future<Output = Result<()>> {
+        let (rsp_tx, rsp_rx) = async_channel::bounded(1);
+
+        self.tx.try_send(Message::Sync(path, rsp_tx)).expect("send");
+
+        async {
+            let rsp_rx = rsp_rx;
+            rsp_rx.recv().await.expect("recv")
+        }
+    }
+}
+
+impl Group {
+    /// Write all appends, then sync,
+    /// so each sync covers every append in the group.
+    fn finish(&mut self, ctx: &mut FsThreadContext) {
+        for (path, appends) in std::mem::take(&mut self.appends) {
+            trace!("appending {} frames to {}", appends.len(), path.display());
+            let bytes: Vec<u8> = appends.iter()
+                .flat_map(|(bytes, _)| bytes.iter().copied())
+                .collect();
+            let r = (|| -> Result<u64> {
+                let file = ctx.open_append(&path)?;
+                let pos = file.seek(SeekFrom::End(0))?;
+                file.write_all(&bytes)?;
+                Ok(pos)
+            })();
+            match r {
+                Ok(mut pos) => {
+                    for (bytes, rsp_tx) in appends {
+                        let _r = rsp_tx.try_send(Ok(pos));
+                        pos += bytes.len() as u64;
+                    }
+                },
+                Err(e) => {
+                    for (_, rsp_tx) in appends {
+                        let _r = rsp_tx.try_send(Err(anyhow!("{:#}", e)));
+                    }
+                }
+            }
+        }
+
+        for (path, rsp_txs) in std::mem::take(&mut self.syncs) {
+            trace!("syncing {} for {} waiters", path.display(), rsp_txs.len());
+            let r = ctx.sync(&path);
+            match r {
+                Ok(()) => {
+                    for rsp_tx in rsp_txs {
+                        let _r = rsp_tx.try_send(Ok(()));
+                    }
+                },
+                Err(e) => {
+                    for rsp_tx in rsp_txs {
+                        let _r = rsp_tx.try_send(Err(anyhow!("{:#}", e)));
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl FsThread {
@@ -96,6 +231,7 @@ impl FsThreadContext {
                     .create(true)
                     .append(true)
                     .open(path)?;
+                record_durable(&mut self.durable, path, &file)?;
                 Ok(entry.insert(file))
             }
             Entry::Occupied(entry) => {
@@ -113,6 +249,7 @@ impl FsThreadContext {
                     .write(true)
                     .read(true)
                     .open(path)?;
+                record_durable(&mut self.durable, path, &file)?;
                 Ok(entry.insert(file))
             }
             Entry::Occupied(entry) => {
@@ -124,6 +261,38 @@ impl FsThreadContext {
     pub fn close(&mut self, path: &Path) {
         sync_close(path, self.append_handles.remove(path).as_mut());
         sync_close(path, self.read_handles.remove(path).as_mut());
+        self.durable.remove(path);
+    }
+
+    /// Sync a file and record its new durable length.
+    pub fn sync(&mut self, path: &Path) -> Result<()> {
+        let file = self.open_append(path)?;
+        file.sync_all()?;
+        let len = file.metadata()?.len();
+        self.durable.insert(path.to_owned(), len);
+        Ok(())
+    }
+
+    /// Discard everything written since each file was last synced,
+    /// and close every file without syncing.
+    ///
+    /// This doesn't simulate losing unsynced
+    /// directory entries.
+    pub fn crash(&mut self) -> Result<()> {
+        self.append_handles.clear();
+        self.read_handles.clear();
+        for (path, durable_len) in std::mem::take(&mut self.durable) {
+            let file = match OpenOptions::new().write(true).open(&path) {
+                Ok(file) => file,
+                // Removed since it was opened
+                Err(_) => continue,
+            };
+            if file.metadata()?.len() > durable_len {
+                debug!("crash truncating {} to {}", path.display(), durable_len);
+                file.set_len(durable_len)?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -132,6 +301,7 @@ impl FsThreadContext {
         FsThreadContext {
             append_handles: BTreeMap::new(),
             read_handles: BTreeMap::new(),
+            durable: BTreeMap::new(),
         }
     }
 
@@ -144,6 +314,14 @@ impl FsThreadContext {
     }
 }
 
+/// Files are assumed to be durable when first opened.
+fn record_durable(durable: &mut BTreeMap<PathBuf, u64>, path: &Path, file: &File) -> Result<()> {
+    if !durable.contains_key(path) {
+        durable.insert(path.to_owned(), file.metadata()?.len());
+    }
+    Ok(())
+}
+
 fn sync_close(path: &Path, file: Option<&mut File>) {
     if let Some(file) = file {
         if let Err(e) = file.sync_all() {
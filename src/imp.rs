@@ -1,37 +1,134 @@
 use log::error;
 use std::fs::{self, File};
 use std::collections::BTreeMap;
-use anyhow::Result;
-use std::sync::Arc;
+use anyhow::{Result, bail};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::path::{PathBuf, Path};
 use crate::log::Log;
+use crate::log_file::LogFile;
 use crate::simple_log_file;
+pub use crate::simple_log_file::LogFormat;
 use crate::mem_log_file;
-use crate::command::Command;
+pub use crate::mem_log_file::MemDisk;
+pub use crate::basic_db::{Conflict, Precondition};
+pub use crate::merge::MergeOperator;
+pub use crate::changes::{Change, ChangeBatch};
+pub use crate::verify::{Report as VerifyReport, Problem as VerifyProblem};
+use crate::lock_table::{LockTable, Span};
+pub use crate::lock_table::{LockMode, LockError};
+use crate::faulty_log_file::{self, Faults};
+use crate::tree_logs::{self, TreeLogs};
+use crate::compacting_tree::CompactingTree;
 use crate::commit_log::CommitCommand;
+use crate::index_checkpoint::IndexCheckpoint;
+use crate::manifest::{Manifest, ManifestCommand};
 use crate::fs_thread::FsThread;
+use crate::syncer::Syncer;
+use crate::checkpointer::Checkpointer;
 use crate::basic_db as bdb;
-use crate::types::{Key, Value};
+use crate::types::{Batch, Key, KeyRange, Value};
+pub use crate::types::Commit;
 use std::ops::Deref;
+use std::time::Duration;
+use futures::executor::block_on;
+use futures::stream::{self, Stream, StreamExt};
+use futures::sink::{Sink, SinkExt};
+use std::pin::Pin;
 
 #[derive(Clone, Debug)]
 pub struct DbConfig {
+    /// The directory to store logs in, or `None` to store them in memory.
     pub dir: Option<PathBuf>,
+    /// The trees to create if they don't already exist.
+    /// Trees made by `Db::create_tree` are opened whether listed or not.
     pub trees: Vec<String>,
+    /// The encoding of the logs.
+    pub log_format: LogFormat,
+    /// Whether opening the database discards partially-written
+    /// records at the ends of logs, as left by a crash.
+    /// Otherwise opening a database with partial records fails.
+    pub truncate_torn_writes: bool,
+    /// The size at which logs start a new segment file.
+    pub log_segment_size: u64,
+    /// When commits are synced to disk.
+    pub durability: Durability,
+    /// The storage of an in-memory database, when `dir` is `None`.
+    /// Databases opened on the same `MemDisk` see the same logs,
+    /// as if they were on disk.
+    /// If `None` the database starts empty.
+    pub mem_disk: Option<MemDisk>,
+    /// How long a write batch waits to lock keys before failing,
+    /// or `None` to wait until they are unlocked.
+    /// Waits that would deadlock fail immediately either way.
+    pub lock_timeout: Option<Duration>,
+    /// The merge operator of each tree that can be merged into.
+    pub merge_operators: BTreeMap<String, MergeOperator>,
+    /// Open the database as a read view at this commit would see it,
+    /// or `None` to open it at the latest commit.
+    /// Unless `truncate_rollback` is set the database is read-only.
+    pub rollback_to: Option<Commit>,
+    /// Whether opening with `rollback_to` permanently discards
+    /// the later commits from the logs.
+    pub truncate_rollback: bool,
+    /// Checkpoint the indexes in the background every this many commits,
+    /// or `None` to only checkpoint them with `Db::checkpoint_indexes`.
+    pub index_checkpoint_interval: Option<u64>,
+    /// The number of commits a subscription buffers until they are received.
+    /// A subscription that falls further behind is ended.
+    pub subscription_capacity: usize,
+}
+
+pub type ChangeStream = Pin<Box<dyn Stream<Item = ChangeBatch> + Send>>;
+
+#[derive(Clone, Debug)]
+pub enum Durability {
+    /// Every commit is synced before it returns.
+    SyncOnCommit,
+    /// Commits are synced in the background,
+    /// every `interval`, or sooner once `bytes`
+    /// have been written to the logs.
+    Periodic {
+        interval: Duration,
+        bytes: u64,
+    },
+    /// Commits are only synced by `Db::sync`.
+    Manual,
 }
 
 #[derive(Clone, Debug)]
 pub struct Db {
     config: Arc<DbConfig>,
     inner: Arc<bdb::Db>,
-    trees: Arc<Vec<String>>,
     dir_handle: Option<Arc<File>>, // Unix only, non-mem only
+    fs_thread: Option<Arc<FsThread>>, // non-mem only
+    syncer: Option<Arc<Syncer>>, // periodic durability only
+    checkpointer: Option<Arc<Checkpointer>>, // with an index checkpoint interval only
+    faults: Option<Faults>,
+    locks: Arc<LockTable>,
+    writers: Arc<Mutex<Writers>>,
 }
 
+/// The write batches open on a database,
+/// none of which can be opened while it is restored.
+#[derive(Default, Debug)]
+struct Writers {
+    open_batches: usize,
+    restoring: bool,
+}
+
+/// Ends a restore when dropped.
+struct RestoreGuard<'db>(&'db Mutex<Writers>);
+
 pub struct WriteBatch {
     inner: bdb::BatchWriter,
-    trees: Arc<Vec<String>>,
     closed: bool,
+    sync_on_commit: bool,
+    syncer: Option<Arc<Syncer>>,
+    locks: Arc<LockTable>,
+    checkpointer: Option<Arc<Checkpointer>>,
+    /// Counts the batch as open, unless it is restoring
+    writers: Option<Arc<Mutex<Writers>>>,
 }
 
 #[derive(Clone, Debug)]
@@ -51,14 +148,88 @@ pub struct ReadTree<'view> {
 
 pub struct Cursor {
     inner: bdb::Cursor,
+    /// Where a transaction's cursor records what it reads
+    reads: Option<CursorReads>,
+}
+
+pub struct Transaction {
+    view: ReadView,
+    batch: WriteBatch,
+    reads: Arc<Mutex<bdb::ReadSet>>,
+    committed: AtomicBool,
+}
+
+pub struct TransactionTree<'tx> {
+    tree: String,
+    tx: &'tx Transaction,
+}
+
+/// The single range of keys a cursor has read,
+/// grown as it moves.
+struct CursorReads {
+    reads: Arc<Mutex<bdb::ReadSet>>,
+    tree: String,
+    index: Option<usize>,
 }
 
 impl Db {
     pub async fn open(config: DbConfig) -> Result<Db> {
-        let (tree_logs, commit_log) = make_logs(&config)?;
+        Db::open_with_faults(config, None).await
+    }
+
+    pub async fn open_with_faults(mut config: DbConfig, faults: Option<Faults>) -> Result<Db> {
+        for tree in &config.trees {
+            check_tree_name(tree)?;
+        }
+        if config.index_checkpoint_interval == Some(0) {
+            bail!("index checkpoint interval must not be zero");
+        }
+        if config.subscription_capacity == 0 {
+            bail!("subscription capacity must not be zero");
+        }
+
+        // Keep all the logs of an in-memory database on one disk,
+        // which counts the bytes appended to them
+        if config.dir.is_none() && config.mem_disk.is_none() {
+            config.mem_disk = Some(MemDisk::new());
+        }
+
+        let (manifest_log, commit_log, index_log, fs_thread) = make_logs(&config)?;
+        let (manifest_log, commit_log, index_log) = if let Some(faults) = &faults {
+            (faulty_log_file::wrap("manifest".to_string(), manifest_log, faults.clone()),
+             faulty_log_file::wrap("commits".to_string(), commit_log, faults.clone()),
+             faulty_log_file::wrap("indexes".to_string(), index_log, faults.clone()))
+        } else {
+            (manifest_log, commit_log, index_log)
+        };
+        let manifest = Manifest::new(Log::new(manifest_log));
+        let commit_log = Log::new(commit_log);
+
+        manifest.recover(config.truncate_torn_writes).await?;
+        let mut tree_batches = manifest.load().await?;
+
+        // A new database, or one from before the manifest,
+        // has every configured tree from the first batch
+        if tree_batches.is_empty() {
+            for tree in &config.trees {
+                manifest.create_tree(tree, Batch(0)).await?;
+                tree_batches.insert(tree.clone(), Batch(0));
+            }
+        }
+
+        let mut trees = BTreeMap::new();
+        for (tree, first_batch) in tree_batches {
+            let logs = make_tree_logs(&config, &fs_thread, &faults, &tree, first_batch)?;
+            let merge = config.merge_operators.get(&tree).cloned();
+            let tree_ = CompactingTree::open(logs, config.truncate_torn_writes, merge).await?;
+            trees.insert(tree, (tree_, first_batch));
+        }
+
+        commit_log.recover(config.truncate_torn_writes).await?;
 
-        let db = bdb::Db::new(tree_logs, commit_log);
-        db.init().await?;
+        let db = bdb::Db::new(trees, commit_log, manifest, Log::new(index_log),
+                             config.subscription_capacity);
+        db.init(config.rollback_to, config.truncate_rollback).await?;
 
         let dir_handle = if cfg!(unix) {
             if let Some(ref dir) = config.dir {
@@ -71,62 +242,84 @@ impl Db {
             None
         };
 
-        let trees = Arc::new(config.trees.clone());
+        let inner = Arc::new(db);
+
+        let syncer = match &config.durability {
+            Durability::Periodic { interval, bytes } => {
+                let appended = match (&fs_thread, &config.mem_disk) {
+                    (Some(fs_thread), _) => fs_thread.appended_bytes(),
+                    (None, Some(disk)) => disk.appended_bytes(),
+                    (None, None) => unreachable!(),
+                };
+                let weak_inner = Arc::downgrade(&inner);
+                let dir_handle = dir_handle.clone();
+                let syncer = Syncer::start(appended, *interval, *bytes, move || {
+                    let inner = weak_inner.upgrade()?;
+                    Some(block_on(sync(&inner, &dir_handle)))
+                });
+                Some(Arc::new(syncer))
+            },
+            _ => None,
+        };
 
-        return Ok(Db {
-            config: Arc::new(config),
-            inner: Arc::new(db),
-            trees,
-            dir_handle,
+        let checkpointer = config.index_checkpoint_interval.map(|interval| {
+            let weak_inner = Arc::downgrade(&inner);
+            let checkpointer = Checkpointer::start(interval, move || {
+                let inner = weak_inner.upgrade()?;
+                Some(block_on(inner.checkpoint_indexes()))
+            });
+            Arc::new(checkpointer)
         });
 
-        fn make_logs(config: &DbConfig) -> Result<(BTreeMap<String, Log<Command>>, Log<CommitCommand>)> {
-
-            if let Some(ref dir) = config.dir {
-                // FIXME: async create dir
-                fs::create_dir_all(dir)?;
-
-                let fs_thread = Arc::new(FsThread::start()?);
-
-                let tree_logs = config.trees.iter()
-                    .map(|tree| {
-                        let path = dir.join(format!("{}.toml", tree));
-                        (tree.clone(), path)
-                    });
-
-                assert!(!config.trees.iter().any(|t| t == "commits"));
-                let commit_log = dir.join(format!("commits.toml"));
+        let locks = Arc::new(LockTable::new(config.lock_timeout));
 
-                let tree_logs = tree_logs.into_iter()
-                    .map(|(tree, path)| {
-                        (tree, Log::new(simple_log_file::create(path, fs_thread.clone())))
-                    }).collect();
-
-                let commit_log = Log::new(simple_log_file::create(commit_log, fs_thread.clone()));
+        let db = Db {
+            config: Arc::new(config),
+            inner,
+            dir_handle,
+            fs_thread,
+            syncer,
+            checkpointer,
+            faults,
+            locks,
+            writers: Arc::new(Mutex::new(Writers::default())),
+        };
 
-                Ok((tree_logs, commit_log))
-            } else {
-                let tree_logs = config.trees.iter().cloned().map(|tree| {
-                    (tree, Log::new(mem_log_file::create()))
-                }).collect();
+        // Trees configured since the database was created
+        let existing = db.inner.tree_names();
+        for tree in &db.config.trees {
+            if !existing.contains(tree) && !db.is_read_only() {
+                db.create_tree(tree).await?;
+            }
+        }
 
-                let commit_log = Log::new(mem_log_file::create());
+        Ok(db)
+    }
 
-                Ok((tree_logs, commit_log))
+    pub async fn write_batch(&self) -> Result<WriteBatch> {
+        self.check_writable()?;
+        {
+            let mut writers = self.writers.lock().expect("lock");
+            if writers.restoring {
+                bail!("database is being restored");
             }
+            writers.open_batches += 1;
         }
+        Ok(self.new_write_batch(Some(self.writers.clone())))
     }
 
-    pub async fn write_batch(&self) -> Result<WriteBatch> {
+    fn new_write_batch(&self, writers: Option<Arc<Mutex<Writers>>>) -> WriteBatch {
         let batch = self.inner.batch();
-        for tree in &*self.trees {
-            batch.open(tree).await?;
-        }
-        Ok(WriteBatch {
+        let sync_on_commit = matches!(self.config.durability, Durability::SyncOnCommit);
+        WriteBatch {
             inner: batch,
-            trees: self.trees.clone(),
             closed: false,
-        })
+            sync_on_commit,
+            syncer: self.syncer.clone(),
+            locks: self.locks.clone(),
+            checkpointer: self.checkpointer.clone(),
+            writers,
+        }
     }
 
     pub fn read_view(&self) -> ReadView {
@@ -135,19 +328,333 @@ impl Db {
         }
     }
 
+    pub fn read_view_at(&self, commit: Commit) -> Result<ReadView> {
+        Ok(ReadView {
+            inner: self.inner.view_at(commit)?,
+        })
+    }
+
+    pub async fn subscribe(&self) -> Result<ChangeStream> {
+        let (_, receiver) = self.inner.subscribe(None).await?;
+        Ok(Box::pin(receiver))
+    }
+
+    pub async fn subscribe_from(&self, commit: Commit) -> Result<ChangeStream> {
+        let (replayed, receiver) = self.inner.subscribe(Some(commit)).await?;
+        Ok(Box::pin(stream::iter(replayed).chain(receiver)))
+    }
+
+    pub async fn backup_since<S>(&self, commit: Commit, mut sink: S) -> Result<Commit>
+    where S: Sink<ChangeBatch> + Unpin,
+          S::Error: std::error::Error + Send + Sync + 'static,
+    {
+        self.inner.changes_since(commit, &mut sink).await
+    }
+
+    pub async fn restore<S>(&self, mut batches: S) -> Result<Commit>
+    where S: Stream<Item = ChangeBatch> + Unpin,
+    {
+        self.check_writable()?;
+        // No other batch may take the commit numbers being restored
+        {
+            let mut writers = self.writers.lock().expect("lock");
+            if writers.restoring {
+                bail!("database is already being restored");
+            }
+            if writers.open_batches > 0 {
+                bail!("cannot restore with {} write batches open", writers.open_batches);
+            }
+            writers.restoring = true;
+        }
+        let _restore_guard = RestoreGuard(&self.writers);
+
+        let mut commit_limit = self.read_view().commit();
+        while let Some(changes) = batches.next().await {
+            for tree in changes.trees.keys() {
+                if !self.tree_names().contains(tree) {
+                    self.create_tree(tree).await?;
+                }
+            }
+
+            let batch = self.new_write_batch(None);
+            let r = match apply_changes(&batch, &changes).await {
+                // Keep the numbers of the backed up commits
+                Ok(()) => batch.commit_checked(None, Some(changes.commit)).await,
+                Err(e) => Err(e),
+            };
+            if r.is_err() {
+                batch.abort().await;
+            }
+            batch.close().await;
+
+            let commit = r?;
+            commit_limit = Commit(commit.0.checked_add(1).expect("overflow"));
+        }
+
+        return Ok(commit_limit);
+
+        async fn apply_changes(batch: &WriteBatch, changes: &ChangeBatch) -> Result<()> {
+            for (tree, changes) in changes.trees.iter() {
+                let tree = batch.tree(tree);
+                for change in changes {
+                    match change {
+                        Change::Write { key, value } => tree.write(key, value).await?,
+                        Change::Delete { key } => tree.delete(key).await?,
+                        Change::DeleteRange { start_key, end_key } => tree.delete_range(start_key, end_key).await?,
+                        Change::Merge { key, operand } => tree.merge(key, operand).await?,
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    pub async fn transaction(&self) -> Result<Transaction> {
+        let view = self.read_view();
+        let batch = self.write_batch().await?;
+        let reads = bdb::ReadSet::new(view.inner.commit_limit());
+        Ok(Transaction {
+            view,
+            batch,
+            reads: Arc::new(Mutex::new(reads)),
+            committed: AtomicBool::new(false),
+        })
+    }
+
     pub async fn sync(&self) -> Result<()> {
-        self.inner.sync().await?;
+        sync(&self.inner, &self.dir_handle).await
+    }
+
+    pub async fn checkpoint(&self, dir: &Path) -> Result<Commit> {
+        // FIXME: async create dir
+        fs::create_dir_all(dir)?;
+        if fs::read_dir(dir)?.next().is_some() {
+            bail!("checkpoint directory {} is not empty", dir.display());
+        }
 
-        // Also need to sync the directory
-        if let Some(dir) = &self.dir_handle {
+        let fs_thread = match &self.fs_thread {
+            Some(fs_thread) => fs_thread.clone(),
+            None => Arc::new(FsThread::start()?),
+        };
+        let config = DbConfig {
+            dir: Some(dir.to_path_buf()),
+            ..(*self.config).clone()
+        };
+
+        let format = config.log_format;
+        let segment_size = config.log_segment_size;
+        let manifest_log = simple_log_file::create(dir.to_path_buf(), "manifest".to_string(),
+                                                   format, segment_size, fs_thread.clone());
+        let commit_log = simple_log_file::create(dir.to_path_buf(), "commits".to_string(),
+                                                 format, segment_size, fs_thread.clone());
+        let fs_thread = Some(fs_thread);
+
+        let commit = self.inner.checkpoint(Manifest::new(Log::new(manifest_log)),
+                                           Log::new(commit_log),
+                                           |tree, first_batch| make_tree_logs(&config, &fs_thread, &None, tree, first_batch)).await?;
+
+        if cfg!(unix) {
             // FIXME async
-            dir.sync_all()?;
+            File::open(dir)?.sync_all()?;
         }
 
+        Ok(commit)
+    }
+
+    pub async fn checkpoint_indexes(&self) -> Result<Commit> {
+        self.check_writable()?;
+        self.inner.checkpoint_indexes().await
+    }
+
+    pub async fn simulate_crash(self) -> Result<()> {
+        if let Some(fs_thread) = &self.fs_thread {
+            fs_thread.run(|ctx| ctx.crash()).await?;
+        }
+        if let Some(disk) = &self.config.mem_disk {
+            disk.crash();
+        }
+        Ok(())
+    }
+
+    pub async fn compact(&self, tree: &str) -> Result<bool> {
+        self.check_writable()?;
+        self.inner.compact(tree).await
+    }
+
+    pub async fn create_tree(&self, tree: &str) -> Result<()> {
+        self.check_writable()?;
+        check_tree_name(tree)?;
+        let make_logs = |first_batch| make_tree_logs(&self.config, &self.fs_thread, &self.faults, tree, first_batch);
+        let merge = self.config.merge_operators.get(tree).cloned();
+        self.inner.create_tree(tree, make_logs, merge).await
+    }
+
+    pub async fn drop_tree(&self, tree: &str) -> Result<()> {
+        self.check_writable()?;
+        self.inner.drop_tree(tree).await
+    }
+
+    pub fn tree_names(&self) -> Vec<String> {
+        self.inner.tree_names()
+    }
+
+    pub fn collect_garbage(&self) -> usize {
+        self.inner.collect_garbage()
+    }
+
+    pub fn index_entries(&self, tree: &str) -> Result<usize> {
+        self.inner.index_entries(tree)
+    }
+
+    /// Opened at an earlier commit, with the later commits still in the logs.
+    fn is_read_only(&self) -> bool {
+        self.config.rollback_to.is_some() && !self.config.truncate_rollback
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            bail!("database opened at an earlier commit without truncation is read-only");
+        }
         Ok(())
     }
 }
 
+pub async fn verify(config: &DbConfig) -> Result<VerifyReport> {
+    // Nothing is created or changed on disk
+    let (manifest_log, commit_log) = if let Some(dir) = &config.dir {
+        let format = config.log_format;
+        let logs = ["manifest", "commits"];
+        if logs.iter().all(|log| !simple_log_file::segment_path(dir, log, format, 0).exists()) {
+            bail!("no database in {}", dir.display());
+        }
+        let manifest_log = simple_log_file::open_read_only(dir.clone(), "manifest".to_string(), format)?;
+        let commit_log = simple_log_file::open_read_only(dir.clone(), "commits".to_string(), format)?;
+        (manifest_log, commit_log)
+    } else {
+        let (manifest_log, commit_log, _, _) = make_logs(config)?;
+        (manifest_log, commit_log)
+    };
+    let manifest = Manifest::new(Log::new(manifest_log));
+    let mut tree_batches = manifest.load().await?;
+
+    // As on open, a database from before the manifest
+    // has every configured tree from the first batch
+    if tree_batches.is_empty() {
+        for tree in &config.trees {
+            tree_batches.insert(tree.clone(), Batch(0));
+        }
+    }
+
+    let mut trees = vec![];
+    for (tree, first_batch) in tree_batches {
+        let logs = match &config.dir {
+            Some(dir) => tree_logs::open_read_only(dir, &tree, first_batch, config.log_format)?,
+            None => make_tree_logs(config, &None, &None, &tree, first_batch)?.open_all().await?,
+        };
+        trees.push((tree, first_batch, logs));
+    }
+
+    crate::verify::verify(&Log::new(commit_log), trees).await
+}
+
+type Logs = (LogFile<ManifestCommand>,
+             LogFile<CommitCommand>,
+             LogFile<IndexCheckpoint>,
+             Option<Arc<FsThread>>);
+
+fn make_logs(config: &DbConfig) -> Result<Logs> {
+    if let Some(ref dir) = config.dir {
+        // FIXME: async create dir
+        fs::create_dir_all(dir)?;
+
+        let fs_thread = Arc::new(FsThread::start()?);
+
+        let format = config.log_format;
+        let segment_size = config.log_segment_size;
+
+        // Before logs were split into segments each was a single file,
+        // `commits.toml` and `<tree>.toml`, with tree logs in `<tree>/`
+        for old_format in &[LogFormat::Toml, LogFormat::Binary] {
+            let ext = old_format.extension();
+            let old_logs = std::iter::once("commits").chain(config.trees.iter().map(String::as_str));
+            for old_log in old_logs {
+                let old_path = dir.join(format!("{}.{}", old_log, ext));
+                if old_path.exists() {
+                    bail!("{} is from the unsegmented log layout, which can't be opened; \
+                           rename each log file `<name>.{}` to `<name>.0.{}`, \
+                           moving `<tree>.{}` to `<tree>/0.0.{}`",
+                          old_path.display(), ext, ext, ext, ext);
+                }
+            }
+        }
+
+        for other_format in &[LogFormat::Toml, LogFormat::Binary] {
+            let other_commit_log = simple_log_file::segment_path(dir, "commits", *other_format, 0);
+            if *other_format != format && other_commit_log.exists() {
+                bail!("database uses the {:?} log format, not {:?}", other_format, format);
+            }
+        }
+
+        let manifest_log = simple_log_file::create(dir.clone(), "manifest".to_string(),
+                                                   format, segment_size, fs_thread.clone());
+        let commit_log = simple_log_file::create(dir.clone(), "commits".to_string(),
+                                                 format, segment_size, fs_thread.clone());
+        let index_log = simple_log_file::create(dir.clone(), "indexes".to_string(),
+                                                format, segment_size, fs_thread.clone());
+
+        Ok((manifest_log, commit_log, index_log, Some(fs_thread)))
+    } else {
+        let disk = config.mem_disk.clone().unwrap_or_default();
+
+        let manifest_log = mem_log_file::open(&disk, "manifest");
+        let commit_log = mem_log_file::open(&disk, "commits");
+        let index_log = mem_log_file::open(&disk, "indexes");
+
+        Ok((manifest_log, commit_log, index_log, None))
+    }
+}
+
+fn check_tree_name(tree: &str) -> Result<()> {
+    // Trees are directories next to the commit log, manifest and index checkpoints,
+    // with `@` separating the first batch of re-created trees
+    let reserved = ["", ".", "..", "commits", "manifest", "indexes"];
+    if reserved.contains(&tree) || tree.contains(&['/', '\\', '@'][..]) {
+        bail!("invalid tree name '{}'", tree);
+    }
+    Ok(())
+}
+
+fn make_tree_logs(config: &DbConfig,
+                  fs_thread: &Option<Arc<FsThread>>,
+                  faults: &Option<Faults>,
+                  tree: &str,
+                  first_batch: Batch) -> Result<TreeLogs> {
+    let logs = if let (Some(dir), Some(fs_thread)) = (&config.dir, fs_thread) {
+        tree_logs::simple(dir.clone(), tree, first_batch, config.log_format,
+                          config.log_segment_size, fs_thread.clone())?
+    } else {
+        let disk = config.mem_disk.clone().unwrap_or_default();
+        tree_logs::mem(disk, tree, first_batch)
+    };
+
+    Ok(match faults {
+        Some(faults) => tree_logs::faulty(logs, tree.to_string(), faults.clone()),
+        None => logs,
+    })
+}
+
+async fn sync(inner: &bdb::Db, dir_handle: &Option<Arc<File>>) -> Result<()> {
+    inner.sync().await?;
+
+    // Also need to sync the directory
+    if let Some(dir) = dir_handle {
+        // FIXME async
+        dir.sync_all()?;
+    }
+
+    Ok(())
+}
+
 impl WriteBatch {
     pub fn tree<'batch>(&'batch self, tree: &str) -> WriteTree<'batch> {
         WriteTree {
@@ -157,33 +664,28 @@ impl WriteBatch {
     }
 
     pub async fn push_save_point(&self) -> Result<()> {
-        for tree in self.trees.iter() {
-            self.inner.push_save_point(tree).await?;
-        }
-
-        Ok(())
+        self.inner.push_save_point().await
     }
 
     pub async fn pop_save_point(&self) -> Result<()> {
-        for tree in self.trees.iter() {
-            self.inner.pop_save_point(tree).await?;
-        }
-
-        Ok(())
+        self.inner.pop_save_point().await
     }
 
     pub async fn rollback_save_point(&self) -> Result<()> {
-        for tree in self.trees.iter() {
-            self.inner.rollback_save_point(tree).await?;
-        }
+        self.inner.rollback_save_point().await
+    }
 
-        Ok(())
+    pub async fn commit(&self) -> Result<Commit> {
+        self.commit_checked(None, None).await
     }
 
-    pub async fn commit(&self) -> Result<()> {
+    /// Commit, unless any of `reads` have changed,
+    /// with the next commit number or else `number`.
+    async fn commit_checked(&self, reads: Option<&bdb::ReadSet>, number: Option<Commit>) -> Result<Commit> {
+        let trees = self.inner.opened_tree_names().await;
         let batch_commit = self.inner.new_batch_commit_number();
         let mut error = None;
-        for tree in self.trees.iter() {
+        for tree in trees.iter() {
             if error.is_none() {
                 let r = self.inner.ready_commit(tree, batch_commit).await;
                 if let Err(e) = r {
@@ -202,24 +704,46 @@ impl WriteBatch {
             return Err(e);
         }
 
-        self.inner.commit(batch_commit).await?;
+        let r = self.inner.commit(&trees, batch_commit, self.sync_on_commit, reads, number).await;
+        let commit = match r {
+            Ok(commit) => commit,
+            Err(e) => {
+                if e.is::<Precondition>() || e.is::<Conflict>() {
+                    self.abort().await;
+                }
+                return Err(e);
+            },
+        };
+
+        if let Some(syncer) = &self.syncer {
+            syncer.committed();
+        }
 
-        Ok(())
+        if let Some(checkpointer) = &self.checkpointer {
+            checkpointer.committed(commit);
+        }
+
+        self.locks.release(self.inner.number());
+
+        Ok(commit)
     }
 
     pub async fn abort(&self) {
+        let trees = self.inner.opened_tree_names().await;
         let batch_commit = self.inner.new_batch_commit_number();
-        for tree in self.trees.iter() {
+        for tree in trees.iter() {
             let r = self.inner.abort_commit(tree, batch_commit).await;
             if let Err(e) = r {
                 error!("error aborting batch commit {} for batch {} for tree {}: {}",
                        batch_commit.0, self.inner.number().0, tree, e);
             }
         }
+
+        self.locks.release(self.inner.number());
     }
 
     pub async fn close(mut self) {
-        for tree in self.trees.iter() {
+        for tree in self.inner.opened_tree_names().await.iter() {
             let r = self.inner.close(tree).await;
             if let Err(e) = r {
                 error!("error closing batch {} for tree {}: {}",
@@ -227,12 +751,17 @@ impl WriteBatch {
             }
         }
 
+        self.locks.release(self.inner.number());
         self.closed = true;
     }
 }
 
 impl Drop for WriteBatch {
     fn drop(&mut self) {
+        self.locks.release(self.inner.number());
+        if let Some(writers) = &self.writers {
+            writers.lock().expect("lock").open_batches -= 1;
+        }
         if !self.closed {
             error!("write batch {} not closed", self.inner.number().0);
             // TODO: last-ditch attempt in another thread?
@@ -240,6 +769,12 @@ impl Drop for WriteBatch {
     }
 }
 
+impl<'db> Drop for RestoreGuard<'db> {
+    fn drop(&mut self) {
+        self.0.lock().expect("lock").restoring = false;
+    }
+}
+
 impl ReadView {
     pub fn tree<'view>(&'view self, tree: &str) -> ReadTree<'view> {
         ReadTree {
@@ -247,6 +782,10 @@ impl ReadView {
             view: self,
         }
     }
+
+    pub fn commit(&self) -> Commit {
+        self.inner.commit_limit()
+    }
 }
 
 impl<'batch> WriteTree<'batch> {
@@ -261,6 +800,40 @@ impl<'batch> WriteTree<'batch> {
     pub async fn delete_range(&self, start_key: &[u8], end_key: &[u8]) -> Result<()> {
         Ok(self.batch.inner.delete_range(&self.tree, Key::from_slice(start_key), Key::from_slice(end_key)).await?)
     }
+
+    pub async fn write_if(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<()> {
+        self.batch.inner.write_if(&self.tree, Key::from_slice(key), expected.map(Value::from_slice), Value::from_slice(value)).await
+    }
+
+    pub async fn delete_if(&self, key: &[u8], expected: &[u8]) -> Result<()> {
+        self.batch.inner.delete_if(&self.tree, Key::from_slice(key), Value::from_slice(expected)).await
+    }
+
+    pub async fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.batch.inner.merge(&self.tree, Key::from_slice(key), Value::from_slice(operand)).await
+    }
+
+    pub async fn read(&self, view: &ReadView, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.batch.inner.read(&view.inner, &self.tree, &Key::from_slice(key)).await?
+           .map(|v| v.0.clone()))
+    }
+
+    pub async fn cursor(&self, view: &ReadView) -> Result<Cursor> {
+        Ok(Cursor {
+            inner: self.batch.inner.cursor(&view.inner, &self.tree).await?,
+            reads: None,
+        })
+    }
+
+    pub async fn lock(&self, key: &[u8], mode: LockMode) -> Result<()> {
+        let span = Span::point(Key::from_slice(key));
+        self.batch.locks.lock(self.batch.inner.number(), &self.tree, span, mode).await
+    }
+
+    pub async fn lock_range(&self, start_key: &[u8], end_key: &[u8], mode: LockMode) -> Result<()> {
+        let span = Span::range(Key::from_slice(start_key), Key::from_slice(end_key));
+        self.batch.locks.lock(self.batch.inner.number(), &self.tree, span, mode).await
+    }
 }
 
 impl<'view> ReadTree<'view> {
@@ -269,10 +842,91 @@ impl<'view> ReadTree<'view> {
            .map(|v| v.0.clone()))
     }
 
-    pub fn cursor(&self) -> Cursor {
-        Cursor {
-            inner: self.view.inner.cursor(&self.tree),
+    pub fn cursor(&self) -> Result<Cursor> {
+        Ok(Cursor {
+            inner: self.view.inner.cursor(&self.tree)?,
+            reads: None,
+        })
+    }
+
+    pub async fn history(&self, key: &[u8]) -> Result<Vec<(Commit, Option<Vec<u8>>)>> {
+        let history = self.view.inner.history(&self.tree, &Key::from_slice(key)).await?;
+        Ok(history.into_iter().map(|(commit, value)| (commit, value.map(|v| v.0))).collect())
+    }
+}
+
+impl Transaction {
+    pub fn tree<'tx>(&'tx self, tree: &str) -> TransactionTree<'tx> {
+        TransactionTree {
+            tree: tree.to_string(),
+            tx: self,
+        }
+    }
+
+    pub async fn push_save_point(&self) -> Result<()> {
+        self.batch.push_save_point().await
+    }
+
+    pub async fn pop_save_point(&self) -> Result<()> {
+        self.batch.pop_save_point().await
+    }
+
+    pub async fn rollback_save_point(&self) -> Result<()> {
+        self.batch.rollback_save_point().await
+    }
+
+    pub async fn commit(&self) -> Result<Commit> {
+        // Later commits would conflict with the first
+        if self.committed.swap(true, Ordering::SeqCst) {
+            bail!("transaction already committed");
         }
+
+        let reads = self.reads.lock().expect("lock").clone();
+        self.batch.commit_checked(Some(&reads), None).await
+    }
+
+    pub async fn abort(&self) {
+        self.batch.abort().await
+    }
+
+    pub async fn close(self) {
+        self.batch.close().await
+    }
+}
+
+impl<'tx> TransactionTree<'tx> {
+    pub async fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = Key::from_slice(key);
+        self.tx.reads.lock().expect("lock").insert(&self.tree, KeyRange::point(key.clone()));
+        Ok(self.tx.batch.inner.read(&self.tx.view.inner, &self.tree, &key).await?
+           .map(|v| v.0.clone()))
+    }
+
+    pub async fn cursor(&self) -> Result<Cursor> {
+        Ok(Cursor {
+            inner: self.tx.batch.inner.cursor(&self.tx.view.inner, &self.tree).await?,
+            reads: Some(CursorReads {
+                reads: self.tx.reads.clone(),
+                tree: self.tree.clone(),
+                index: None,
+            }),
+        })
+    }
+
+    pub async fn write(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.tx.batch.tree(&self.tree).write(key, value).await
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> Result<()> {
+        self.tx.batch.tree(&self.tree).delete(key).await
+    }
+
+    pub async fn delete_range(&self, start_key: &[u8], end_key: &[u8]) -> Result<()> {
+        self.tx.batch.tree(&self.tree).delete_range(start_key, end_key).await
+    }
+
+    pub async fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.tx.batch.tree(&self.tree).merge(key, operand).await
     }
 }
 
@@ -290,27 +944,65 @@ impl Cursor {
     }
 
     pub fn next(&mut self) {
-        self.inner.next()
+        let from = self.tracked_key();
+        self.inner.next();
+        let to = self.tracked_key();
+        self.record(from, to);
     }
 
     pub fn prev(&mut self) {
-        self.inner.prev()
+        let from = self.tracked_key();
+        self.inner.prev();
+        let to = self.tracked_key();
+        self.record(to, from);
     }
 
     pub fn seek_first(&mut self) {
-        self.inner.seek_first()
+        self.inner.seek_first();
+        let to = self.tracked_key();
+        self.record(None, to);
     }
 
     pub fn seek_last(&mut self) {
-        self.inner.seek_last()
+        self.inner.seek_last();
+        let to = self.tracked_key();
+        self.record(to, None);
     }
 
     pub fn seek_key(&mut self, key: &[u8]) {
-        self.inner.seek_key(Key::from_slice(key))
+        let key = Key::from_slice(key);
+        self.inner.seek_key(key.clone());
+        let to = self.tracked_key();
+        self.record(Some(key), to);
     }
 
     pub fn seek_key_rev(&mut self, key: &[u8]) {
-        self.inner.seek_key_rev(Key::from_slice(key))
+        let key = Key::from_slice(key);
+        self.inner.seek_key_rev(key.clone());
+        let to = self.tracked_key();
+        self.record(to, Some(key));
+    }
+
+    /// The current key, if reads are recorded and the cursor is valid.
+    fn tracked_key(&self) -> Option<Key> {
+        if self.reads.is_some() && self.inner.valid() {
+            Some(self.inner.key())
+        } else {
+            None
+        }
+    }
+
+    /// Record that every key from `start` to `end` was read,
+    /// where a missing bound is the end of the tree.
+    fn record(&mut self, start: Option<Key>, end: Option<Key>) {
+        if let Some(cursor_reads) = &mut self.reads {
+            let range = KeyRange { start, end };
+            let mut reads = cursor_reads.reads.lock().expect("lock");
+            match cursor_reads.index {
+                Some(index) => reads.extend(&cursor_reads.tree, index, range),
+                None => cursor_reads.index = Some(reads.insert(&cursor_reads.tree, range)),
+            }
+        }
     }
 }
 
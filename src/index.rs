@@ -5,8 +5,10 @@ use parking_lot::{RwLock as PlRwLock, RwLockWriteGuard as PlRwLockWriteGuard};
 use std::sync::{RwLock, RwLockWriteGuard};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::collections::btree_map::{BTreeMap, Entry};
+use std::collections::HashSet;
 use std::ops::Range;
-use crate::types::{Key, Address, Commit};
+use serde::{Serialize, Deserialize};
+use crate::types::{Key, KeyRange, Address, Commit};
 
 /// An index from keys to addresses in a log.
 pub struct Index {
@@ -16,7 +18,29 @@ pub struct Index {
 
 struct IndexState {
     keymap: BTreeMap<Key, Arc<Node>>,
-    range_deletes: Vec<(Commit, Range<Key>, BatchIdx)>,
+    range_deletes: RangeDeletes,
+}
+
+/// Range deletes, each stored once.
+///
+/// The keys are split into non-overlapping fragments.
+/// Each fragment starts at its key and runs to the next fragment's key,
+/// and holds the most recent range delete covering it.
+/// Each range delete holds the fragments it covered when it was written,
+/// so a key's earlier range deletes are found by following
+/// the fragments containing it back in time.
+struct RangeDeletes {
+    fragments: BTreeMap<Key, Fragment>,
+}
+
+type Fragment = Option<Arc<RangeDelete>>;
+
+struct RangeDelete {
+    range: Range<Key>,
+    commit: Commit,
+    batch_idx: BatchIdx,
+    /// The fragments this delete covered, the first starting at `range.start`
+    covered: RwLock<Vec<(Key, Fragment)>>,
 }
 
 #[derive(Debug)]
@@ -34,7 +58,7 @@ struct BatchIdx(pub u32);
 
 pub struct Cursor {
     commit_limit: Commit,
-    current: Option<(Arc<Node>, Address)>,
+    current: Option<Arc<Node>>,
     state: Arc<PlRwLock<IndexState>>,
 }
 
@@ -45,32 +69,118 @@ pub struct Writer<'index> {
     batch_index: BatchIdx,
 }
 
+#[derive(Serialize, Deserialize)]
 #[derive(Copy, Clone)]
 #[derive(Debug)]
+#[serde(tag = "type")]
 pub enum ReadValue {
     Written(Address),
     Deleted(Address),
+    Merged(Address),
+}
+
+/// The contents of an index, as saved in an index checkpoint.
+///
+/// Fields holding tables come last so that this can be written as TOML,
+/// which also can't write empty arrays after them.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+pub struct Snapshot {
+    next_commit: Commit,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    keys: Vec<KeySnapshot>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    range_deletes: Vec<RangeDeleteSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+struct KeySnapshot {
+    key: Key,
+    history: Vec<VersionSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+struct VersionSnapshot {
+    commit: Commit,
+    batch_idx: u32,
+    value: ReadValue,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+struct RangeDeleteSnapshot {
+    start: Key,
+    end: Key,
+    commit: Commit,
+    batch_idx: u32,
+}
+
+/// The state of a key as of a commit limit.
+///
+/// This distinguishes keys that were
+/// deleted from keys the index knows nothing about,
+/// so that indexes can be stacked on top of each other.
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum Lookup {
+    Written(Address),
+    Deleted,
+    Missing,
+    /// Merge operands, oldest first,
+    /// to fold into the value found by `base`,
+    /// which is never itself `Merged`.
+    Merged {
+        operands: Vec<Address>,
+        base: Box<Lookup>,
+    },
 }
 
 impl Index {
     pub fn new() -> Index {
+        Index::starting_at(Commit(0))
+    }
+
+    /// Create an index whose first commit will be no less than `next_commit`.
+    pub fn starting_at(next_commit: Commit) -> Index {
         Index {
             state: Arc::new(PlRwLock::new(IndexState {
                 keymap: BTreeMap::new(),
-                range_deletes: Vec::new(),
+                range_deletes: RangeDeletes::new(),
             })),
-            maybe_next_commit: AtomicU64::new(0),
+            maybe_next_commit: AtomicU64::new(next_commit.0),
         }
     }
 
-    pub fn read(&self, commit_limit: Commit, key: &Key) -> Option<Address> {
-        assert!(commit_limit <= Commit(self.maybe_next_commit.load(Ordering::SeqCst)));
+    pub fn next_commit(&self) -> Commit {
+        Commit(self.maybe_next_commit.load(Ordering::SeqCst))
+    }
+
+    // Reads may be limited past the next commit,
+    // since a tree doesn't take part in every commit,
+    // and no commit earlier than the next commit can be written.
+    pub fn lookup(&self, commit_limit: Commit, key: &Key) -> Lookup {
+        let state = self.state.read();
+        state.key_lookup(commit_limit, key)
+    }
+
+    /// The commits before the commit limit that wrote,
+    /// deleted, range deleted or merged the key, oldest first.
+    pub fn versions(&self, commit_limit: Commit, key: &Key) -> Vec<Commit> {
         let state = self.state.read();
-        state.key_true_value(commit_limit, key)
+        let mut commits: Vec<Commit> = state.range_deletes.commits(key);
+        if let Some(node) = state.keymap.get(key) {
+            let history = node.history.read().expect("lock");
+            commits.extend(history.iter().map(|(commit, _, _)| *commit));
+        }
+        commits.retain(|commit| *commit < commit_limit);
+        commits.sort();
+        commits.dedup();
+        commits
     }
 
     pub fn cursor(&self, commit_limit: Commit) -> Cursor {
-        assert!(commit_limit <= Commit(self.maybe_next_commit.load(Ordering::SeqCst)));
         Cursor {
             commit_limit,
             current: None,
@@ -78,6 +188,152 @@ impl Index {
         }
     }
 
+    /// Whether any key in `range` was written, deleted, or range deleted
+    /// by a commit at or after `since`.
+    pub fn written_since(&self, since: Commit, range: &KeyRange) -> bool {
+        if let (Some(start), Some(end)) = (&range.start, &range.end) {
+            if start > end {
+                return false;
+            }
+        }
+
+        let state = self.state.read();
+        let mut nodes = state.keymap.range::<Key, _>(range.bounds());
+        let written = nodes.any(|(_, node)| {
+            let history = node.history.read().expect("lock");
+            matches!(history.last(), Some((commit, _, _)) if *commit >= since)
+        });
+        written || state.range_deletes.written_since(since, range)
+    }
+
+    /// Discard history that no read at `horizon` or later can see.
+    ///
+    /// If no index is stacked below this one
+    /// then deletes before `horizon` have nothing to hide,
+    /// and are discarded along with keys left with no history.
+    ///
+    /// Returns the number of versions and range deletes discarded.
+    pub fn collect_garbage(&self, horizon: Commit, bottom: bool) -> usize {
+        let mut state = self.state.write();
+        let state = &mut *state;
+        let mut discarded = 0;
+        let mut empty = vec![];
+        for node in state.keymap.values() {
+            let mut history = node.history.write().expect("lock");
+
+            // Versions before the last range delete before the horizon are hidden
+            if let Some(range_delete) = state.range_deletes.query(horizon, &node.key) {
+                let hidden = history.partition_point(|(commit, _, batch_idx)| {
+                    (*commit, *batch_idx) < range_delete
+                });
+                history.drain(..hidden);
+                discarded += hidden;
+            }
+
+            discarded += discard_hidden(&mut history, horizon, |(commit, _, _)| *commit, |(_, value, _)| {
+                !matches!(value, ReadValue::Merged(_))
+            });
+
+            if bottom {
+                if let [(commit, ReadValue::Deleted(_), _)] = history[..] {
+                    if commit < horizon {
+                        history.clear();
+                        discarded += 1;
+                    }
+                }
+            }
+
+            if history.is_empty() {
+                empty.push(node.clone());
+            }
+        }
+
+        for node in empty {
+            state.unlink(&node);
+        }
+
+        discarded += state.range_deletes.collect_garbage(horizon, bottom);
+        discarded
+    }
+
+    /// The number of versions and range deletes in the index.
+    pub fn entries(&self) -> usize {
+        let state = self.state.read();
+        let versions: usize = state.keymap.values().map(|node| {
+            node.history.read().expect("lock").len()
+        }).sum();
+        versions + state.range_deletes.all().len()
+    }
+
+    /// Copy the index's contents,
+    /// as of every commit written to it so far.
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.state.read();
+        let keys = state.keymap.values().map(|node| {
+            let history = node.history.read().expect("lock");
+            KeySnapshot {
+                key: node.key.clone(),
+                history: history.iter().map(|(commit, value, batch_idx)| VersionSnapshot {
+                    commit: *commit,
+                    batch_idx: batch_idx.0,
+                    value: *value,
+                }).collect(),
+            }
+        }).collect();
+        let range_deletes = state.range_deletes.all().iter().map(|delete| {
+            RangeDeleteSnapshot {
+                start: delete.range.start.clone(),
+                end: delete.range.end.clone(),
+                commit: delete.commit,
+                batch_idx: delete.batch_idx.0,
+            }
+        }).collect();
+        Snapshot {
+            next_commit: self.next_commit(),
+            keys,
+            range_deletes,
+        }
+    }
+
+    /// Empty the index, as if just created starting at `next_commit`.
+    pub fn reset(&self, next_commit: Commit) {
+        let mut state = self.state.write();
+        state.keymap = BTreeMap::new();
+        state.range_deletes = RangeDeletes::new();
+        self.maybe_next_commit.store(next_commit.0, Ordering::SeqCst);
+    }
+
+    /// Fill an empty index from a snapshot.
+    pub fn restore(&self, snapshot: Snapshot) {
+        let mut state = self.state.write();
+        assert!(state.keymap.is_empty() && state.range_deletes.fragments.is_empty());
+
+        let mut prev: Option<Arc<Node>> = None;
+        for key in snapshot.keys {
+            let history = key.history.into_iter().map(|version| {
+                (version.commit, version.value, BatchIdx(version.batch_idx))
+            }).collect();
+            let node = Arc::new(Node {
+                key: key.key.clone(),
+                prev: RwLock::new(prev.clone()),
+                next: RwLock::new(None),
+                history: RwLock::new(history),
+            });
+            if let Some(prev) = &prev {
+                *prev.next.write().expect("lock") = Some(node.clone());
+            }
+            state.keymap.insert(key.key, node.clone());
+            prev = Some(node);
+        }
+
+        for delete in snapshot.range_deletes {
+            let range = delete.start..delete.end;
+            state.range_deletes.insert(range, delete.commit, BatchIdx(delete.batch_idx));
+        }
+
+        self.maybe_next_commit.store(snapshot.next_commit.0, Ordering::SeqCst);
+    }
+
     pub fn writer(&self, commit: Commit) -> Writer {
         assert!(commit >= Commit(self.maybe_next_commit.load(Ordering::SeqCst)));
         Writer {
@@ -110,77 +366,308 @@ impl Drop for Index {
 }
 
 impl IndexState {
-    fn point_query(&self, commit_limit: Commit, key: &Key) -> Option<(Commit, ReadValue, BatchIdx)> {
-        if let Some(node) = self.keymap.get(key) {
-            self.node_value_within_commit_limit(commit_limit, node)
+    /// Remove a key, leaving its node linked to its neighbours
+    /// for any cursor still on it.
+    fn unlink(&mut self, node: &Arc<Node>) {
+        self.keymap.remove(&node.key);
+        let prev = node.prev.read().expect("lock").clone();
+        let next = node.next.read().expect("lock").clone();
+        if let Some(prev) = &prev {
+            *prev.next.write().expect("lock") = next.clone();
+        }
+        if let Some(next) = &next {
+            *next.prev.write().expect("lock") = prev;
+        }
+    }
+
+    fn key_lookup(&self, commit_limit: Commit, key: &Key) -> Lookup {
+        let node = self.keymap.get(key).map(|node| &**node);
+        self.node_lookup(commit_limit, key, node)
+    }
+
+    /// Fold the history of a key back to its most recent write,
+    /// delete, or range delete before the commit limit.
+    fn node_lookup(&self, commit_limit: Commit, key: &Key, node: Option<&Node>) -> Lookup {
+        let range_delete = self.range_deletes.query(commit_limit, key);
+        let mut base = match range_delete {
+            Some(_) => Lookup::Deleted,
+            None => Lookup::Missing,
+        };
+        let mut operands = vec![];
+
+        if let Some(node) = node {
+            let history = node.history.read().expect("lock");
+            let visible = history.iter().rev().filter(|(commit, _, _)| *commit < commit_limit);
+            for (commit, value, batch_idx) in visible {
+                if matches!(range_delete, Some(range_delete) if (*commit, *batch_idx) < range_delete) {
+                    break;
+                }
+                match value {
+                    ReadValue::Written(addr) => {
+                        base = Lookup::Written(*addr);
+                        break;
+                    },
+                    ReadValue::Deleted(_) => {
+                        base = Lookup::Deleted;
+                        break;
+                    },
+                    ReadValue::Merged(addr) => {
+                        operands.push(*addr);
+                    },
+                }
+            }
+        }
+
+        if operands.is_empty() {
+            base
         } else {
-            None
+            operands.reverse();
+            Lookup::Merged {
+                operands,
+                base: Box::new(base),
+            }
+        }
+    }
+
+    /// Whether the key has a value before the commit limit.
+    fn node_visible(&self, commit_limit: Commit, node: &Node) -> bool {
+        match self.node_lookup(commit_limit, &node.key, Some(node)) {
+            Lookup::Written(_) | Lookup::Merged { .. } => true,
+            Lookup::Deleted | Lookup::Missing => false,
         }
     }
+}
 
-    fn node_value_within_commit_limit(&self, commit_limit: Commit, node: &Node) -> Option<(Commit, ReadValue, BatchIdx)> {
-        let history = node.history.read().expect("lock");
-        let mut rev_iter = history.iter().rev();
-        let most_recent = rev_iter.find(|(commit, _, _)| *commit < commit_limit);
-        most_recent.cloned()
+impl RangeDeletes {
+    fn new() -> RangeDeletes {
+        RangeDeletes {
+            fragments: BTreeMap::new(),
+        }
     }
 
-    fn range_delete_query(&self, commit_limit: Commit, key: &Key) -> Option<(Commit, BatchIdx)> {
-        let mut rev_iter = self.range_deletes.iter().rev();
-        let match_ = rev_iter.find(|(commit, range, _)| {
-            *commit < commit_limit && range.contains(key)
-        });
-        match_.map(|(commit, _, batch_idx)| (*commit, *batch_idx))
-    }
-
-    fn key_true_value(&self, commit_limit: Commit, key: &Key) -> Option<Address> {
-        let point_result = self.point_query(commit_limit, key);
-        let range_delete_result = self.range_delete_query(commit_limit, key);
-        self.inner_true_value(point_result, range_delete_result)
-    }
-
-    fn node_true_value(&self, commit_limit: Commit, node: &Node) -> Option<Address> {
-        let point_result = self.node_value_within_commit_limit(commit_limit, node);
-        let range_delete_result = self.range_delete_query(commit_limit, &node.key);
-        self.inner_true_value(point_result, range_delete_result)
-    }
-
-    fn inner_true_value(&self,
-                        point_result: Option<(Commit, ReadValue, BatchIdx)>,
-                        range_delete_result: Option<(Commit, BatchIdx)>) -> Option<Address> {
-        match (point_result, range_delete_result) {
-            (None, Some(_)) => {
-                None
-            },
-            (Some((p_commit, ReadValue::Written(addr), p_batch_idx)), Some((rd_commit, rd_batch_idx))) => {
-                if p_commit > rd_commit {
-                    Some(addr)
-                } else if p_commit == rd_commit {
-                    if p_batch_idx > rd_batch_idx {
-                        Some(addr)
-                    } else {
-                        None
+    /// Add a range delete.
+    ///
+    /// Commits must not decrease from one insert to the next,
+    /// so that each fragment's range delete is its most recent.
+    fn insert(&mut self, range: Range<Key>, commit: Commit, batch_idx: BatchIdx) {
+        if range.start == range.end {
+            return;
+        }
+
+        self.split_at(&range.start);
+        self.split_at(&range.end);
+
+        // Replace the covered fragments with a single fragment
+        let starts: Vec<Key> = self.fragments.range(range.clone()).map(|(start, _)| start.clone()).collect();
+        let covered: Vec<(Key, Fragment)> = starts.into_iter().map(|start| {
+            let delete = self.fragments.remove(&start).expect("fragment");
+            (start, delete)
+        }).collect();
+
+        debug_assert!(covered.iter().filter_map(|(_, delete)| delete.as_ref()).all(|delete| {
+            (delete.commit, delete.batch_idx) < (commit, batch_idx)
+        }));
+
+        let delete = RangeDelete {
+            range: range.clone(),
+            commit,
+            batch_idx,
+            covered: RwLock::new(covered),
+        };
+        self.fragments.insert(range.start, Some(Arc::new(delete)));
+    }
+
+    /// Start a fragment at `key`, with the range delete of the fragment containing it.
+    fn split_at(&mut self, key: &Key) {
+        if self.fragments.contains_key(key) {
+            return;
+        }
+
+        let delete = self.fragments.range(..key.clone()).next_back()
+            .and_then(|(_, delete)| delete.clone());
+        self.fragments.insert(key.clone(), delete);
+    }
+
+    /// The most recent range delete of a key.
+    fn latest(&self, key: &Key) -> Option<Arc<RangeDelete>> {
+        self.fragments.range(..=key.clone()).next_back()
+            .and_then(|(_, delete)| delete.clone())
+    }
+
+    /// Every range delete, in the order they were written.
+    fn all(&self) -> Vec<Arc<RangeDelete>> {
+        let mut seen = HashSet::new();
+        let mut all = vec![];
+        let mut unvisited: Vec<_> = self.fragments.values().flatten().cloned().collect();
+        while let Some(delete) = unvisited.pop() {
+            if !seen.insert(Arc::as_ptr(&delete)) {
+                continue;
+            }
+            let covered = delete.covered.read().expect("lock");
+            unvisited.extend(covered.iter().filter_map(|(_, delete)| delete.clone()));
+            drop(covered);
+            all.push(delete);
+        }
+        all.sort_by_key(|delete| (delete.commit, delete.batch_idx));
+        all
+    }
+
+    /// Discard the range deletes that are hidden at `horizon` and later,
+    /// being covered by a later range delete before `horizon`.
+    ///
+    /// If `bottom` then every range delete before `horizon` is discarded,
+    /// having nothing left to hide.
+    ///
+    /// Returns the number of range deletes discarded.
+    fn collect_garbage(&mut self, horizon: Commit, bottom: bool) -> usize {
+        let before_horizon = |delete: &Fragment| {
+            matches!(delete, Some(delete) if delete.commit < horizon)
+        };
+
+        let mut hidden = vec![];
+        if bottom {
+            for delete in self.fragments.values_mut() {
+                if before_horizon(delete) {
+                    hidden.extend(delete.take());
+                }
+            }
+        }
+
+        // Whatever a range delete before the horizon covered is hidden
+        let mut seen = HashSet::new();
+        let mut hiding = vec![];
+        let mut unvisited: Vec<_> = self.fragments.values().flatten().cloned().collect();
+        while let Some(delete) = unvisited.pop() {
+            if !seen.insert(Arc::as_ptr(&delete)) {
+                continue;
+            }
+            if delete.commit < horizon {
+                hiding.push(delete);
+            } else {
+                let mut covered = delete.covered.write().expect("lock");
+                if bottom {
+                    for (_, delete) in covered.iter_mut() {
+                        if before_horizon(delete) {
+                            hidden.extend(delete.take());
+                        }
                     }
-                } else {
-                    None
                 }
-            },
-            (Some((_, ReadValue::Deleted(addr), _)), Some(_)) => {
-                None
-            },
-            (Some((_, ReadValue::Written(addr), _)), None) => {
-                Some(addr)
-            },
-            (Some((_, ReadValue::Deleted(addr), _)), None) => {
-                None
-            },
-            (None, None) => {
-                None
-            },
+                unvisited.extend(covered.iter().filter_map(|(_, delete)| delete.clone()));
+            }
+        }
+
+        for delete in &hiding {
+            let covered = std::mem::take(&mut *delete.covered.write().expect("lock"));
+            hidden.extend(covered.into_iter().filter_map(|(_, delete)| delete));
+        }
+        drop(hiding);
+
+        let discarded = release(hidden);
+
+        // Merge fragments that no longer differ
+        let mut prev: Option<&Fragment> = None;
+        let mut redundant = vec![];
+        for (key, delete) in &self.fragments {
+            let same = match (prev, delete) {
+                (Some(Some(prev)), Some(delete)) => Arc::ptr_eq(prev, delete),
+                (Some(None), None) | (None, None) => true,
+                _ => false,
+            };
+            if same {
+                redundant.push(key.clone());
+            } else {
+                prev = Some(delete);
+            }
+        }
+        for key in redundant {
+            self.fragments.remove(&key);
+        }
+
+        discarded
+    }
+
+    fn written_since(&self, since: Commit, range: &KeyRange) -> bool {
+        // The fragment containing the start of the range,
+        // and every fragment starting within it
+        let first = range.start.as_ref().and_then(|start| {
+            self.fragments.range(..=start.clone()).next_back()
+        });
+        let rest = self.fragments.range::<Key, _>(range.bounds());
+        first.into_iter().chain(rest).any(|(_, delete)| {
+            matches!(delete, Some(delete) if delete.commit >= since)
+        })
+    }
+
+    /// The commits that range deleted a key, latest first.
+    fn commits(&self, key: &Key) -> Vec<Commit> {
+        let mut commits = vec![];
+        let mut delete = self.latest(key);
+        while let Some(earlier) = delete {
+            commits.push(earlier.commit);
+            delete = earlier.covering(key);
         }
+        commits
+    }
+
+    /// The most recent range delete of a key before the commit limit.
+    fn query(&self, commit_limit: Commit, key: &Key) -> Option<(Commit, BatchIdx)> {
+        let mut delete = self.latest(key)?;
+        while delete.commit >= commit_limit {
+            delete = delete.covering(key)?;
+        }
+        Some((delete.commit, delete.batch_idx))
     }
 }
 
+/// Drop range deletes, and those they covered that nothing else refers to,
+/// without recursing through long chains of them.
+///
+/// Returns the number of range deletes dropped.
+fn release(mut deletes: Vec<Arc<RangeDelete>>) -> usize {
+    let mut released = 0;
+    while let Some(delete) = deletes.pop() {
+        if let Ok(mut delete) = Arc::try_unwrap(delete) {
+            released += 1;
+            let covered = std::mem::take(delete.covered.get_mut().expect("lock"));
+            deletes.extend(covered.into_iter().filter_map(|(_, delete)| delete));
+        }
+    }
+    released
+}
+
+impl Drop for RangeDelete {
+    fn drop(&mut self) {
+        if let Ok(covered) = self.covered.get_mut() {
+            let covered = std::mem::take(covered);
+            release(covered.into_iter().filter_map(|(_, delete)| delete).collect());
+        }
+    }
+}
+
+impl RangeDelete {
+    /// The range delete of a key that this one covered.
+    fn covering(&self, key: &Key) -> Option<Arc<RangeDelete>> {
+        let covered = self.covered.read().expect("lock");
+        let containing = covered.partition_point(|(start, _)| start <= key);
+        containing.checked_sub(1).and_then(|i| covered[i].1.clone())
+    }
+}
+
+/// Discard the entries before the last base entry before `horizon`,
+/// which with any later entries is all that is visible at `horizon` and later.
+///
+/// Entries that aren't bases are merges into the entries before them.
+///
+/// Returns the number of entries discarded.
+fn discard_hidden<T>(entries: &mut Vec<T>, horizon: Commit,
+                     commit: impl Fn(&T) -> Commit,
+                     is_base: impl Fn(&T) -> bool) -> usize {
+    let before_horizon = entries.partition_point(|entry| commit(entry) < horizon);
+    let hidden = entries[..before_horizon].iter().rposition(is_base).unwrap_or(0);
+    entries.drain(..hidden);
+    hidden
+}
+
 impl Cursor {
     pub fn valid(&self) -> bool {
         self.current.is_some()
@@ -188,22 +675,17 @@ impl Cursor {
 
     pub fn key(&self) -> Key {
         assert!(self.valid());
-        self.current.as_ref().expect("valid").0.key.clone()
-    }
-
-    pub fn address(&self) -> Address {
-        assert!(self.valid());
-        self.current.as_ref().expect("valid").1
+        self.current.as_ref().expect("valid").key.clone()
     }
 
     pub fn next(&mut self) {
         assert!(self.valid());
         let mut candidate_node = {
-            self.current.as_ref().expect("valid").0.next.read().expect("lock").clone()
+            self.current.as_ref().expect("valid").next.read().expect("lock").clone()
         };
         while let Some(node) = candidate_node {
-            if let Some(addr) = self.value_within_commit_limit(&node) {
-                self.current = Some((node, addr));
+            if self.visible(&node) {
+                self.current = Some(node);
                 return;
             }
             candidate_node = node.next.read().expect("lock").clone();
@@ -214,11 +696,11 @@ impl Cursor {
     pub fn prev(&mut self) {
         assert!(self.valid());
         let mut candidate_node = {
-            self.current.as_ref().expect("valid").0.prev.read().expect("lock").clone()
+            self.current.as_ref().expect("valid").prev.read().expect("lock").clone()
         };
         while let Some(node) = candidate_node {
-            if let Some(addr) = self.value_within_commit_limit(&node) {
-                self.current = Some((node, addr));
+            if self.visible(&node) {
+                self.current = Some(node);
                 return;
             }
             candidate_node = node.prev.read().expect("lock").clone();
@@ -229,38 +711,36 @@ impl Cursor {
     pub fn seek_first(&mut self) {
         let state = self.state.read();
         let iter = state.keymap.iter();
-        self.current = self.first_within_commit_limit(iter);
+        self.current = self.first_within_commit_limit(&state, iter);
     }
 
     pub fn seek_last(&mut self) {
         let state = self.state.read();
         let iter = state.keymap.iter().rev();
-        self.current = self.first_within_commit_limit(iter);
+        self.current = self.first_within_commit_limit(&state, iter);
     }
 
     pub fn seek_key(&mut self, key: Key) {
         let state = self.state.read();
         let iter = state.keymap.range(key..);
-        self.current = self.first_within_commit_limit(iter);
+        self.current = self.first_within_commit_limit(&state, iter);
     }
 
     pub fn seek_key_rev(&mut self, key: Key) {
         let state = self.state.read();
         let iter = state.keymap.range(..=key).rev();
-        self.current = self.first_within_commit_limit(iter);
+        self.current = self.first_within_commit_limit(&state, iter);
     }
 
-    fn value_within_commit_limit(&self, node: &Node) -> Option<Address> {
+    fn visible(&self, node: &Node) -> bool {
         let state = self.state.read();
-        state.node_true_value(self.commit_limit, node)
+        state.node_visible(self.commit_limit, node)
     }
 
-    fn first_within_commit_limit<'a>(&self, iter: impl Iterator<Item = (&'a Key, &'a Arc<Node>)>) -> Option<(Arc<Node>, Address)> {
+    fn first_within_commit_limit<'a>(&self, state: &IndexState, iter: impl Iterator<Item = (&'a Key, &'a Arc<Node>)>) -> Option<Arc<Node>> {
         iter.map(|(_, node)| node)
-            .filter_map(|node| {
-                self.value_within_commit_limit(node).map(|addr| (node.clone(), addr))
-            })
-            .next()
+            .find(|node| state.node_visible(self.commit_limit, node))
+            .cloned()
     }
 }
 
@@ -275,11 +755,16 @@ impl<'index> Writer<'index> {
         self.update_value(key, ReadValue::Deleted(addr), batch_idx)
     }
 
+    pub fn merge(&mut self, key: Key, addr: Address) {
+        let batch_idx = self.next_batch_index();
+        self.update_value(key, ReadValue::Merged(addr), batch_idx)
+    }
+
     pub fn delete_range(&mut self, range: Range<Key>, addr: Address)
     {
         let batch_idx = self.next_batch_index();
         assert!(range.start <= range.end);
-        self.state.range_deletes.push((self.commit, range, batch_idx));
+        self.state.range_deletes.insert(range, self.commit, batch_idx);
     }
 
     fn update_value(&mut  self, key: Key, value: ReadValue, batch_idx: BatchIdx) {
@@ -0,0 +1,82 @@
+use serde::{Serialize, Deserialize};
+use anyhow::Result;
+use futures::StreamExt;
+use crate::log::Log;
+use crate::tree;
+use crate::types::{Address, Batch, BatchCommit, Commit};
+
+/// The log holding the latest index checkpoint.
+pub struct IndexCheckpoints {
+    log: Log<IndexCheckpoint>,
+}
+
+/// The indexes of every tree between two commits,
+/// and where their logs were up to.
+///
+/// Fields holding tables come last so that this can be written as TOML,
+/// which also can't write empty arrays after them.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+pub struct IndexCheckpoint {
+    /// The indexes hold every commit before this
+    pub commit: Commit,
+    pub next_batch: Batch,
+    pub next_batch_commit: BatchCommit,
+    /// The last command of the commit log
+    pub commit_log: Option<Address>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trees: Vec<TreeCheckpoint>,
+}
+
+/// The checkpoint of one of a tree's logs.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+pub struct TreeCheckpoint {
+    pub tree: String,
+    /// Tells a tree from an earlier one of the same name
+    pub first_batch: Batch,
+    /// The commit the log starts from
+    pub log: Commit,
+    pub checkpoint: tree::Checkpoint,
+}
+
+impl IndexCheckpoints {
+    pub fn new(log: Log<IndexCheckpoint>) -> IndexCheckpoints {
+        IndexCheckpoints { log }
+    }
+
+    /// The latest checkpoint that can be read, if any.
+    ///
+    /// A checkpoint is only an optimization,
+    /// so an unreadable one is ignored.
+    pub async fn load(&self) -> Option<IndexCheckpoint> {
+        let mut latest = None;
+        let mut replay = self.log.replay();
+        while let Some(checkpoint) = replay.next().await {
+            match checkpoint {
+                Ok((checkpoint, _)) => {
+                    latest = Some(checkpoint);
+                },
+                Err(e) => {
+                    log::warn!("ignoring unreadable index checkpoint: {}", e);
+                    break;
+                },
+            }
+        }
+        latest
+    }
+
+    /// Replace the checkpoint.
+    ///
+    /// If this is interrupted there is no checkpoint,
+    /// and the next open replays the logs in full.
+    pub async fn write(&self, checkpoint: IndexCheckpoint) -> Result<()> {
+        self.discard().await?;
+        self.log.append(checkpoint).await?;
+        self.log.sync().await
+    }
+
+    pub async fn discard(&self) -> Result<()> {
+        self.log.truncate(Address { segment: 0, offset: 0 }).await
+    }
+}
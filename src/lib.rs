@@ -25,6 +25,14 @@ mod log;
 mod index;
 /// Adds committed batches from the log to the index.
 mod batch_player;
+/// Locks on keys, held by write batches.
+mod lock_table;
+/// A batch's uncommitted writes, read on top of a view.
+mod pending_writes;
+/// Folds merged operands into values.
+mod merge;
+/// The changes made by commits, published to subscribers.
+mod changes;
 
 /// Commands in a tree's log.
 mod command;
@@ -35,20 +43,36 @@ mod types;
 mod log_file;
 /// An in-memory log.
 mod mem_log_file;
-/// A simple on-disk, human-readable, log.
+/// A simple on-disk log of segment files, human-readable or binary.
 mod simple_log_file;
+/// A log wrapper that injects faults, for testing.
+mod faulty_log_file;
 
 /// The master commit log.
 mod commit_log;
+/// The log of which trees exist.
+mod manifest;
 /// A section of a log file.
 mod frame;
+/// A compact, checksummed, section of a log file.
+mod binary_frame;
 /// Off-thread async file I/O.
 mod fs_thread;
+/// Periodic background syncing.
+mod syncer;
+/// Background index checkpoints.
+mod checkpointer;
 /// Loads a set of trees from logs and commit log.
 mod loader;
+/// Saved indexes, so that opening only replays the logs after them.
+mod index_checkpoint;
+/// Offline consistency checks of a database's logs.
+mod verify;
 
 /// A tree that compacts other trees.
 mod compacting_tree;
+/// The logs that make up a compacting tree.
+mod tree_logs;
 
 /// A simple script language for exercising the database.
 #[doc(hidden)]
@@ -60,6 +84,9 @@ pub mod raw {
     pub mod fs_thread {
         pub use crate::fs_thread::*;
     }
+    pub mod faulty_log_file {
+        pub use crate::faulty_log_file::*;
+    }
     pub mod log {
         pub use crate::log::*;
     }
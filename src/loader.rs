@@ -1,13 +1,25 @@
 use anyhow::{Result, bail};
 use std::collections::BTreeMap;
 use crate::commit_log::{CommitLog, CommitCommand};
-use crate::tree::Tree;
+use crate::compacting_tree::CompactingTree;
+use crate::index_checkpoint::IndexCheckpoint;
+use std::sync::Arc;
 use futures::stream::StreamExt;
 use crate::types::{Batch, BatchCommit, Commit};
 
-pub async fn load(commit_log: &CommitLog, trees: &BTreeMap<String, Tree>) -> Result<DbInitState> {
-    if commit_log.is_empty().await? {
-        for tree in trees.values() {
+/// Replay the commit log against the trees,
+/// each of which only takes part in batches from its first batch on.
+///
+/// Commits from `commit_limit` on are replayed as if they never happened.
+///
+/// Tree logs with an index in `checkpoint` are only replayed after it,
+/// and if they all have one, so is the commit log.
+pub async fn load(commit_log: &CommitLog,
+                  trees: &BTreeMap<String, (Arc<CompactingTree>, Batch)>,
+                  commit_limit: Option<Commit>,
+                  checkpoint: Option<IndexCheckpoint>) -> Result<DbInitState> {
+    if checkpoint.is_none() && commit_log.is_empty().await? {
+        for (tree, _) in trees.values() {
             tree.skip_init();
         }
         return Ok(DbInitState {
@@ -17,24 +29,86 @@ pub async fn load(commit_log: &CommitLog, trees: &BTreeMap<String, Tree>) -> Res
         });
     }
 
-    let mut commit_replay_stream = commit_log.replay();
+    let init_trees: BTreeMap<_, _> = trees.iter().map(|(tree_name, (tree, first_batch))| {
+        (tree_name, (tree.init_trees(), *first_batch))
+    }).collect();
 
-    let mut tree_players: BTreeMap<_, _> = trees.iter().map(|(tree_name, tree)| {
-        (tree_name, tree.init_replayer())
+    let (checkpoint, checkpoint_trees) = match checkpoint {
+        Some(IndexCheckpoint { commit, next_batch, next_batch_commit, commit_log, trees }) => {
+            log::debug!("resuming from the index checkpoint at commit {}", commit.0);
+            (Some((commit, next_batch, next_batch_commit, commit_log)), trees)
+        },
+        None => (None, vec![]),
+    };
+
+    let mut tree_checkpoints: BTreeMap<String, BTreeMap<_, _>> = BTreeMap::new();
+    for tree_checkpoint in checkpoint_trees {
+        // A dropped tree may have been recreated since
+        let same_tree = trees.get(&tree_checkpoint.tree)
+            .map(|(_, first_batch)| *first_batch == tree_checkpoint.first_batch)
+            .unwrap_or(false);
+        if same_tree {
+            tree_checkpoints.entry(tree_checkpoint.tree).or_default()
+                .insert(tree_checkpoint.log, tree_checkpoint.checkpoint);
+        }
+    }
+
+    let mut resumed_all = checkpoint.is_some();
+    let mut tree_players: BTreeMap<_, _> = init_trees.iter().map(|(tree_name, (init_trees, first_batch))| {
+        let player = match &checkpoint {
+            Some((commit, ..)) => {
+                let logs = tree_checkpoints.remove(tree_name.as_str()).unwrap_or_default();
+                let (player, resumed) = init_trees.resume_replayer(logs, *commit);
+                resumed_all &= resumed;
+                player
+            },
+            None => init_trees.init_replayer(),
+        };
+        (tree_name, (player, *first_batch))
     }).collect();
 
     let mut max_commit = None;
 
+    // Batches may only remain in the commit log
+    // after their trees have been compacted.
+    let mut max_batch = None;
+    let mut max_batch_commit = None;
+
+    // Nor are the batches before a checkpoint
+    // replayed from the tree logs.
+    let mut commit_log_start = None;
+    if let Some((commit, next_batch, next_batch_commit, commit_log_last)) = checkpoint {
+        max_batch = next_batch.0.checked_sub(1).map(Batch);
+        max_batch_commit = next_batch_commit.0.checked_sub(1).map(BatchCommit);
+        if resumed_all {
+            max_commit = commit.0.checked_sub(1).map(Commit);
+            commit_log_start = commit_log_last;
+        }
+    }
+
+    let mut commit_replay_stream = commit_log.replay_after(commit_log_start);
+
     while let Some(next_commit) = commit_replay_stream.next().await {
         log::trace!("next commit {:?}", next_commit);
         let next_commit = next_commit?;
 
-        // FIXME: If a tree doesn't participate in a batch,
-        // then this will not work as expected and eat
-        // a bunch of memory.
-        // Fix for this is to do ready-commit under its
-        // own lock so that it is serialized.
-        for (_, player) in tree_players.iter_mut() {
+        if let Some(commit_limit) = commit_limit {
+            if next_commit.commit >= commit_limit {
+                break;
+            }
+        }
+
+        for (tree_name, (player, first_batch)) in tree_players.iter_mut() {
+            // Batches before the tree was created didn't write to it
+            if next_commit.batch < *first_batch {
+                continue;
+            }
+            // Nor did batches that only opened other trees
+            if let Some(trees) = &next_commit.trees {
+                if !trees.iter().any(|t| t == tree_name.as_str()) {
+                    continue;
+                }
+            }
             player.replay_commit(next_commit.batch,
                                  next_commit.batch_commit,
                                  next_commit.commit).await?;
@@ -47,12 +121,11 @@ pub async fn load(commit_log: &CommitLog, trees: &BTreeMap<String, Tree>) -> Res
         }
 
         max_commit = Some(next_commit.commit);
+        max_batch = max_batch.max(Some(next_commit.batch));
+        max_batch_commit = max_batch_commit.max(Some(next_commit.batch_commit));
     }
 
-    let mut max_batch = None;
-    let mut max_batch_commit = None;
-
-    for (_, player) in tree_players.iter_mut() {
+    for (_, (player, _)) in tree_players.iter_mut() {
         let (tree_max_batch, tree_max_batch_commit)
             = player.replay_rest().await?;
 
@@ -77,7 +150,7 @@ pub async fn load(commit_log: &CommitLog, trees: &BTreeMap<String, Tree>) -> Res
         }
     }
 
-    for (_, player) in tree_players.into_iter() {
+    for (_, (player, _)) in tree_players.into_iter() {
         player.init_success();
     }
 
@@ -0,0 +1,301 @@
+//! Locks on keys and key ranges, held by write batches.
+//!
+//! A batch waiting for a lock fails if its wait would complete
+//! a cycle of batches waiting for each other,
+//! or if it waits longer than the lock timeout.
+//! Timeouts are run by a background thread.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+use futures::channel::oneshot;
+use anyhow::{Result, anyhow};
+use crate::types::{Batch, Key};
+
+pub struct LockTable {
+    state: Arc<Mutex<State>>,
+    timeout: Option<Duration>,
+    /// Wakes the timeout thread to look for a new deadline
+    timer: Option<Mutex<mpsc::Sender<()>>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[derive(Eq, PartialEq)]
+pub enum LockMode {
+    /// May be held by many batches at once.
+    Shared,
+    /// May only be held by one batch at a time.
+    Exclusive,
+}
+
+/// The error of a batch that failed to take a lock.
+#[derive(Debug)]
+pub enum LockError {
+    /// The lock was not released before the lock timeout.
+    Timeout,
+    /// Waiting for the lock would have deadlocked.
+    Deadlock,
+}
+
+/// The keys from `start` to `end`.
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub start: Key,
+    pub end: Bound<Key>,
+}
+
+#[derive(Default)]
+struct State {
+    held: Vec<Request>,
+    waiting: BTreeMap<u64, Waiter>,
+    next_waiter: u64,
+}
+
+#[derive(Clone)]
+struct Request {
+    owner: Batch,
+    tree: String,
+    span: Span,
+    mode: LockMode,
+}
+
+struct Waiter {
+    request: Request,
+    deadline: Option<Instant>,
+    wake: oneshot::Sender<Wake>,
+}
+
+enum Wake {
+    /// A lock the waiter conflicted with was released
+    Retry,
+    TimedOut,
+}
+
+/// Removes a waiter whose wait is abandoned.
+struct WaitGuard<'table> {
+    state: &'table Mutex<State>,
+    id: u64,
+}
+
+impl LockTable {
+    /// If `timeout` is `None` then waits only end
+    /// when the lock is released or a deadlock is found.
+    pub fn new(timeout: Option<Duration>) -> LockTable {
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let timer = timeout.map(|_| {
+            let (tx, rx) = mpsc::channel();
+            let thread_state = state.clone();
+            thread::spawn(move || run_timer(thread_state, rx));
+            Mutex::new(tx)
+        });
+
+        LockTable {
+            state,
+            timeout,
+            timer,
+        }
+    }
+
+    /// Take a lock for `owner`, waiting for conflicting locks
+    /// held by other owners to be released.
+    pub async fn lock(&self, owner: Batch, tree: &str, span: Span, mode: LockMode) -> Result<()> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let request = Request {
+            owner,
+            tree: tree.to_string(),
+            span,
+            mode,
+        };
+
+        loop {
+            let (rx, id) = {
+                let mut state = self.state.lock().expect("lock");
+
+                let blockers = state.blockers(&request);
+                if blockers.is_empty() {
+                    state.held.push(request);
+                    return Ok(());
+                }
+
+                if state.waits_for(blockers, owner) {
+                    return Err(anyhow!(LockError::Deadlock));
+                }
+
+                if matches!(deadline, Some(deadline) if deadline <= Instant::now()) {
+                    return Err(anyhow!(LockError::Timeout));
+                }
+
+                let (tx, rx) = oneshot::channel();
+                let id = state.next_waiter;
+                state.next_waiter += 1;
+                state.waiting.insert(id, Waiter {
+                    request: request.clone(),
+                    deadline,
+                    wake: tx,
+                });
+
+                (rx, id)
+            };
+
+            let _guard = WaitGuard {
+                state: &self.state,
+                id,
+            };
+
+            if let Some(timer) = &self.timer {
+                let _ = timer.lock().expect("lock").send(());
+            }
+
+            match rx.await {
+                Ok(Wake::Retry) | Err(oneshot::Canceled) => { },
+                Ok(Wake::TimedOut) => {
+                    return Err(anyhow!(LockError::Timeout));
+                },
+            }
+        }
+    }
+
+    /// Release every lock held by `owner`.
+    pub fn release(&self, owner: Batch) {
+        let mut state = self.state.lock().expect("lock");
+
+        let (released, held) = state.held.drain(..).partition(|held| held.owner == owner);
+        state.held = held;
+
+        // Wake the waiters that may now be able to lock
+        let woken: Vec<u64> = state.waiting.iter().filter(|(_, waiter)| {
+            released.iter().any(|held| conflicts(held, &waiter.request))
+        }).map(|(id, _)| *id).collect();
+
+        for id in woken {
+            let waiter = state.waiting.remove(&id).expect("waiter");
+            let _ = waiter.wake.send(Wake::Retry);
+        }
+    }
+}
+
+impl State {
+    /// The owners of held locks that conflict with `request`.
+    fn blockers(&self, request: &Request) -> BTreeSet<Batch> {
+        self.held.iter()
+            .filter(|held| held.owner != request.owner && conflicts(held, request))
+            .map(|held| held.owner)
+            .collect()
+    }
+
+    /// Whether any of `owners` is waiting, directly or indirectly, for `owner`.
+    fn waits_for(&self, owners: BTreeSet<Batch>, owner: Batch) -> bool {
+        let mut visited = BTreeSet::new();
+        let mut stack: Vec<Batch> = owners.into_iter().collect();
+
+        while let Some(next) = stack.pop() {
+            if next == owner {
+                return true;
+            }
+            if !visited.insert(next) {
+                continue;
+            }
+            for waiter in self.waiting.values() {
+                if waiter.request.owner == next {
+                    stack.extend(self.blockers(&waiter.request));
+                }
+            }
+        }
+
+        false
+    }
+}
+
+fn conflicts(a: &Request, b: &Request) -> bool {
+    a.tree == b.tree
+        && (a.mode == LockMode::Exclusive || b.mode == LockMode::Exclusive)
+        && starts_before_end(&a.span.start, &b.span.end)
+        && starts_before_end(&b.span.start, &a.span.end)
+}
+
+fn starts_before_end(start: &Key, end: &Bound<Key>) -> bool {
+    match end {
+        Bound::Included(end) => start <= end,
+        Bound::Excluded(end) => start < end,
+        Bound::Unbounded => true,
+    }
+}
+
+/// Time out waiters until the lock table is dropped.
+fn run_timer(state: Arc<Mutex<State>>, rx: mpsc::Receiver<()>) {
+    loop {
+        let next_deadline = {
+            let state = state.lock().expect("lock");
+            state.waiting.values().filter_map(|waiter| waiter.deadline).min()
+        };
+
+        let woken = match next_deadline {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                rx.recv_timeout(timeout).map_err(|e| e == mpsc::RecvTimeoutError::Disconnected)
+            },
+            None => rx.recv().map_err(|_| true),
+        };
+        if let Err(true) = woken {
+            break;
+        }
+
+        let mut state = state.lock().expect("lock");
+        let now = Instant::now();
+        let expired: Vec<u64> = state.waiting.iter()
+            .filter(|(_, waiter)| matches!(waiter.deadline, Some(deadline) if deadline <= now))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let waiter = state.waiting.remove(&id).expect("waiter");
+            let _ = waiter.wake.send(Wake::TimedOut);
+        }
+    }
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().expect("lock");
+        state.waiting.remove(&self.id);
+    }
+}
+
+impl Span {
+    pub fn point(key: Key) -> Span {
+        Span {
+            start: key.clone(),
+            end: Bound::Included(key),
+        }
+    }
+
+    /// The keys from `start` up to but not including `end`.
+    pub fn range(start: Key, end: Key) -> Span {
+        Span {
+            start,
+            end: Bound::Excluded(end),
+        }
+    }
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Timeout => write!(f, "timed out waiting for lock"),
+            LockError::Deadlock => write!(f, "waiting for lock would deadlock"),
+        }
+    }
+}
+
+impl std::error::Error for LockError { }
+
+impl fmt::Debug for LockTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LockTable")
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
@@ -22,11 +22,23 @@ where Cmd: Serialize + for <'de> Deserialize<'de>
     }
 
     pub fn replay(&self) -> impl Stream<Item = Result<(Cmd, Address)>> + Unpin {
-        let addr = Address(0);
+        let addr = Address { segment: 0, offset: 0 };
         let state = Some((self.log_file.clone(), addr));
         Box::pin(stream::unfold(state, |state| async {
             match state {
                 Some((log_file, addr)) => {
+                    // An empty log has nothing to read at the first address
+                    if addr == (Address { segment: 0, offset: 0 }) {
+                        match log_file.is_empty().await {
+                            Err(e) => {
+                                return Some((Err(e), None));
+                            },
+                            Ok(true) => {
+                                return None;
+                            },
+                            Ok(false) => { },
+                        }
+                    }
                     let cmd = log_file.read_at(addr).await;
                     match cmd {
                         Err(e) => {
@@ -48,6 +60,36 @@ where Cmd: Serialize + for <'de> Deserialize<'de>
         }))
     }
 
+    /// Replay the commands after the one at an address.
+    pub fn replay_after(&self, address: Address) -> impl Stream<Item = Result<(Cmd, Address)>> + Unpin {
+        let state = Some((self.log_file.clone(), address, true));
+        Box::pin(stream::unfold(state, |state| async {
+            let (log_file, mut addr, skip) = state?;
+            if skip {
+                match log_file.read_at(addr).await {
+                    Err(e) => {
+                        return Some((Err(e), None));
+                    },
+                    Ok((_, Some(next_addr))) => {
+                        addr = next_addr;
+                    },
+                    Ok((_, None)) => {
+                        return None;
+                    },
+                }
+            }
+            match log_file.read_at(addr).await {
+                Err(e) => {
+                    Some((Err(e), None))
+                },
+                Ok((cmd, next_addr)) => {
+                    let next_state = next_addr.map(|next_addr| (log_file, next_addr, false));
+                    Some((Ok((cmd, addr)), next_state))
+                },
+            }
+        }))
+    }
+
     pub async fn is_empty(&self) -> Result<bool> {
         Ok(self.log_file.is_empty().await?)
     }
@@ -64,4 +106,13 @@ where Cmd: Serialize + for <'de> Deserialize<'de>
     pub async fn sync(&self) -> Result<()> {
         Ok(self.log_file.sync().await?)
     }
+
+    pub async fn recover(&self, truncate: bool) -> Result<()> {
+        self.log_file.recover(truncate).await
+    }
+
+    /// Discard the command at an address and everything after it.
+    pub async fn truncate(&self, address: Address) -> Result<()> {
+        self.log_file.truncate(address).await
+    }
 }
@@ -9,7 +9,9 @@ pub struct LogFile<Cmd> where Cmd: Serialize + for <'de> Deserialize<'de> {
     pub is_empty: Box<dyn Fn() -> BoxFuture<'static, Result<bool>> + Send + Sync>,
     pub append: Box<dyn Fn(Cmd) -> BoxFuture<'static, Result<Address>> + Send + Sync>,
     pub read_at: Box<dyn Fn(Address) -> BoxFuture<'static, Result<(Cmd, Option<Address>)>> + Send + Sync>,
-    pub sync: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>
+    pub sync: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>,
+    pub recover: Box<dyn Fn(bool) -> BoxFuture<'static, Result<()>> + Send + Sync>,
+    pub truncate: Box<dyn Fn(Address) -> BoxFuture<'static, Result<()>> + Send + Sync>,
 }
 
 impl<Cmd> LogFile<Cmd>
@@ -30,5 +32,16 @@ where Cmd: Serialize + for <'de> Deserialize<'de>
     pub async fn sync(&self) -> Result<()> {
         (self.sync)().await
     }
+
+    /// Check for a torn write at the end of the log,
+    /// and if `truncate` then discard it.
+    pub async fn recover(&self, truncate: bool) -> Result<()> {
+        (self.recover)(truncate).await
+    }
+
+    /// Discard the command at an address and everything after it.
+    pub async fn truncate(&self, addr: Address) -> Result<()> {
+        (self.truncate)(addr).await
+    }
 }
 
@@ -0,0 +1,78 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use crate::log::Log;
+use crate::types::Batch;
+use futures::StreamExt;
+use anyhow::{Result, bail};
+
+pub struct Manifest {
+    log: Log<ManifestCommand>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+#[serde(tag = "type")]
+pub enum ManifestCommand {
+    /// A tree that batches numbered from `first_batch` on may write to.
+    CreateTree {
+        tree: String,
+        first_batch: Batch,
+    },
+    DropTree {
+        tree: String,
+    },
+}
+
+impl Manifest {
+    pub fn new(log: Log<ManifestCommand>) -> Manifest {
+        Manifest { log }
+    }
+
+    pub async fn recover(&self, truncate: bool) -> Result<()> {
+        self.log.recover(truncate).await
+    }
+
+    /// The trees that exist, and the first batch of each.
+    pub async fn load(&self) -> Result<BTreeMap<String, Batch>> {
+        let mut trees = BTreeMap::new();
+        let mut replay = self.log.replay();
+
+        while let Some(cmd) = replay.next().await {
+            let (cmd, _) = cmd?;
+            match cmd {
+                ManifestCommand::CreateTree { tree, first_batch } => {
+                    if trees.contains_key(&tree) {
+                        bail!("tree {} created twice in manifest", tree);
+                    }
+                    trees.insert(tree, first_batch);
+                },
+                ManifestCommand::DropTree { tree } => {
+                    if trees.remove(&tree).is_none() {
+                        bail!("tree {} dropped before creation in manifest", tree);
+                    }
+                },
+            }
+        }
+
+        Ok(trees)
+    }
+
+    /// Durably record a new tree.
+    pub async fn create_tree(&self, tree: &str, first_batch: Batch) -> Result<()> {
+        self.log.append(ManifestCommand::CreateTree {
+            tree: tree.to_string(),
+            first_batch,
+        }).await?;
+
+        self.log.sync().await
+    }
+
+    /// Durably record that a tree no longer exists.
+    pub async fn drop_tree(&self, tree: &str) -> Result<()> {
+        self.log.append(ManifestCommand::DropTree {
+            tree: tree.to_string(),
+        }).await?;
+
+        self.log.sync().await
+    }
+}
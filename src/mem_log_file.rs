@@ -1,8 +1,17 @@
+//! An in-memory log.
+//!
+//! Logs opened on a [`MemDisk`] outlive the database that opened them,
+//! and keep track of how much of them has been synced,
+//! so a crash can be simulated by keeping only the synced commands.
+
 use std::convert::TryFrom;
 use crate::types::Address;
 use anyhow::{Result, anyhow};
+use std::fmt;
 use std::future::Future;
-use std::sync::{Arc, RwLock};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 use crate::log_file::LogFile;
 use crate::fs_thread::FsThread;
 use serde::{Serialize, Deserialize};
@@ -11,14 +20,100 @@ use futures::future::BoxFuture;
 use std::io::{Seek, SeekFrom, BufReader};
 use crate::frame;
 
+/// A set of named in-memory logs, shared like a directory on disk.
+#[derive(Clone, Default)]
+pub struct MemDisk {
+    logs: Arc<Mutex<BTreeMap<String, Arc<State>>>>,
+    /// The number of bytes appended to all logs
+    appended: Arc<AtomicU64>,
+}
+
+impl MemDisk {
+    pub fn new() -> MemDisk {
+        MemDisk::default()
+    }
+
+    /// A copy of the disk holding only what has been synced,
+    /// as it would be found after a power loss.
+    pub fn durable_snapshot(&self) -> MemDisk {
+        let logs = self.logs.lock().expect("lock");
+        let appended = Arc::new(AtomicU64::new(0));
+        let logs = logs.iter().map(|(name, state)| {
+            (name.clone(), Arc::new(state.durable_copy(&appended)))
+        }).collect();
+        MemDisk {
+            logs: Arc::new(Mutex::new(logs)),
+            appended,
+        }
+    }
+
+    /// Discard everything that has not been synced.
+    ///
+    /// Logs opened before the crash are detached from the disk,
+    /// so databases using them can't change it any further.
+    pub fn crash(&self) {
+        let snapshot = self.durable_snapshot();
+        let snapshot = std::mem::take(&mut *snapshot.logs.lock().expect("lock"));
+        *self.logs.lock().expect("lock") = snapshot;
+    }
+
+    /// The names of the logs holding any commands.
+    pub fn names(&self) -> Vec<String> {
+        let logs = self.logs.lock().expect("lock");
+        logs.iter()
+            .filter(|(_, state)| !state.buffers.read().expect("lock").is_empty())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.logs.lock().expect("lock").remove(name);
+    }
+
+    /// The count of bytes appended to logs on this disk so far.
+    pub(crate) fn appended_bytes(&self) -> Arc<AtomicU64> {
+        self.appended.clone()
+    }
+
+    fn state(&self, name: &str) -> Arc<State> {
+        let mut logs = self.logs.lock().expect("lock");
+        logs.entry(name.to_string()).or_insert_with(|| {
+            Arc::new(State {
+                appended: self.appended.clone(),
+                .. State::default()
+            })
+        }).clone()
+    }
+}
+
+impl fmt::Debug for MemDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemDisk").finish()
+    }
+}
+
+/// Create a log that belongs to no disk.
 pub fn create<Cmd>() -> LogFile<Cmd>
 where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
-    let buffers = RwLock::new(vec![]);
-    let state1 = Arc::new(State { buffers });
+    from_state(Arc::new(State::default()))
+}
+
+/// Open a log on a disk, creating it if it doesn't exist.
+pub fn open<Cmd>(disk: &MemDisk, name: &str) -> LogFile<Cmd>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    from_state(disk.state(name))
+}
+
+fn from_state<Cmd>(state1: Arc<State>) -> LogFile<Cmd>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
     let state2 = state1.clone();
     let state3 = state1.clone();
     let state4 = state1.clone();
+    let state5 = state1.clone();
+    let state6 = state1.clone();
 
     let is_empty_impl: Box<dyn Fn() -> BoxFuture<'static, Result<bool>> + Send + Sync> = {
         Box::new(move || {
@@ -41,21 +136,50 @@ where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
             Box::pin(sync(state4.clone()))
         })
     };
+    let recover_impl: Box<dyn Fn(bool) -> BoxFuture<'static, Result<()>> + Send + Sync> = {
+        Box::new(move |truncate| {
+            Box::pin(recover(state5.clone(), truncate))
+        })
+    };
+    let truncate_impl: Box<dyn Fn(Address) -> BoxFuture<'static, Result<()>> + Send + Sync> = {
+        Box::new(move |addr| {
+            Box::pin(truncate(state6.clone(), addr))
+        })
+    };
 
     LogFile {
         is_empty: is_empty_impl,
         append: append_impl,
         read_at: read_at_impl,
         sync: sync_impl,
+        recover: recover_impl,
+        truncate: truncate_impl,
     }
 }
 
+#[derive(Default)]
 struct State {
     buffers: RwLock<Vec<Buffer>>,
+    /// The number of buffers synced
+    durable: Mutex<usize>,
+    /// Shared by the logs on a disk
+    appended: Arc<AtomicU64>,
 }
 
 type Buffer = Vec<u8>;
 
+impl State {
+    fn durable_copy(&self, appended: &Arc<AtomicU64>) -> State {
+        let buffers = self.buffers.read().expect("lock");
+        let durable = *self.durable.lock().expect("lock");
+        State {
+            buffers: RwLock::new(buffers[..durable].to_vec()),
+            durable: Mutex::new(durable),
+            appended: appended.clone(),
+        }
+    }
+}
+
 async fn is_empty(state: Arc<State>) -> Result<bool> {
     let buffers = state.buffers.read().expect("lock");
     Ok(buffers.is_empty())
@@ -65,17 +189,18 @@ async fn append<Cmd>(state: Arc<State>, cmd: Cmd) -> Result<Address>
 where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
     let bin = serde_cbor::to_vec(&cmd)?;
+    state.appended.fetch_add(bin.len() as u64, Ordering::SeqCst);
     let mut buffers = state.buffers.write().expect("lock");
     buffers.push(bin);
     let addr = u64::try_from(buffers.len()).expect("u64");
     let addr = addr - 1;
-    Ok(Address(addr))
+    Ok(Address { segment: 0, offset: addr })
 }
 
 async fn read_at<Cmd>(state: Arc<State>, addr: Address) -> Result<(Cmd, Option<Address>)>
 where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
-    let addr = usize::try_from(addr.0).expect("usize");
+    let addr = usize::try_from(addr.offset).expect("usize");
     let buffers = state.buffers.read().expect("lock");
     let bin = buffers.get(addr).ok_or_else(|| {
         anyhow!("no command at address {}", addr)
@@ -84,10 +209,26 @@ where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
     let next = addr.checked_add(1).expect("overflow");
     let next = buffers.get(next).map(|_| next);
     let next = next.map(|n| u64::try_from(n).expect("u64"));
-    let next = next.map(|n| Address(n));
+    let next = next.map(|n| Address { segment: 0, offset: n });
     Ok((cmd, next))
 }
 
 async fn sync(state: Arc<State>) -> Result<()> {
+    let buffers = state.buffers.read().expect("lock");
+    *state.durable.lock().expect("lock") = buffers.len();
+    Ok(())
+}
+
+async fn recover(state: Arc<State>, truncate: bool) -> Result<()> {
+    // Appends are atomic
     Ok(( /* nop */ ))
 }
+
+async fn truncate(state: Arc<State>, addr: Address) -> Result<()> {
+    let addr = usize::try_from(addr.offset).expect("usize");
+    let mut buffers = state.buffers.write().expect("lock");
+    buffers.truncate(addr);
+    let mut durable = state.durable.lock().expect("lock");
+    *durable = (*durable).min(addr);
+    Ok(())
+}
@@ -0,0 +1,42 @@
+//! User-supplied functions that fold merge operands into values.
+//!
+//! Merges are stored as operands and folded when the key is read,
+//! so a merge needs no read of the key's value when it is written.
+
+use std::fmt;
+use std::sync::Arc;
+use anyhow::{Result, bail};
+use crate::types::{Key, Value};
+
+type MergeFn = dyn Fn(&[u8], Option<&[u8]>, &[&[u8]]) -> Vec<u8> + Send + Sync;
+
+#[derive(Clone)]
+pub struct MergeOperator(Arc<MergeFn>);
+
+impl MergeOperator {
+    /// `f` is called with a key, its value before the merges if it has one,
+    /// and the operands merged into it since, oldest first.
+    pub fn new(f: impl Fn(&[u8], Option<&[u8]>, &[&[u8]]) -> Vec<u8> + Send + Sync + 'static) -> MergeOperator {
+        MergeOperator(Arc::new(f))
+    }
+
+    pub fn merge(&self, key: &Key, existing: Option<&Value>, operands: &[Value]) -> Value {
+        let existing = existing.map(|value| &value.0[..]);
+        let operands: Vec<&[u8]> = operands.iter().map(|operand| &operand.0[..]).collect();
+        Value((self.0)(&key.0, existing, &operands))
+    }
+}
+
+impl fmt::Debug for MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MergeOperator").finish()
+    }
+}
+
+/// Merge with `operator`, failing if the tree has none.
+pub fn merge(operator: Option<&MergeOperator>, key: &Key, existing: Option<&Value>, operands: &[Value]) -> Result<Value> {
+    match operator {
+        Some(operator) => Ok(operator.merge(key, existing, operands)),
+        None => bail!("merged key in tree with no merge operator"),
+    }
+}
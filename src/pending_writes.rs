@@ -0,0 +1,274 @@
+//! A batch's uncommitted writes to a tree,
+//! read on top of a view of the tree.
+//!
+//! The writes are taken from the batch when the reader is created,
+//! with rolled back save points undone,
+//! and shadow the view wherever they write or delete.
+//! Merges are folded into the value they find beneath them,
+//! which may be in the view.
+
+use std::collections::BTreeMap;
+use std::ops::{Bound, Range};
+use std::sync::Arc;
+use anyhow::Result;
+use crate::batch_player::IndexOp;
+use crate::compacting_tree;
+use crate::index::Lookup;
+use crate::merge;
+use crate::types::{Address, Key, Value};
+
+pub struct PendingWrites {
+    /// The state of each key written, deleted or merged,
+    /// after any range deletes covering it.
+    /// Never `Lookup::Missing`.
+    points: BTreeMap<Key, Lookup>,
+    range_deletes: Vec<Range<Key>>,
+    /// Where the writes' values are read from
+    writer: Option<Arc<compacting_tree::BatchWriter>>,
+}
+
+pub struct Cursor {
+    view: compacting_tree::View,
+    view_cursor: compacting_tree::Cursor,
+    pending: PendingWrites,
+    /// The current key, and whether it is a pending write
+    current: Option<(Key, bool)>,
+}
+
+enum Direction {
+    Forward,
+    Backward,
+}
+
+impl PendingWrites {
+    /// No writes, for reading a view as it is.
+    pub fn none() -> PendingWrites {
+        PendingWrites {
+            points: BTreeMap::new(),
+            range_deletes: vec![],
+            writer: None,
+        }
+    }
+
+    pub fn new(writer: Arc<compacting_tree::BatchWriter>) -> PendingWrites {
+        let mut points = BTreeMap::new();
+        let mut range_deletes = vec![];
+
+        for op in writer.pending() {
+            match op {
+                IndexOp::Write { key, address } => {
+                    points.insert(key, Lookup::Written(address));
+                },
+                IndexOp::Delete { key, .. } => {
+                    points.insert(key, Lookup::Deleted);
+                },
+                IndexOp::Merge { key, address } => {
+                    let lookup = match points.remove(&key) {
+                        Some(Lookup::Merged { mut operands, base }) => {
+                            operands.push(address);
+                            Lookup::Merged { operands, base }
+                        },
+                        Some(base) => {
+                            Lookup::Merged { operands: vec![address], base: Box::new(base) }
+                        },
+                        None => {
+                            let base = range_lookup(&range_deletes, &key);
+                            Lookup::Merged { operands: vec![address], base: Box::new(base) }
+                        },
+                    };
+                    points.insert(key, lookup);
+                },
+                IndexOp::DeleteRange { start_key, end_key, .. } => {
+                    if start_key < end_key {
+                        let deleted: Vec<Key> = points.range(start_key.clone()..end_key.clone())
+                            .map(|(key, _)| key.clone())
+                            .collect();
+                        for key in deleted {
+                            points.remove(&key);
+                        }
+                        range_deletes.push(start_key..end_key);
+                    }
+                },
+            }
+        }
+
+        PendingWrites {
+            points,
+            range_deletes,
+            writer: Some(writer),
+        }
+    }
+
+    /// Whether the batch wrote, deleted or merged the key.
+    pub fn lookup(&self, key: &Key) -> Lookup {
+        match self.points.get(key) {
+            Some(lookup) => lookup.clone(),
+            None => range_lookup(&self.range_deletes, key),
+        }
+    }
+
+    /// Read a key from `view` as if the writes were committed.
+    pub async fn read(&self, view: &compacting_tree::View, key: &Key) -> Result<Option<Value>> {
+        match self.lookup(key) {
+            Lookup::Written(address) => Ok(Some(self.read_value(key, address).await?)),
+            Lookup::Deleted => Ok(None),
+            Lookup::Missing => view.read(key).await,
+            Lookup::Merged { operands, base } => {
+                let mut values = Vec::with_capacity(operands.len());
+                for address in operands {
+                    values.push(self.read_value(key, address).await?);
+                }
+                let existing = match *base {
+                    Lookup::Written(address) => Some(self.read_value(key, address).await?),
+                    Lookup::Deleted => None,
+                    Lookup::Missing => return view.read_merged(key, values).await,
+                    Lookup::Merged { .. } => panic!("merge into merge"),
+                };
+                Ok(Some(merge::merge(view.merge_operator(), key, existing.as_ref(), &values)?))
+            },
+        }
+    }
+
+    async fn read_value(&self, key: &Key, address: Address) -> Result<Value> {
+        let writer = self.writer.as_ref().expect("writer");
+        writer.read_value(key, address).await
+    }
+
+    /// The nearest write or merge in `direction` from `from`.
+    fn nearest(&self, from: Bound<Key>, direction: &Direction) -> Option<Key> {
+        let mut written = |(key, lookup): (&Key, &Lookup)| {
+            match lookup {
+                Lookup::Written(_) | Lookup::Merged { .. } => Some(key.clone()),
+                Lookup::Deleted | Lookup::Missing => None,
+            }
+        };
+        match direction {
+            Direction::Forward => {
+                self.points.range((from, Bound::Unbounded)).find_map(&mut written)
+            },
+            Direction::Backward => {
+                self.points.range((Bound::Unbounded, from)).rev().find_map(&mut written)
+            },
+        }
+    }
+}
+
+fn range_lookup(range_deletes: &[Range<Key>], key: &Key) -> Lookup {
+    if range_deletes.iter().any(|range| range.contains(key)) {
+        Lookup::Deleted
+    } else {
+        Lookup::Missing
+    }
+}
+
+impl Cursor {
+    pub fn new(view: compacting_tree::View, pending: PendingWrites) -> Cursor {
+        Cursor {
+            view_cursor: view.cursor(),
+            view,
+            pending,
+            current: None,
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn key(&self) -> Key {
+        let (key, _) = self.current.as_ref().expect("invalid cursor");
+        key.clone()
+    }
+
+    pub async fn value(&mut self) -> Result<Value> {
+        let (key, pending) = self.current.as_ref().expect("invalid cursor");
+        if *pending {
+            let value = self.pending.read(&self.view, key).await?;
+            Ok(value.expect("value"))
+        } else {
+            self.view_cursor.value().await
+        }
+    }
+
+    pub fn next(&mut self) {
+        let current_key = self.key();
+        self.view_cursor.seek_key(current_key.clone());
+        if self.view_cursor.valid() && self.view_cursor.key() == current_key {
+            self.view_cursor.next();
+        }
+        self.settle(Bound::Excluded(current_key), Direction::Forward);
+    }
+
+    pub fn prev(&mut self) {
+        let current_key = self.key();
+        self.view_cursor.seek_key_rev(current_key.clone());
+        if self.view_cursor.valid() && self.view_cursor.key() == current_key {
+            self.view_cursor.prev();
+        }
+        self.settle(Bound::Excluded(current_key), Direction::Backward);
+    }
+
+    pub fn seek_first(&mut self) {
+        self.view_cursor.seek_first();
+        self.settle(Bound::Unbounded, Direction::Forward);
+    }
+
+    pub fn seek_last(&mut self) {
+        self.view_cursor.seek_last();
+        self.settle(Bound::Unbounded, Direction::Backward);
+    }
+
+    pub fn seek_key(&mut self, key: Key) {
+        self.view_cursor.seek_key(key.clone());
+        self.settle(Bound::Included(key), Direction::Forward);
+    }
+
+    pub fn seek_key_rev(&mut self, key: Key) {
+        self.view_cursor.seek_key_rev(key.clone());
+        self.settle(Bound::Included(key), Direction::Backward);
+    }
+
+    /// Point at the nearest key in `direction` from `from`,
+    /// with the view cursor already there,
+    /// that is either a pending write,
+    /// or in the view and not shadowed by the pending writes.
+    fn settle(&mut self, from: Bound<Key>, direction: Direction) {
+        // Skip the view's keys that the batch wrote, deleted or merged.
+        // Those it wrote or merged are found among the pending writes.
+        while self.view_cursor.valid() {
+            match self.pending.lookup(&self.view_cursor.key()) {
+                Lookup::Missing => break,
+                Lookup::Written(_) | Lookup::Deleted | Lookup::Merged { .. } => {
+                    match direction {
+                        Direction::Forward => self.view_cursor.next(),
+                        Direction::Backward => self.view_cursor.prev(),
+                    }
+                },
+            }
+        }
+
+        let view_key = if self.view_cursor.valid() {
+            Some(self.view_cursor.key())
+        } else {
+            None
+        };
+        let pending = self.pending.nearest(from, &direction);
+
+        self.current = match (view_key, pending) {
+            (None, None) => None,
+            (Some(view_key), None) => Some((view_key, false)),
+            (None, Some(key)) => Some((key, true)),
+            (Some(view_key), Some(key)) => {
+                let view_nearer = match direction {
+                    Direction::Forward => view_key < key,
+                    Direction::Backward => view_key > key,
+                };
+                if view_nearer {
+                    Some((view_key, false))
+                } else {
+                    Some((key, true))
+                }
+            },
+        };
+    }
+}
@@ -1,8 +1,27 @@
 use crate::imp;
 
 pub use anyhow::{self, Result};
+use std::path::Path;
+use futures::{Sink, Stream};
 
+pub use crate::imp::Commit;
 pub type DbConfig = imp::DbConfig;
+pub type LogFormat = imp::LogFormat;
+pub type Durability = imp::Durability;
+pub type MemDisk = imp::MemDisk;
+pub type Conflict = imp::Conflict;
+pub type Precondition = imp::Precondition;
+pub type MergeOperator = imp::MergeOperator;
+pub type ChangeBatch = imp::ChangeBatch;
+pub type Change = imp::Change;
+pub type ChangeStream = imp::ChangeStream;
+pub type VerifyReport = imp::VerifyReport;
+pub type VerifyProblem = imp::VerifyProblem;
+
+pub async fn verify(config: &DbConfig) -> Result<VerifyReport> { imp::verify(config).await }
+
+pub type LockMode = imp::LockMode;
+pub type LockError = imp::LockError;
 
 #[derive(Clone, Debug)]
 pub struct Db(imp::Db);
@@ -15,11 +34,30 @@ pub struct ReadView(imp::ReadView);
 pub struct ReadTree<'view>(imp::ReadTree<'view>);
 pub struct Cursor(imp::Cursor);
 
+pub struct Transaction(imp::Transaction);
+pub struct TransactionTree<'tx>(imp::TransactionTree<'tx>);
+
 impl Db {
     pub async fn open(config: DbConfig) -> Result<Db> { imp::Db::open(config).await.map(Db) }
+    pub async fn open_with_faults(config: DbConfig, faults: crate::raw::faulty_log_file::Faults) -> Result<Db> { imp::Db::open_with_faults(config, Some(faults)).await.map(Db) }
     pub async fn write_batch(&self) -> Result<WriteBatch> { Ok(WriteBatch(self.0.write_batch().await?)) }
     pub fn read_view(&self) -> ReadView { ReadView(self.0.read_view()) }
+    pub fn read_view_at(&self, commit: Commit) -> Result<ReadView> { self.0.read_view_at(commit).map(ReadView) }
+    pub async fn subscribe(&self) -> Result<ChangeStream> { self.0.subscribe().await }
+    pub async fn subscribe_from(&self, commit: Commit) -> Result<ChangeStream> { self.0.subscribe_from(commit).await }
+    pub async fn backup_since<S>(&self, commit: Commit, sink: S) -> Result<Commit> where S: Sink<ChangeBatch> + Unpin, S::Error: std::error::Error + Send + Sync + 'static { self.0.backup_since(commit, sink).await }
+    pub async fn restore<S>(&self, batches: S) -> Result<Commit> where S: Stream<Item = ChangeBatch> + Unpin { self.0.restore(batches).await }
+    pub async fn transaction(&self) -> Result<Transaction> { Ok(Transaction(self.0.transaction().await?)) }
     pub async fn sync(&self) -> Result<()> { self.0.sync().await }
+    pub async fn checkpoint(&self, dir: &Path) -> Result<Commit> { self.0.checkpoint(dir).await }
+    pub async fn checkpoint_indexes(&self) -> Result<Commit> { self.0.checkpoint_indexes().await }
+    pub async fn simulate_crash(self) -> Result<()> { self.0.simulate_crash().await }
+    pub async fn compact(&self, tree: &str) -> Result<bool> { self.0.compact(tree).await }
+    pub fn collect_garbage(&self) -> usize { self.0.collect_garbage() }
+    pub fn index_entries(&self, tree: &str) -> Result<usize> { self.0.index_entries(tree) }
+    pub async fn create_tree(&self, tree: &str) -> Result<()> { self.0.create_tree(tree).await }
+    pub async fn drop_tree(&self, tree: &str) -> Result<()> { self.0.drop_tree(tree).await }
+    pub fn tree_names(&self) -> Vec<String> { self.0.tree_names() }
 }
 
 impl WriteBatch {
@@ -27,24 +65,52 @@ impl WriteBatch {
     pub async fn push_save_point(&self) -> Result<()> { self.0.push_save_point().await }
     pub async fn pop_save_point(&self) -> Result<()> { self.0.pop_save_point().await }
     pub async fn rollback_save_point(&self) -> Result<()> { self.0.rollback_save_point().await }
-    pub async fn commit(&self) -> Result<()> { self.0.commit().await }
+    pub async fn commit(&self) -> Result<Commit> { self.0.commit().await }
     pub async fn abort(&self) { self.0.abort().await }
     pub async fn close(self) { self.0.close().await }
 }
 
 impl ReadView {
     pub fn tree<'view>(&'view self, tree: &str) -> ReadTree<'view> { ReadTree(self.0.tree(tree)) }
+    pub fn commit(&self) -> Commit { self.0.commit() }
 }
 
 impl<'batch> WriteTree<'batch> {
     pub async fn write(&self, key: &[u8], value: &[u8]) -> Result<()> { self.0.write(key, value).await }
     pub async fn delete(&self, key: &[u8]) -> Result<()> { self.0.delete(key).await }
     pub async fn delete_range(&self, start_key: &[u8], end_key: &[u8]) -> Result<()> { self.0.delete_range(start_key, end_key).await }
+    pub async fn write_if(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<()> { self.0.write_if(key, expected, value).await }
+    pub async fn delete_if(&self, key: &[u8], expected: &[u8]) -> Result<()> { self.0.delete_if(key, expected).await }
+    pub async fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> { self.0.merge(key, operand).await }
+    pub async fn read(&self, view: &ReadView, key: &[u8]) -> Result<Option<Vec<u8>>> { self.0.read(&view.0, key).await }
+    pub async fn cursor(&self, view: &ReadView) -> Result<Cursor> { self.0.cursor(&view.0).await.map(Cursor) }
+    pub async fn lock(&self, key: &[u8], mode: LockMode) -> Result<()> { self.0.lock(key, mode).await }
+    pub async fn lock_range(&self, start_key: &[u8], end_key: &[u8], mode: LockMode) -> Result<()> { self.0.lock_range(start_key, end_key, mode).await }
 }
 
 impl<'view> ReadTree<'view> {
     pub async fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { self.0.read(key).await }
-    pub fn cursor(&self) -> Cursor { Cursor(self.0.cursor()) }
+    pub fn cursor(&self) -> Result<Cursor> { self.0.cursor().map(Cursor) }
+    pub async fn history(&self, key: &[u8]) -> Result<Vec<(Commit, Option<Vec<u8>>)>> { self.0.history(key).await }
+}
+
+impl Transaction {
+    pub fn tree<'tx>(&'tx self, tree: &str) -> TransactionTree<'tx> { TransactionTree(self.0.tree(tree)) }
+    pub async fn push_save_point(&self) -> Result<()> { self.0.push_save_point().await }
+    pub async fn pop_save_point(&self) -> Result<()> { self.0.pop_save_point().await }
+    pub async fn rollback_save_point(&self) -> Result<()> { self.0.rollback_save_point().await }
+    pub async fn commit(&self) -> Result<Commit> { self.0.commit().await }
+    pub async fn abort(&self) { self.0.abort().await }
+    pub async fn close(self) { self.0.close().await }
+}
+
+impl<'tx> TransactionTree<'tx> {
+    pub async fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { self.0.read(key).await }
+    pub async fn cursor(&self) -> Result<Cursor> { self.0.cursor().await.map(Cursor) }
+    pub async fn write(&self, key: &[u8], value: &[u8]) -> Result<()> { self.0.write(key, value).await }
+    pub async fn delete(&self, key: &[u8]) -> Result<()> { self.0.delete(key).await }
+    pub async fn delete_range(&self, start_key: &[u8], end_key: &[u8]) -> Result<()> { self.0.delete_range(start_key, end_key).await }
+    pub async fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> { self.0.merge(key, operand).await }
 }
 
 impl Cursor {
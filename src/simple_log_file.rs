@@ -1,23 +1,62 @@
+//! A simple on-disk log, split into numbered segment files.
+//!
+//! A log named `n` in directory `d` is stored as
+//! `d/n.0.toml`, `d/n.1.toml`, etc.,
+//! where the extension depends on the log format.
+//! Appends start a new segment once the last
+//! has grown to the segment size.
+
 use crate::types::Address;
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
 use std::future::Future;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::collections::BTreeSet;
 use crate::log_file::LogFile;
-use crate::fs_thread::FsThread;
+use crate::fs_thread::{FsThread, FsThreadContext};
 use serde::{Serialize, Deserialize};
-use std::path::PathBuf;
-use futures::future::BoxFuture;
-use std::io::{Seek, SeekFrom, BufReader};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use futures::future::{self, BoxFuture};
+use std::io::{self, Read, Seek, SeekFrom, BufReader};
 use crate::frame;
+use crate::binary_frame;
+
+/// The encoding of frames in a log file.
+#[derive(Copy, Clone, Debug)]
+#[derive(Eq, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable TOML frames, for debugging
+    Toml,
+    /// Compact, checksummed, CBOR frames
+    Binary,
+}
+
+impl LogFormat {
+    /// The extension of log files in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            LogFormat::Toml => "toml",
+            LogFormat::Binary => "bin",
+        }
+    }
+}
 
-pub fn create<Cmd>(path: PathBuf, fs_thread: Arc<FsThread>) -> LogFile<Cmd>
+pub fn create<Cmd>(dir: PathBuf,
+                   name: String,
+                   format: LogFormat,
+                   segment_size: u64,
+                   fs_thread: Arc<FsThread>) -> LogFile<Cmd>
 where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
-    let path = Arc::new(path);
-    let state1 = Arc::new(State { path, fs_thread });
+    let state1 = Arc::new(State {
+        dir, name, format, segment_size, fs_thread,
+        segments: Mutex::new(None),
+    });
     let state2 = state1.clone();
     let state3 = state1.clone();
     let state4 = state1.clone();
+    let state5 = state1.clone();
+    let state6 = state1.clone();
 
     let is_empty_impl: Box<dyn Fn() -> BoxFuture<'static, Result<bool>> + Send + Sync> = {
         Box::new(move || {
@@ -40,77 +79,475 @@ where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
             Box::pin(sync(state4.clone()))
         })
     };
+    let recover_impl: Box<dyn Fn(bool) -> BoxFuture<'static, Result<()>> + Send + Sync> = {
+        Box::new(move |truncate| {
+            Box::pin(recover::<Cmd>(state5.clone(), truncate))
+        })
+    };
+    let truncate_impl: Box<dyn Fn(Address) -> BoxFuture<'static, Result<()>> + Send + Sync> = {
+        Box::new(move |addr| {
+            Box::pin(truncate(state6.clone(), addr))
+        })
+    };
 
     LogFile {
         is_empty: is_empty_impl,
         append: append_impl,
         read_at: read_at_impl,
         sync: sync_impl,
+        recover: recover_impl,
+        truncate: truncate_impl,
     }
 }
 
+/// Open a log only to read it, with blocking I/O and no fs thread.
+///
+/// A log with no segments is empty,
+/// but one missing any segment before its last can't be opened.
+/// Anything but reading fails.
+pub fn open_read_only<Cmd>(dir: PathBuf,
+                           name: String,
+                           format: LogFormat) -> Result<LogFile<Cmd>>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    let numbers = segment_numbers(&dir, &name, format)?;
+    for (expected, number) in (0..).zip(&numbers) {
+        if *number != expected {
+            bail!("log {} in {} is missing segment {}", name, dir.display(), expected);
+        }
+    }
+    let is_empty = match numbers.as_slice() {
+        [] => true,
+        [only] => fs::metadata(segment_path(&dir, &name, format, *only))?.len() == 0,
+        _ => false,
+    };
+
+    let state1 = Arc::new(ReadOnlyState { dir, name, format, numbers });
+    let state2 = state1.clone();
+    let state3 = state1.clone();
+    let state4 = state1.clone();
+    let state5 = state1.clone();
+
+    Ok(LogFile {
+        is_empty: Box::new(move || Box::pin(future::ready(Ok(is_empty)))),
+        append: Box::new(move |_| read_only(&state1.name)),
+        read_at: Box::new(move |addr| {
+            let state = state2.clone();
+            Box::pin(async move {
+                let next_segment = state.numbers.iter().copied().find(|n| *n > addr.segment);
+                let path = segment_path(&state.dir, &state.name, state.format, addr.segment);
+                read_frame(&mut File::open(path)?, state.format, addr, next_segment)
+            })
+        }),
+        sync: Box::new(move || read_only(&state3.name)),
+        recover: Box::new(move |_| read_only(&state4.name)),
+        truncate: Box::new(move |_| read_only(&state5.name)),
+    })
+}
+
+fn read_only<T>(name: &str) -> BoxFuture<'static, Result<T>>
+where T: Send + 'static
+{
+    Box::pin(future::ready(Err(anyhow!("log {} is opened read-only", name))))
+}
+
+struct ReadOnlyState {
+    dir: PathBuf,
+    name: String,
+    format: LogFormat,
+    /// The numbers of the segment files, in order
+    numbers: Vec<u64>,
+}
+
 struct State {
-    path: Arc<PathBuf>,
-    fs_thread: Arc<FsThread>
+    dir: PathBuf,
+    name: String,
+    format: LogFormat,
+    segment_size: u64,
+    fs_thread: Arc<FsThread>,
+    /// Loaded from disk on first use
+    segments: Mutex<Option<Segments>>,
 }
 
-async fn is_empty(state: Arc<State>) -> Result<bool> {
-    let path = state.path.clone();
-    let future = state.fs_thread.run(move |ctx| -> Result<_> {
-        let mut file = ctx.open_read(&path)?;
-        let pos = file.seek(SeekFrom::End(0))?;
-        file.seek(SeekFrom::Start(0))?;
-        if pos == 0 {
-            Ok(true)
-        } else {
-            Ok(false)
+struct Segments {
+    /// The numbers of the segment files, in order
+    numbers: Vec<u64>,
+    /// The length of the last segment, including appends in flight
+    last_len: u64,
+    /// Segments appended to since they were last synced
+    unsynced: BTreeSet<u64>,
+    /// Whether segments were created since the directory was last synced
+    dir_unsynced: bool,
+}
+
+impl State {
+    fn path(&self, segment: u64) -> PathBuf {
+        segment_path(&self.dir, &self.name, self.format, segment)
+    }
+}
+
+/// The path of one segment of a log.
+pub fn segment_path(dir: &Path, name: &str, format: LogFormat, segment: u64) -> PathBuf {
+    dir.join(format!("{}.{}.{}", name, segment, format.extension()))
+}
+
+/// The names of the logs in a directory.
+///
+/// This does blocking I/O.
+pub fn log_names(dir: &Path, format: LogFormat) -> Result<BTreeSet<String>> {
+    Ok(list_segments(dir, format)?.into_iter()
+       .map(|(name, _)| name)
+       .collect())
+}
+
+/// The segment numbers of a log, in order.
+///
+/// This does blocking I/O.
+pub fn segment_numbers(dir: &Path, name: &str, format: LogFormat) -> Result<Vec<u64>> {
+    let mut numbers: Vec<_> = list_segments(dir, format)?.into_iter()
+        .filter(|(segment_name, _)| segment_name == name)
+        .map(|(_, number)| number)
+        .collect();
+    numbers.sort();
+    Ok(numbers)
+}
+
+/// Delete every segment of a log.
+pub fn remove(ctx: &mut FsThreadContext, dir: &Path, name: &str, format: LogFormat) -> Result<()> {
+    for segment in segment_numbers(dir, name, format)? {
+        let path = segment_path(dir, name, format, segment);
+        ctx.close(&path);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => { },
         }
+    }
+    Ok(())
+}
+
+fn list_segments(dir: &Path, format: LogFormat) -> Result<Vec<(String, u64)>> {
+    let ext = format!(".{}", format.extension());
+    let entries = match fs::read_dir(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        r => r?,
+    };
+
+    let mut segments = vec![];
+    for entry in entries {
+        let file_name = entry?.file_name();
+        let file_name = file_name.to_string_lossy();
+        let stem = match file_name.strip_suffix(&ext) {
+            Some(stem) => stem,
+            None => continue,
+        };
+        let (name, number) = match stem.rsplit_once('.') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        if let Ok(number) = number.parse() {
+            segments.push((name.to_string(), number));
+        }
+    }
+
+    Ok(segments)
+}
+
+async fn load_segments(state: &Arc<State>) -> Result<()> {
+    if state.segments.lock().expect("lock").is_some() {
+        return Ok(());
+    }
+
+    let dir = state.dir.clone();
+    let name = state.name.clone();
+    let format = state.format;
+    let future = state.fs_thread.run(move |_| -> Result<_> {
+        let numbers = segment_numbers(&dir, &name, format)?;
+        let last_len = match numbers.last() {
+            Some(last) => fs::metadata(segment_path(&dir, &name, format, *last))?.len(),
+            None => 0,
+        };
+        Ok(Segments {
+            numbers,
+            last_len,
+            unsynced: BTreeSet::new(),
+            dir_unsynced: false,
+        })
     });
-    Ok(future.await?)
+    let loaded = future.await?;
+
+    let mut segments = state.segments.lock().expect("lock");
+    if segments.is_none() {
+        *segments = Some(loaded);
+    }
+
+    Ok(())
+}
+
+async fn is_empty(state: Arc<State>) -> Result<bool> {
+    load_segments(&state).await?;
+    let segments = state.segments.lock().expect("lock");
+    let segments = segments.as_ref().expect("segments");
+    Ok(match segments.numbers.len() {
+        0 => true,
+        1 => segments.last_len == 0,
+        _ => false,
+    })
 }
 
 async fn append<Cmd>(state: Arc<State>, cmd: Cmd) -> Result<Address>
 where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
-    let path = state.path.clone();
-    let future = state.fs_thread.run(move |ctx| -> Result<_> {
-        let mut file = ctx.open_append(&path)?;
-        let pos = file.seek(SeekFrom::Current(0))?;
-        frame::write(file, &cmd)?;
-        let addr = Address(pos);
-        Ok(addr)
-    });
-    Ok(future.await?)
+    let mut bytes = vec![];
+    match state.format {
+        LogFormat::Toml => frame::write(&mut bytes, &cmd)?,
+        LogFormat::Binary => binary_frame::write(&mut bytes, &cmd)?,
+    }
+
+    load_segments(&state).await?;
+
+    // Queue the append while holding the lock,
+    // so that appends reach the fs thread in the order
+    // their lengths are counted.
+    let (segment, future) = {
+        let mut segments = state.segments.lock().expect("lock");
+        let segments = segments.as_mut().expect("segments");
+        let segment = match segments.numbers.last() {
+            Some(last) if segments.last_len < state.segment_size => *last,
+            Some(last) => {
+                let next = last.checked_add(1).expect("overflow");
+                segments.numbers.push(next);
+                segments.last_len = 0;
+                segments.dir_unsynced = true;
+                next
+            },
+            None => {
+                segments.numbers.push(0);
+                segments.last_len = 0;
+                segments.dir_unsynced = true;
+                0
+            },
+        };
+        segments.last_len += bytes.len() as u64;
+        segments.unsynced.insert(segment);
+        (segment, state.fs_thread.append(state.path(segment), bytes))
+    };
+
+    let offset = future.await?;
+    Ok(Address { segment, offset })
 }
 
 async fn read_at<Cmd>(state: Arc<State>, addr: Address) -> Result<(Cmd, Option<Address>)>
 where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
 {
-    let path = state.path.clone();
+    load_segments(&state).await?;
+    let next_segment = {
+        let segments = state.segments.lock().expect("lock");
+        let segments = segments.as_ref().expect("segments");
+        segments.numbers.iter().copied().find(|n| *n > addr.segment)
+    };
+
+    let path = state.path(addr.segment);
+    let format = state.format;
     let future = state.fs_thread.run(move |ctx| -> Result<_> {
-        let mut file = ctx.open_read(&path)?;
-        let mut file = BufReader::new(file);
-        file.seek(SeekFrom::Start(addr.0))?;
-        let cmd = frame::read(&mut file)?;
-        let pos = file.seek(SeekFrom::Current(0))?;
-        let eof = file.seek(SeekFrom::End(0))?;
-        file.seek(SeekFrom::Start(pos))?;
-        let next_addr = if pos != eof {
-            Some(Address(pos))
-        } else {
-            None
-        };
-        Ok((cmd, next_addr))
+        read_frame(ctx.open_read(&path)?, format, addr, next_segment)
     });
     Ok(future.await?)
 }
 
+/// Read the frame at an address of a segment file,
+/// and find the address after it.
+fn read_frame<Cmd>(file: &mut File,
+                   format: LogFormat,
+                   addr: Address,
+                   next_segment: Option<u64>) -> Result<(Cmd, Option<Address>)>
+where Cmd: for <'de> Deserialize<'de>
+{
+    let mut file = BufReader::new(file);
+    let eof = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(addr.offset))?;
+    let cmd = match format {
+        LogFormat::Toml => frame::read(&mut file)?,
+        LogFormat::Binary => binary_frame::read(&mut file, eof.saturating_sub(addr.offset))?,
+    };
+    let pos = file.stream_position()?;
+    let next_addr = if pos != eof {
+        Some(Address { segment: addr.segment, offset: pos })
+    } else {
+        next_segment.map(|segment| Address { segment, offset: 0 })
+    };
+    Ok((cmd, next_addr))
+}
+
 async fn sync(state: Arc<State>) -> Result<()> {
-    let path = state.path.clone();
+    load_segments(&state).await?;
+    let (unsynced, dir_unsynced) = {
+        let mut segments = state.segments.lock().expect("lock");
+        let segments = segments.as_mut().expect("segments");
+        (std::mem::take(&mut segments.unsynced),
+         std::mem::take(&mut segments.dir_unsynced))
+    };
+
+    let syncs = unsynced.iter().map(|segment| {
+        state.fs_thread.sync(state.path(*segment))
+    });
+    let mut r = future::try_join_all(syncs).await.map(|_| ());
+
+    // New segments aren't durable until their directory is synced
+    if r.is_ok() && dir_unsynced && cfg!(unix) {
+        let dir = state.dir.clone();
+        r = state.fs_thread.run(move |_| -> Result<_> {
+            File::open(&dir)?.sync_all()?;
+            Ok(())
+        }).await;
+    }
+
+    if r.is_err() {
+        // Try again next time
+        let mut segments = state.segments.lock().expect("lock");
+        let segments = segments.as_mut().expect("segments");
+        segments.unsynced.extend(unsynced);
+        segments.dir_unsynced |= dir_unsynced;
+    }
+
+    r
+}
+
+/// Find the end of the last complete frame.
+///
+/// A frame that can't be read is a torn write
+/// only if it is the last frame, cut short:
+/// it must end past the end of the last segment,
+/// according to a length that is checksummed, or in the TOML format,
+/// with no other frame starting after it.
+/// Otherwise the log is corrupt and can't be recovered
+/// without losing frames that may be committed.
+async fn recover<Cmd>(state: Arc<State>, truncate: bool) -> Result<()>
+where Cmd: Serialize + for <'de> Deserialize<'de> + Send + 'static
+{
+    let dir = state.dir.clone();
+    let name = state.name.clone();
+    let format = state.format;
     let future = state.fs_thread.run(move |ctx| -> Result<_> {
-        let file = ctx.open_append(&path)?;
-        file.sync_all()?;
+        let mut numbers = segment_numbers(&dir, &name, format)?;
+
+        // A crash just after rolling over can leave an empty segment,
+        // which holds nothing to lose.
+        while numbers.len() > 1 {
+            let last = *numbers.last().expect("last");
+            let path = segment_path(&dir, &name, format, last);
+            if fs::metadata(&path)?.len() != 0 {
+                break;
+            }
+            ctx.close(&path);
+            fs::remove_file(&path)?;
+            numbers.pop();
+        }
+
+        for (i, segment) in numbers.iter().enumerate() {
+            let path = segment_path(&dir, &name, format, *segment);
+            let torn = find_torn_write::<Cmd>(ctx, &path, format)?;
+            let (pos, eof) = match torn {
+                Some(torn) => torn,
+                None => continue,
+            };
+            if i + 1 != numbers.len() {
+                bail!("corrupt frame at offset {} of {}, before later segments", pos, path.display());
+            }
+
+            if !truncate {
+                bail!("torn write at offset {} of {}", pos, path.display());
+            }
+
+            log::warn!("discarding torn write of {} bytes at offset {} of {}",
+                       eof - pos, pos, path.display());
+            let file = ctx.open_read(&path)?;
+            file.set_len(pos)?;
+            file.sync_all()?;
+        }
+
         Ok(())
     });
-    Ok(future.await?)
+    future.await?;
+
+    // Reload after truncating
+    *state.segments.lock().expect("lock") = None;
+
+    Ok(())
+}
+
+/// Truncate the segment at the address and delete later segments.
+///
+/// A segment truncated to nothing is deleted too,
+/// unless it is the first.
+async fn truncate(state: Arc<State>, addr: Address) -> Result<()> {
+    let dir = state.dir.clone();
+    let name = state.name.clone();
+    let format = state.format;
+    let future = state.fs_thread.run(move |ctx| -> Result<_> {
+        for (i, segment) in segment_numbers(&dir, &name, format)?.into_iter().enumerate() {
+            let path = segment_path(&dir, &name, format, segment);
+            if segment == addr.segment && (addr.offset != 0 || i == 0) {
+                let file = ctx.open_read(&path)?;
+                file.set_len(addr.offset)?;
+                file.sync_all()?;
+            } else if segment >= addr.segment {
+                ctx.close(&path);
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    });
+    future.await?;
+
+    // Reload after truncating
+    *state.segments.lock().expect("lock") = None;
+
+    Ok(())
+}
+
+/// Returns the offset of the torn write and the end of the file.
+fn find_torn_write<Cmd>(ctx: &mut FsThreadContext, path: &Path, format: LogFormat) -> Result<Option<(u64, u64)>>
+where Cmd: for <'de> Deserialize<'de>
+{
+    let file = ctx.open_read(path)?;
+    let eof = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut reader = BufReader::new(file);
+    let mut pos = 0;
+    while pos != eof {
+        let frame: Result<Cmd> = match format {
+            LogFormat::Toml => frame::read(&mut reader),
+            LogFormat::Binary => binary_frame::read(&mut reader, eof - pos),
+        };
+        if let Err(e) = frame {
+            if is_io_error(&e) {
+                return Err(e);
+            }
+            let frame_end = reader.stream_position()?;
+            let torn = match format {
+                LogFormat::Toml => frame_end == eof && {
+                    let mut rest = vec![];
+                    reader.seek(SeekFrom::Start(pos))?;
+                    reader.read_to_end(&mut rest)?;
+                    !frame::has_later_frame(&rest)
+                },
+                LogFormat::Binary => e.is::<binary_frame::Truncated>(),
+            };
+            if !torn {
+                bail!("corrupt frame at offset {} of {}: {}", pos, path.display(), e);
+            }
+            return Ok(Some((pos, eof)));
+        }
+        pos = reader.stream_position()?;
+    }
+
+    Ok(None)
+}
+
+fn is_io_error(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<io::Error>() {
+        Some(e) => e.kind() != io::ErrorKind::UnexpectedEof,
+        None => false,
+    }
 }
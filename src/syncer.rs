@@ -0,0 +1,83 @@
+//! Syncs a database in the background,
+//! after an interval or after enough data has been appended to its logs.
+
+use log::error;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use anyhow::Result;
+
+#[derive(Debug)]
+pub struct Syncer {
+    tx: Mutex<mpsc::Sender<()>>,
+    state: Arc<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// The number of bytes appended to the database's logs
+    appended: Arc<AtomicU64>,
+    bytes: u64,
+    /// The appended bytes that the last sync covered
+    synced_bytes: AtomicU64,
+}
+
+impl Syncer {
+    /// Start the sync thread.
+    ///
+    /// `sync` returns `None` once the database is gone,
+    /// and the thread also exits when the `Syncer` is dropped.
+    pub fn start<F>(appended: Arc<AtomicU64>,
+                    interval: Duration,
+                    bytes: u64,
+                    mut sync: F) -> Syncer
+    where F: FnMut() -> Option<Result<()>> + Send + 'static
+    {
+        let (tx, rx) = mpsc::channel();
+        let state = Arc::new(State {
+            synced_bytes: AtomicU64::new(appended.load(Ordering::SeqCst)),
+            appended,
+            bytes,
+        });
+        let thread_state = state.clone();
+
+        thread::spawn(move || {
+            let state = thread_state;
+            // Wake on timeout or request, until the syncer is dropped
+            while let Ok(()) | Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                // Any other requests are covered by this sync
+                while rx.try_recv().is_ok() { }
+
+                // Nothing to sync if nothing was appended since the last sync
+                let appended = state.appended.load(Ordering::SeqCst);
+                if appended == state.synced_bytes.load(Ordering::SeqCst) {
+                    continue;
+                }
+                match sync() {
+                    Some(Ok(())) => {
+                        state.synced_bytes.store(appended, Ordering::SeqCst);
+                    },
+                    Some(Err(e)) => {
+                        error!("error syncing in background: {}", e);
+                    },
+                    None => break,
+                }
+            }
+        });
+
+        Syncer {
+            tx: Mutex::new(tx),
+            state,
+        }
+    }
+
+    /// Sync early if enough data has been appended since the last sync.
+    pub fn committed(&self) {
+        let appended = self.state.appended.load(Ordering::SeqCst);
+        let synced = self.state.synced_bytes.load(Ordering::SeqCst);
+        if appended.saturating_sub(synced) >= self.state.bytes {
+            let _ = self.tx.lock().expect("lock").send(());
+        }
+    }
+}
@@ -3,19 +3,22 @@ use std::collections::{BTreeMap, BTreeSet};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::convert::TryFrom;
-use crate::types::{Batch, BatchCommit, Commit, Key, Value, Address};
+use crate::types::{Batch, BatchCommit, Commit, Key, KeyRange, Value, Address};
 use crate::command::Command;
 use crate::log::Log;
-use crate::batch_player::{BatchPlayer, IndexOp};
-use crate::index::{self, Index};
+use crate::batch_player::{BatchPlayer, BatchSnapshot, IndexOp};
+use crate::index::{self, Index, Lookup};
 use anyhow::{Result, anyhow, bail};
 use futures::{Stream, StreamExt};
+use futures::lock::Mutex;
+use serde::{Serialize, Deserialize};
 
 pub struct Tree {
     initialized: AtomicBool,
     log: Arc<Log<Command>>,
     batch_player: Arc<BatchPlayer>,
     index: Arc<Index>,
+    appends: Arc<Mutex<Option<Address>>>,
 }
 
 pub struct BatchWriter {
@@ -23,12 +26,34 @@ pub struct BatchWriter {
     log: Arc<Log<Command>>,
     batch_player: Arc<BatchPlayer>,
     index: Arc<Index>,
+    /// The address of the last command appended,
+    /// locked while appending so that checkpoints
+    /// see every command along with its address.
+    appends: Arc<Mutex<Option<Address>>>,
 }
 
+/// A tree's state at an index checkpoint,
+/// from which its log can be replayed
+/// after the last command appended.
+///
+/// Fields holding tables come last so that this can be written as TOML,
+/// which also can't write empty arrays after them.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+pub struct Checkpoint {
+    last: Option<Address>,
+    index: index::Snapshot,
+    /// The batches that were open
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    batches: Vec<BatchSnapshot>,
+}
+
+/// A cursor over the keys with values in one tree.
+///
+/// Values may need merging with other trees',
+/// so are looked up through the tree.
 pub struct Cursor {
-    log: Arc<Log<Command>>,
     index_cursor: index::Cursor,
-    value: Option<Value>,
 }
 
 pub struct InitReplayer<'tree> {
@@ -40,31 +65,96 @@ pub struct InitReplayer<'tree> {
     max_batch_seen: Option<Batch>,
     max_batch_commit_seen: Option<BatchCommit>,
     waiting_to_commit: BTreeSet<(Batch, BatchCommit)>,
+    /// Commits before this are already in the index
+    skip_before: Commit,
+    last_address: Option<Address>,
+    appends: &'tree Mutex<Option<Address>>,
     init_success: bool,
 }
 
+type CommandStream = Pin<Box<dyn Stream<Item = Result<(Command, Address)>> + Send>>;
+
+/// Replays the ops of chosen commits from a tree's log,
+/// in the order they were made ready to commit.
+pub struct CommitReplayer {
+    cmd_stream: CommandStream,
+    batch_player: BatchPlayer,
+    open_batches: BTreeSet<Batch>,
+}
+
 impl Tree {
     pub fn new(log: Log<Command>) -> Tree {
+        Tree::starting_at(log, Commit(0))
+    }
+
+    /// Create a tree whose log only contains commits from `next_commit` on.
+    pub fn starting_at(log: Log<Command>, next_commit: Commit) -> Tree {
         Tree {
             initialized: AtomicBool::new(false),
             log: Arc::new(log),
             batch_player: Arc::new(BatchPlayer::new()),
-            index: Arc::new(Index::new()),
+            index: Arc::new(Index::starting_at(next_commit)),
+            appends: Arc::new(Mutex::new(None)),
         }
     }
 
-    pub fn init_replayer(&self) -> InitReplayer {
+    pub fn init_replayer(&self) -> InitReplayer<'_> {
         assert!(!self.initialized.load(Ordering::SeqCst));
 
         InitReplayer {
             initialized: &self.initialized,
             cmd_stream: Box::pin(self.log.replay()),
-            index: &*self.index,
+            index: &self.index,
             batch_players: BTreeMap::new(),
             previous_commit: None,
             max_batch_seen: None,
             max_batch_commit_seen: None,
             waiting_to_commit: BTreeSet::new(),
+            skip_before: Commit(0),
+            last_address: None,
+            appends: &self.appends,
+            init_success: false,
+        }
+    }
+
+    /// Restore the index from a checkpoint taken at `commit_limit`,
+    /// and replay only the commands after it.
+    pub fn resume_replayer(&self, checkpoint: Checkpoint, commit_limit: Commit) -> InitReplayer<'_> {
+        assert!(!self.initialized.load(Ordering::SeqCst));
+
+        self.index.restore(checkpoint.index);
+
+        let cmd_stream: Pin<Box<dyn Stream<Item = _>>> = match checkpoint.last {
+            Some(last) => Box::pin(self.log.replay_after(last)),
+            None => Box::pin(self.log.replay()),
+        };
+
+        // The open batches may commit after the checkpoint
+        // commands they logged before it.
+        let mut batch_players = BTreeMap::new();
+        let mut waiting_to_commit = BTreeSet::new();
+        for snapshot in checkpoint.batches {
+            let batch = snapshot.batch();
+            for batch_commit in snapshot.batch_commits() {
+                waiting_to_commit.insert((batch, batch_commit));
+            }
+            let batch_player = BatchPlayer::new();
+            batch_player.restore(snapshot);
+            batch_players.insert(batch, batch_player);
+        }
+
+        InitReplayer {
+            initialized: &self.initialized,
+            cmd_stream,
+            index: &self.index,
+            batch_players,
+            previous_commit: None,
+            max_batch_seen: None,
+            max_batch_commit_seen: None,
+            waiting_to_commit,
+            skip_before: commit_limit,
+            last_address: checkpoint.last,
+            appends: &self.appends,
             init_success: false,
         }
     }
@@ -73,6 +163,82 @@ impl Tree {
         self.initialized.store(true, Ordering::SeqCst);
     }
 
+    /// Discard what a failed init loaded into the index,
+    /// so that the tree can be initialized again.
+    pub fn reset_init(&self, next_commit: Commit) {
+        assert!(!self.initialized.load(Ordering::SeqCst));
+
+        self.index.reset(next_commit);
+    }
+
+    /// Initialize from a log containing a single batch,
+    /// not recorded in the commit log, as `commit`.
+    ///
+    /// Returns `false` if the batch was never closed,
+    /// or the log could not be read to the end,
+    /// in which case the tree remains uninitialized.
+    pub async fn init_single_batch(&self,
+                                   batch: Batch,
+                                   batch_commit: BatchCommit,
+                                   commit: Commit) -> Result<bool> {
+        assert!(!self.initialized.load(Ordering::SeqCst));
+
+        let batch_player = BatchPlayer::new();
+        let mut cmd_stream = self.log.replay();
+        let mut opened = false;
+        let mut committed = false;
+
+        while let Some(next_cmd) = cmd_stream.next().await {
+            let (next_cmd, addr) = match next_cmd {
+                Ok(next_cmd) => next_cmd,
+                Err(e) => {
+                    log::warn!("unreadable single-batch log: {}", e);
+                    return Ok(false);
+                }
+            };
+
+            if next_cmd.batch() != batch {
+                bail!("unexpected batch {} in single-batch log", next_cmd.batch().0);
+            }
+
+            match next_cmd {
+                Command::Open { .. } => {
+                    if opened {
+                        bail!("redundant batch open in single-batch log");
+                    }
+                    opened = true;
+                    batch_player.record(&next_cmd, addr);
+                },
+                _ if !opened => {
+                    bail!("command before batch open in single-batch log");
+                },
+                Command::ReadyCommit { batch_commit: bc, .. } if bc == batch_commit => {
+                    batch_player.record(&next_cmd, addr);
+                    commit_to_index(&batch_player, &self.index, batch, batch_commit, commit);
+                    committed = true;
+                },
+                Command::Close { .. } => {
+                    if !committed {
+                        bail!("single-batch log closed before commit");
+                    }
+                    batch_player.record(&next_cmd, addr);
+                    self.initialized.store(true, Ordering::SeqCst);
+                    return Ok(true);
+                },
+                _ => {
+                    batch_player.record(&next_cmd, addr);
+                },
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// The commit number that the next commit to this tree must not precede.
+    pub fn next_commit(&self) -> Commit {
+        self.index.next_commit()
+    }
+
     pub fn batch(&self, batch: Batch) -> BatchWriter {
         assert!(self.initialized.load(Ordering::SeqCst));
 
@@ -81,43 +247,141 @@ impl Tree {
             log: self.log.clone(),
             batch_player: self.batch_player.clone(),
             index: self.index.clone(),
+            appends: self.appends.clone(),
         }
     }
 
-    pub async fn read(&self, commit_limit: Commit, key: &Key) -> Result<Option<Value>> {
+    /// The tree's index and open batches,
+    /// which must be taken between commits.
+    pub async fn checkpoint(&self) -> Checkpoint {
         assert!(self.initialized.load(Ordering::SeqCst));
 
-        let addr = self.index.read(commit_limit, key);
+        let last = self.appends.lock().await;
+        Checkpoint {
+            last: *last,
+            index: self.index.snapshot(),
+            batches: self.batch_player.snapshot(),
+        }
+    }
 
-        if let Some(addr) = addr {
-            let cmd = self.log.read_at(addr).await?;
-            match cmd {
-                Command::Write { key: log_key , value, .. } => {
-                    assert_eq!(key, &log_key);
-                    Ok(Some(value))
-                }
-                _ => {
-                    Err(anyhow!(UNEXPECTED_LOG))
-                }
+    pub fn lookup(&self, commit_limit: Commit, key: &Key) -> Lookup {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        self.index.lookup(commit_limit, key)
+    }
+
+    /// Read the value of a write, or the operand of a merge.
+    pub async fn read_value(&self, key: &Key, addr: Address) -> Result<Value> {
+        read_value(&self.log, key, addr).await
+    }
+
+    /// Discard the records after the last commit or abort of any of `commits`,
+    /// or every record if there is none.
+    ///
+    /// Records of other batches before then are kept,
+    /// and are replayed as never having committed.
+    pub async fn truncate_log(&self, commits: &BTreeSet<(Batch, BatchCommit)>) -> Result<()> {
+        assert!(!self.initialized.load(Ordering::SeqCst));
+
+        let mut cmd_stream = self.log.replay();
+        let mut truncate_at = None;
+        let mut after_commit = true;
+
+        while let Some(next_cmd) = cmd_stream.next().await {
+            let (next_cmd, addr) = next_cmd?;
+            if after_commit {
+                truncate_at = Some(addr);
+                after_commit = false;
             }
-        } else {
-            Ok(None)
+            match next_cmd {
+                Command::ReadyCommit { batch, batch_commit }
+                | Command::AbortCommit { batch, batch_commit }
+                    if commits.contains(&(batch, batch_commit)) => {
+                    truncate_at = None;
+                    after_commit = true;
+                },
+                _ => { },
+            }
+        }
+
+        drop(cmd_stream);
+        if let Some(addr) = truncate_at {
+            self.log.truncate(addr).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The address of the last command appended to the log, if any.
+    pub async fn last_appended(&self) -> Option<Address> {
+        *self.appends.lock().await
+    }
+
+    /// Append each of the log's records up to and including
+    /// the one at `last` to `dest`, or none if `None`.
+    pub async fn copy_log(&self, dest: &Log<Command>, last: Option<Address>) -> Result<()> {
+        let last = match last {
+            Some(last) => last,
+            None => return dest.sync().await,
+        };
+        let mut cmd_stream = self.log.replay();
+        while let Some(next_cmd) = cmd_stream.next().await {
+            let (next_cmd, addr) = next_cmd?;
+            if addr > last {
+                break;
+            }
+            dest.append(next_cmd).await?;
+        }
+        dest.sync().await
+    }
+
+    /// Replay the log for the ops of chosen commits,
+    /// as they were played into the index when committed.
+    pub fn commit_replayer(&self) -> CommitReplayer {
+        CommitReplayer {
+            cmd_stream: Box::pin(self.log.replay()),
+            batch_player: BatchPlayer::new(),
+            open_batches: BTreeSet::new(),
         }
     }
 
+    /// The commits before the commit limit that changed the key, oldest first.
+    pub fn versions(&self, commit_limit: Commit, key: &Key) -> Vec<Commit> {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        self.index.versions(commit_limit, key)
+    }
+
+    /// Whether any key in `range` was changed by a commit at or after `since`.
+    pub fn written_since(&self, since: Commit, range: &KeyRange) -> bool {
+        assert!(self.initialized.load(Ordering::SeqCst));
+
+        self.index.written_since(since, range)
+    }
+
     pub fn cursor(&self, commit_limit: Commit) -> Cursor {
         assert!(self.initialized.load(Ordering::SeqCst));
 
         Cursor {
-            log: self.log.clone(),
             index_cursor: self.index.cursor(commit_limit),
-            value: None,
         }
     }
 
     pub async fn sync(&self) -> Result<()> {
         Ok(self.log.sync().await?)
     }
+
+    /// Discard index history that no read at `horizon` or later can see.
+    ///
+    /// `bottom` says that no tree is read after this one.
+    pub fn collect_garbage(&self, horizon: Commit, bottom: bool) -> usize {
+        self.index.collect_garbage(horizon, bottom)
+    }
+
+    /// The number of versions and range deletes in the index.
+    pub fn index_entries(&self) -> usize {
+        self.index.entries()
+    }
 }
 
 impl BatchWriter {
@@ -151,6 +415,14 @@ impl BatchWriter {
         }).await?)
     }
 
+    pub async fn merge(&self, key: Key, operand: Value) -> Result<()> {
+        self.append_record(Command::Merge {
+            batch: self.batch,
+            key,
+            operand,
+        }).await
+    }
+
     pub async fn push_save_point(&self) -> Result<()> {
         Ok(self.append_record(Command::PushSavePoint {
             batch: self.batch,
@@ -191,6 +463,16 @@ impl BatchWriter {
                         commit)                        
     }
 
+    /// The batch's writes so far, with rolled back save points undone.
+    pub fn pending(&self) -> Vec<IndexOp> {
+        self.batch_player.pending(self.batch)
+    }
+
+    /// Read the value or operand of one of the batch's writes or merges.
+    pub async fn read_value(&self, key: &Key, addr: Address) -> Result<Value> {
+        read_value(&self.log, key, addr).await
+    }
+
     /// NB: This must only be called after the batch is committed
     pub async fn close(&self) -> Result<()> {
         let res = self.append_record(Command::Close {
@@ -205,9 +487,15 @@ impl BatchWriter {
         }
     }
 
+    pub async fn sync(&self) -> Result<()> {
+        self.log.sync().await
+    }
+
     async fn append_record(&self, cmd: Command) -> Result<()> {
+        let mut last = self.appends.lock().await;
         let address = self.log.append(cmd.clone()).await?;
         self.batch_player.record(&cmd, address);
+        *last = Some(address);
         Ok(())
     }
 }
@@ -221,56 +509,68 @@ impl Cursor {
         self.index_cursor.key()
     }
 
-    pub async fn value(&mut self) -> Result<Value> {
-        assert!(self.valid());
-        if let Some(value) = &self.value {
-            Ok(value.clone())
-        } else {
-            let addr = self.index_cursor.address();
-            let cmd = self.log.read_at(addr).await?;
-            match cmd {
-                Command::Write { key, value, .. } => {
-                    assert_eq!(key, self.key());
-                    Ok(value)
-                },
-                _ => {
-                    Err(anyhow!(UNEXPECTED_LOG))
-                }
-            }
-        }
-    }
-
     pub fn next(&mut self) {
-        self.value = None;
         self.index_cursor.next()
     }
 
     pub fn prev(&mut self) {
-        self.value = None;
         self.index_cursor.prev()
     }
 
     pub fn seek_first(&mut self) {
-        self.value = None;
         self.index_cursor.seek_first()
     }
 
     pub fn seek_last(&mut self) {
-        self.value = None;
         self.index_cursor.seek_last()
     }
 
     pub fn seek_key(&mut self, key: Key) {
-        self.value = None;
         self.index_cursor.seek_key(key)
     }
 
     pub fn seek_key_rev(&mut self, key: Key) {
-        self.value = None;
         self.index_cursor.seek_key_rev(key)
     }
 }
 
+impl CommitReplayer {
+    /// The next of `commits` in the log, and the ops of its batch,
+    /// or `None` at the end of the log.
+    pub async fn next(&mut self,
+                      commits: &BTreeMap<(Batch, BatchCommit), Commit>) -> Result<Option<(Commit, Vec<IndexOp>)>> {
+        while let Some(next_cmd) = self.cmd_stream.next().await {
+            let (next_cmd, addr) = next_cmd?;
+            let batch = next_cmd.batch();
+
+            let opened = match next_cmd {
+                Command::Open { .. } => self.open_batches.insert(batch),
+                _ => self.open_batches.contains(&batch),
+            };
+            if !opened {
+                bail!("unexpected batch {} during commit replay", batch.0);
+            }
+
+            self.batch_player.record(&next_cmd, addr);
+
+            match next_cmd {
+                Command::ReadyCommit { batch, batch_commit } => {
+                    if let Some(commit) = commits.get(&(batch, batch_commit)) {
+                        let ops = self.batch_player.replay(batch, batch_commit).collect();
+                        return Ok(Some((*commit, ops)));
+                    }
+                },
+                Command::Close { batch } => {
+                    self.open_batches.remove(&batch);
+                },
+                _ => { },
+            }
+        }
+
+        Ok(None)
+    }
+}
+
 impl<'tree> InitReplayer<'tree> {
     pub async fn replay_commit(&mut self,
                                batch: Batch,
@@ -280,6 +580,11 @@ impl<'tree> InitReplayer<'tree> {
         let target_batch = batch;
         let target_batch_commit = batch_commit;
 
+        if commit < self.skip_before {
+            // Already restored from a checkpoint
+            return Ok(());
+        }
+
         if self.waiting_to_commit.remove(&(target_batch, target_batch_commit)) {
             let batch_player = self.batch_players.get(&batch);
             if let Some(batch_player) = batch_player {
@@ -293,6 +598,7 @@ impl<'tree> InitReplayer<'tree> {
         while let Some(next_cmd) = self.cmd_stream.next().await {
             let (next_cmd, addr) = next_cmd?;
             log::trace!("next cmd {:?}", next_cmd);
+            self.last_address = Some(addr);
 
             let new_batch = Some(next_cmd.batch());
             let mut new_batch_commit = None;
@@ -397,6 +703,7 @@ impl<'tree> InitReplayer<'tree> {
     pub async fn replay_rest(&mut self) -> Result<(Option<Batch>, Option<BatchCommit>)> {
         while let Some(next_cmd) = self.cmd_stream.next().await {
             let (next_cmd, addr) = next_cmd?;
+            self.last_address = Some(addr);
 
             let new_batch = Some(next_cmd.batch());
             let mut new_batch_commit = None;
@@ -449,6 +756,8 @@ impl<'tree> InitReplayer<'tree> {
     }
 
     pub fn init_success(mut self) {
+        // Nothing can append until the tree is initialized
+        *self.appends.try_lock().expect("lock") = self.last_address;
         self.init_success = true
     }
 }
@@ -459,6 +768,20 @@ impl<'tree> Drop for InitReplayer<'tree> {
     }
 }
 
+async fn read_value(log: &Log<Command>, key: &Key, addr: Address) -> Result<Value> {
+    let cmd = log.read_at(addr).await?;
+    match cmd {
+        Command::Write { key: log_key , value, .. }
+        | Command::Merge { key: log_key, operand: value, .. } => {
+            assert_eq!(key, &log_key);
+            Ok(value)
+        }
+        _ => {
+            Err(anyhow!(UNEXPECTED_LOG))
+        }
+    }
+}
+
 fn commit_to_index(batch_player: &BatchPlayer,
                    index: &Index,
                    batch: Batch,
@@ -477,6 +800,9 @@ fn commit_to_index(batch_player: &BatchPlayer,
             IndexOp::DeleteRange { start_key, end_key, address } => {
                 writer.delete_range(start_key..end_key, address);
             },
+            IndexOp::Merge { key, address } => {
+                writer.merge(key, address);
+            },
         }
     }
 }
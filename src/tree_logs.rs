@@ -0,0 +1,263 @@
+//! The logs that make up a single compacting tree.
+//!
+//! On disk a tree named `t` is stored in directory `t`,
+//! or `t@<batch>` if its first batch is `<batch>` rather than 0,
+//! so that a tree re-created after being dropped
+//! never shares logs with the dropped tree.
+//! The directory holds
+//!
+//! * `<commit>.<segment>.toml` - a log active from `<commit>`
+//! * `compacted-<commit>.<segment>.toml` - the compacted state of the tree
+//!   before `<commit>`
+//!
+//! where the extension depends on the log format.
+//!
+//! In memory the same logs are named `t/<commit>`
+//! and `t/compacted-<commit>`, with `t` named as the directory.
+
+use std::fs;
+use std::io;
+use std::fs::File;
+use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, bail};
+use futures::future::BoxFuture;
+use crate::types::{Batch, Commit};
+use crate::command::Command;
+use crate::log::Log;
+use crate::log_file::LogFile;
+use crate::faulty_log_file::{self, Faults};
+use crate::fs_thread::FsThread;
+use crate::simple_log_file::{self, LogFormat};
+use crate::mem_log_file::{self, MemDisk};
+
+#[derive(Eq, PartialEq)]
+#[derive(Ord, PartialOrd)]
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+pub enum LogName {
+    /// A log of batches committed from a commit number on.
+    Active(Commit),
+    /// A log of the live data committed before a commit number.
+    Compacted(Commit),
+}
+
+pub struct TreeLogs {
+    pub list: Box<dyn Fn() -> BoxFuture<'static, Result<Vec<LogName>>> + Send + Sync>,
+    pub open: Box<dyn Fn(LogName) -> LogFile<Command> + Send + Sync>,
+    pub remove: Box<dyn Fn(LogName) -> BoxFuture<'static, Result<()>> + Send + Sync>,
+    /// Remove the tree's directory, which must be empty.
+    pub remove_dir: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>,
+    pub sync: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>,
+}
+
+impl TreeLogs {
+    pub async fn list(&self) -> Result<Vec<LogName>> {
+        (self.list)().await
+    }
+
+    pub fn open(&self, name: LogName) -> Log<Command> {
+        Log::new((self.open)(name))
+    }
+
+    pub async fn remove(&self, name: LogName) -> Result<()> {
+        (self.remove)(name).await
+    }
+
+    /// Open every log of the tree.
+    pub async fn open_all(&self) -> Result<Vec<(LogName, Log<Command>)>> {
+        Ok(self.list().await?.into_iter()
+           .map(|name| (name, self.open(name)))
+           .collect())
+    }
+
+    pub async fn remove_all(&self) -> Result<()> {
+        for name in self.list().await? {
+            self.remove(name).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove_dir(&self) -> Result<()> {
+        (self.remove_dir)().await
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        (self.sync)().await
+    }
+}
+
+pub fn mem(disk: MemDisk, tree: &str, first_batch: Batch) -> TreeLogs {
+    let tree = dir_name(tree, first_batch);
+    let prefix = format!("{}/", tree);
+    let disk2 = disk.clone();
+    let disk3 = disk.clone();
+    let tree2 = tree.clone();
+
+    TreeLogs {
+        list: Box::new(move || {
+            let mut names: Vec<_> = disk.names().iter()
+                .filter_map(|name| name.strip_prefix(&prefix))
+                .filter_map(parse_log_file_name)
+                .collect();
+            names.sort();
+            Box::pin(async { Ok(names) })
+        }),
+        open: Box::new(move |name| {
+            mem_log_file::open(&disk2, &format!("{}/{}", tree, log_file_name(name)))
+        }),
+        remove: Box::new(move |name| {
+            disk3.remove(&format!("{}/{}", tree2, log_file_name(name)));
+            Box::pin(async { Ok(()) })
+        }),
+        remove_dir: Box::new(|| Box::pin(async { Ok(( /* nop */ )) })),
+        sync: Box::new(|| Box::pin(async { Ok(( /* nop */ )) })),
+    }
+}
+
+pub fn simple(dir: PathBuf,
+              tree: &str,
+              first_batch: Batch,
+              format: LogFormat,
+              segment_size: u64,
+              fs_thread: Arc<FsThread>) -> Result<TreeLogs> {
+    let dir = dir.join(dir_name(tree, first_batch));
+
+    // FIXME: async create dir
+    fs::create_dir_all(&dir)?;
+
+    let state1 = Arc::new(State { dir, format, segment_size, fs_thread });
+    let state2 = state1.clone();
+    let state3 = state1.clone();
+    let state4 = state1.clone();
+    let state5 = state1.clone();
+
+    Ok(TreeLogs {
+        list: Box::new(move || Box::pin(list(state1.clone()))),
+        open: Box::new(move |name| {
+            simple_log_file::create(state2.dir.clone(),
+                                    log_file_name(name),
+                                    state2.format,
+                                    state2.segment_size,
+                                    state2.fs_thread.clone())
+        }),
+        remove: Box::new(move |name| Box::pin(remove(state3.clone(), name))),
+        remove_dir: Box::new(move || Box::pin(remove_dir(state5.clone()))),
+        sync: Box::new(move || Box::pin(sync(state4.clone()))),
+    })
+}
+
+/// Open every log of the tree `tree` in `dir` only to read it,
+/// as by [`simple_log_file::open_read_only`].
+///
+/// Fails if the tree has no directory.
+/// This does blocking I/O.
+pub fn open_read_only(dir: &Path, tree: &str, first_batch: Batch, format: LogFormat) -> Result<Vec<(LogName, Log<Command>)>> {
+    let dir = dir.join(dir_name(tree, first_batch));
+    if !dir.is_dir() {
+        bail!("no directory for tree {} in {}", tree, dir.display());
+    }
+
+    let mut names: Vec<_> = simple_log_file::log_names(&dir, format)?.iter()
+        .filter_map(|name| parse_log_file_name(name))
+        .collect();
+    names.sort();
+
+    names.into_iter().map(|name| {
+        let log = simple_log_file::open_read_only(dir.clone(), log_file_name(name), format)?;
+        Ok((name, Log::new(log)))
+    }).collect()
+}
+
+/// Wrap every log opened with injected faults.
+pub fn faulty(logs: TreeLogs, tree: String, faults: Faults) -> TreeLogs {
+    let TreeLogs { list, open, remove, remove_dir, sync } = logs;
+    TreeLogs {
+        list,
+        open: Box::new(move |name| {
+            let log_name = format!("{}/{}", tree, log_file_name(name));
+            faulty_log_file::wrap(log_name, open(name), faults.clone())
+        }),
+        remove,
+        remove_dir,
+        sync,
+    }
+}
+
+struct State {
+    dir: PathBuf,
+    format: LogFormat,
+    segment_size: u64,
+    fs_thread: Arc<FsThread>,
+}
+
+/// The directory of a tree whose first batch is `first_batch`.
+pub fn dir_name(tree: &str, first_batch: Batch) -> String {
+    if first_batch == Batch(0) {
+        tree.to_string()
+    } else {
+        format!("{}@{}", tree, first_batch.0)
+    }
+}
+
+pub fn log_file_name(name: LogName) -> String {
+    match name {
+        LogName::Active(commit) => format!("{}", commit.0),
+        LogName::Compacted(commit) => format!("compacted-{}", commit.0),
+    }
+}
+
+fn parse_log_file_name(name: &str) -> Option<LogName> {
+    if let Some(commit) = name.strip_prefix("compacted-") {
+        commit.parse().ok().map(|c| LogName::Compacted(Commit(c)))
+    } else {
+        name.parse().ok().map(|c| LogName::Active(Commit(c)))
+    }
+}
+
+async fn list(state: Arc<State>) -> Result<Vec<LogName>> {
+    let dir = state.dir.clone();
+    let format = state.format;
+    let future = state.fs_thread.run(move |_| -> Result<_> {
+        let names = simple_log_file::log_names(&dir, format)?;
+        let mut names: Vec<_> = names.iter()
+            .filter_map(|name| parse_log_file_name(name))
+            .collect();
+        names.sort();
+        Ok(names)
+    });
+    future.await
+}
+
+async fn remove(state: Arc<State>, name: LogName) -> Result<()> {
+    let dir = state.dir.clone();
+    let format = state.format;
+    let future = state.fs_thread.run(move |ctx| -> Result<_> {
+        simple_log_file::remove(ctx, &dir, &log_file_name(name), format)
+    });
+    future.await
+}
+
+async fn remove_dir(state: Arc<State>) -> Result<()> {
+    let dir = state.dir.clone();
+    let future = state.fs_thread.run(move |_| -> Result<_> {
+        match fs::remove_dir(&dir) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            r => Ok(r?),
+        }
+    });
+    future.await
+}
+
+async fn sync(state: Arc<State>) -> Result<()> {
+    if !cfg!(unix) {
+        return Ok(());
+    }
+
+    let path = state.dir.clone();
+    let future = state.fs_thread.run(move |_| -> Result<_> {
+        File::open(&path)?.sync_all()?;
+        Ok(())
+    });
+    future.await
+}
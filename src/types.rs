@@ -1,10 +1,19 @@
 use serde::{Serialize, Deserialize};
 use std::sync::Arc;
+use std::ops::Bound;
 
+#[derive(Serialize, Deserialize)]
 #[derive(Eq, PartialEq)]
+#[derive(Ord, PartialOrd)]
 #[derive(Copy, Clone)]
 #[derive(Debug)]
-pub struct Address(pub u64);
+pub struct Address {
+    /// The segment of the log
+    pub segment: u64,
+    /// The byte offset into the segment,
+    /// or the command number in memory logs
+    pub offset: u64,
+}
 
 #[derive(Serialize, Deserialize)]
 #[derive(Eq, PartialEq)]
@@ -41,6 +50,15 @@ pub struct BatchCommit(pub u64);
 #[derive(Debug)]
 pub struct Commit(pub u64);
 
+/// An inclusive range of keys,
+/// unbounded at either end that is `None`.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct KeyRange {
+    pub start: Option<Key>,
+    pub end: Option<Key>,
+}
+
 impl Key {
     pub fn from_slice(other: &[u8]) -> Key {
         Key(other.to_vec())
@@ -52,3 +70,34 @@ impl Value {
         Value(other.to_vec())
     }
 }
+
+impl KeyRange {
+    pub fn point(key: Key) -> KeyRange {
+        KeyRange {
+            start: Some(key.clone()),
+            end: Some(key),
+        }
+    }
+
+    /// Grow to cover `other` as well.
+    pub fn extend(&mut self, other: KeyRange) {
+        self.start = match (self.start.take(), other.start) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            _ => None,
+        };
+        self.end = match (self.end.take(), other.end) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            _ => None,
+        };
+    }
+
+    pub fn bounds(&self) -> (Bound<&Key>, Bound<&Key>) {
+        fn bound(key: &Option<Key>) -> Bound<&Key> {
+            match key {
+                Some(key) => Bound::Included(key),
+                None => Bound::Unbounded,
+            }
+        }
+        (bound(&self.start), bound(&self.end))
+    }
+}
@@ -0,0 +1,324 @@
+//! Offline consistency checks of a database's logs.
+//!
+//! The logs are only read,
+//! and problems are collected into a report
+//! rather than failing at the first,
+//! as opening the database would.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use futures::StreamExt;
+use anyhow::Result;
+use crate::log::Log;
+use crate::command::Command;
+use crate::commit_log::CommitCommand;
+use crate::tree_logs::{self, LogName};
+use crate::types::{Batch, BatchCommit, Commit};
+
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// The number of commits in the commit log
+    pub commits: usize,
+    /// The number of tree logs read
+    pub logs: usize,
+    pub problems: Vec<Problem>,
+}
+
+#[derive(Clone, Debug)]
+#[derive(Eq, PartialEq)]
+pub enum Problem {
+    /// A log that could not be read past its first `records` records.
+    Unreadable {
+        log: String,
+        records: usize,
+        error: String,
+    },
+    /// A commit numbered no later than the one before it.
+    NonMonotonicCommit {
+        commit: Commit,
+        previous: Commit,
+    },
+    /// A commit with no ready-commit or abort-commit
+    /// in the logs of a tree it wrote to.
+    MissingBatchCommit {
+        commit: Commit,
+        tree: String,
+    },
+    /// A commit whose batch commit is later than any
+    /// in the logs of a tree it wrote to,
+    /// as if the end of the tree's logs was lost.
+    CommitPastLogEnd {
+        commit: Commit,
+        tree: String,
+    },
+    /// Commands for a batch that was not open.
+    UnopenedBatch {
+        log: String,
+        batch: u64,
+    },
+    /// A batch opened while already open.
+    ReopenedBatch {
+        log: String,
+        batch: u64,
+    },
+    /// A batch that was never closed.
+    UnclosedBatch {
+        log: String,
+        batch: u64,
+    },
+    /// A save point popped or rolled back with none pushed.
+    SavePointImbalance {
+        log: String,
+        batch: u64,
+    },
+}
+
+/// What a tree's logs say about the commits that include it.
+struct TreeState {
+    first_batch: Batch,
+    /// Commits before this were compacted, so can't be checked
+    history_start: Commit,
+    batch_commits: BTreeSet<(Batch, BatchCommit)>,
+    /// The latest batch commit in the active logs
+    last_batch_commit: Option<BatchCommit>,
+}
+
+/// A tree, its first batch, and its logs.
+pub type TreeLogs = (String, Batch, Vec<(LogName, Log<Command>)>);
+
+/// Check the commit log against the logs of each tree,
+/// each of which only takes part in batches from its first batch on.
+pub async fn verify(commit_log: &Log<CommitCommand>,
+                    trees: Vec<TreeLogs>) -> Result<Report> {
+    let mut report = Report::default();
+
+    let mut tree_states = BTreeMap::new();
+    for (tree, first_batch, logs) in trees {
+        let mut state = TreeState {
+            first_batch,
+            history_start: Commit(0),
+            batch_commits: BTreeSet::new(),
+            last_batch_commit: None,
+        };
+        for (name, log) in logs {
+            let log_name = format!("{}/{}", tree, tree_logs::log_file_name(name));
+            let (sound, batch_commits) = verify_tree_log(&log_name, &log, &mut report).await;
+            report.logs += 1;
+            match name {
+                LogName::Active(_) => {
+                    state.batch_commits.extend(batch_commits);
+                },
+                // Unsound compacted logs are discarded on open
+                LogName::Compacted(start) if sound => {
+                    state.history_start = state.history_start.max(start);
+                },
+                LogName::Compacted(_) => { },
+            }
+        }
+        state.last_batch_commit = state.batch_commits.iter().map(|(_, batch_commit)| *batch_commit).max();
+        tree_states.insert(tree, state);
+    }
+
+    let mut previous = None;
+    let mut replay = commit_log.replay();
+    while let Some(cmd) = replay.next().await {
+        let cmd = match cmd {
+            Ok((cmd, _)) => cmd,
+            Err(e) => {
+                report.problems.push(Problem::Unreadable {
+                    log: "commits".to_string(),
+                    records: report.commits,
+                    error: e.to_string(),
+                });
+                break;
+            },
+        };
+        report.commits += 1;
+
+        if let Some(previous) = previous {
+            if cmd.commit <= previous {
+                report.problems.push(Problem::NonMonotonicCommit {
+                    commit: cmd.commit,
+                    previous,
+                });
+            }
+        }
+        previous = Some(cmd.commit);
+
+        for (tree, state) in tree_states.iter() {
+            if !participates(&cmd, tree, state) {
+                continue;
+            }
+            if state.batch_commits.contains(&(cmd.batch, cmd.batch_commit)) {
+                continue;
+            }
+            let past_end = match state.last_batch_commit {
+                Some(last) => cmd.batch_commit > last,
+                None => true,
+            };
+            if past_end {
+                report.problems.push(Problem::CommitPastLogEnd {
+                    commit: cmd.commit,
+                    tree: tree.clone(),
+                });
+            } else {
+                report.problems.push(Problem::MissingBatchCommit {
+                    commit: cmd.commit,
+                    tree: tree.clone(),
+                });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Whether a commit should be found in a tree's logs,
+/// as the loader would replay it.
+fn participates(cmd: &CommitCommand, tree: &str, state: &TreeState) -> bool {
+    if cmd.batch < state.first_batch || cmd.commit < state.history_start {
+        return false;
+    }
+    match &cmd.trees {
+        Some(trees) => trees.iter().any(|t| t == tree),
+        None => true,
+    }
+}
+
+/// Check the batches of one tree log,
+/// returning whether it had no problems,
+/// and the batch commits it readied or aborted.
+async fn verify_tree_log(log_name: &str,
+                         log: &Log<Command>,
+                         report: &mut Report) -> (bool, BTreeSet<(Batch, BatchCommit)>) {
+    let problems_before = report.problems.len();
+    let mut problem = |problem| report.problems.push(problem);
+
+    // The save point depth of each open batch
+    let mut batches: BTreeMap<Batch, usize> = BTreeMap::new();
+    let mut unopened = BTreeSet::new();
+    let mut batch_commits = BTreeSet::new();
+    let mut records = 0;
+
+    let mut replay = log.replay();
+    while let Some(cmd) = replay.next().await {
+        let cmd = match cmd {
+            Ok((cmd, _)) => cmd,
+            Err(e) => {
+                problem(Problem::Unreadable {
+                    log: log_name.to_string(),
+                    records,
+                    error: e.to_string(),
+                });
+                break;
+            },
+        };
+        records += 1;
+
+        let batch = cmd.batch();
+        if let Command::Open { .. } = cmd {
+            if batches.insert(batch, 0).is_some() {
+                problem(Problem::ReopenedBatch {
+                    log: log_name.to_string(),
+                    batch: batch.0,
+                });
+            }
+            continue;
+        }
+
+        let depth = match batches.get_mut(&batch) {
+            Some(depth) => depth,
+            None => {
+                if unopened.insert(batch) {
+                    problem(Problem::UnopenedBatch {
+                        log: log_name.to_string(),
+                        batch: batch.0,
+                    });
+                }
+                continue;
+            },
+        };
+
+        match cmd {
+            Command::PushSavePoint { .. } => {
+                *depth += 1;
+            },
+            Command::PopSavePoint { .. } | Command::RollbackSavePoint { .. } => {
+                if *depth == 0 {
+                    problem(Problem::SavePointImbalance {
+                        log: log_name.to_string(),
+                        batch: batch.0,
+                    });
+                } else {
+                    *depth -= 1;
+                }
+            },
+            Command::ReadyCommit { batch_commit, .. }
+            | Command::AbortCommit { batch_commit, .. } => {
+                batch_commits.insert((batch, batch_commit));
+            },
+            Command::Close { .. } => {
+                batches.remove(&batch);
+            },
+            _ => { },
+        }
+    }
+
+    for batch in batches.keys() {
+        problem(Problem::UnclosedBatch {
+            log: log_name.to_string(),
+            batch: batch.0,
+        });
+    }
+
+    (report.problems.len() == problems_before, batch_commits)
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "commits: {}", self.commits)?;
+        writeln!(f, "tree logs: {}", self.logs)?;
+        writeln!(f, "problems: {}", self.problems.len())?;
+        for problem in &self.problems {
+            writeln!(f, "  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Unreadable { log, records, error } => {
+                write!(f, "{}: unreadable after {} records: {}", log, records, error)
+            },
+            Problem::NonMonotonicCommit { commit, previous } => {
+                write!(f, "commits: commit {} follows commit {}", commit.0, previous.0)
+            },
+            Problem::MissingBatchCommit { commit, tree } => {
+                write!(f, "{}: no ready-commit or abort-commit for commit {}", tree, commit.0)
+            },
+            Problem::CommitPastLogEnd { commit, tree } => {
+                write!(f, "{}: commit {} is past the end of the logs", tree, commit.0)
+            },
+            Problem::UnopenedBatch { log, batch } => {
+                write!(f, "{}: batch {} not opened", log, batch)
+            },
+            Problem::ReopenedBatch { log, batch } => {
+                write!(f, "{}: batch {} opened twice", log, batch)
+            },
+            Problem::UnclosedBatch { log, batch } => {
+                write!(f, "{}: batch {} not closed", log, batch)
+            },
+            Problem::SavePointImbalance { log, batch } => {
+                write!(f, "{}: batch {} pops a save point it didn't push", log, batch)
+            },
+        }
+    }
+}
@@ -74,3 +74,2218 @@ read-assert t1 k1 v1
 
 ")
 }
+
+#[test]
+fn compact_overwrites_and_deletes() -> Result<()> {
+    run("
+
+mem
+write t1 k1 v1
+write t1 k1 v2
+write t1 k2 v2
+delete t1 k2
+write t1 k3 v3
+compact t1
+read-assert t1 k1 v2
+read-assert t1 k2 <none>
+read-assert t1 k3 v3
+read-assert t2 k1 <none>
+
+")
+}
+
+#[test]
+fn delete_after_compact() -> Result<()> {
+    run("
+
+mem
+write t1 k1 v1
+write t1 k2 v2
+write t1 k3 v3
+compact t1
+delete t1 k1
+delete-range t1 k2 k3
+read-assert t1 k1 <none>
+read-assert t1 k2 <none>
+read-assert t1 k3 v3
+compact t1
+read-assert t1 k1 <none>
+read-assert t1 k2 <none>
+read-assert t1 k3 v3
+
+")
+}
+
+#[test]
+fn compact_twice() -> Result<()> {
+    run("
+
+mem
+write t1 k1 v1
+compact t1
+compact t1
+write t1 k1 v2
+write t1 k2 v2
+compact t1
+read-assert t1 k1 v2
+read-assert t1 k2 v2
+
+")
+}
+
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir()
+        .join(format!("blocksy3-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn config(dir: Option<std::path::PathBuf>) -> db::DbConfig {
+    db::DbConfig {
+        dir,
+        trees: vec!["t1".to_string(), "t2".to_string()],
+        log_format: db::LogFormat::Toml,
+        truncate_torn_writes: false,
+        log_segment_size: 1024,
+        durability: db::Durability::Manual,
+        mem_disk: None,
+        lock_timeout: None,
+        merge_operators: Default::default(),
+        rollback_to: None,
+        truncate_rollback: false,
+        index_checkpoint_interval: None,
+        subscription_capacity: 1024,
+    }
+}
+
+async fn write(db: &db::Db, key: &str, value: &str) -> Result<()> {
+    let batch = db.write_batch().await?;
+    batch.tree("t1").write(key.as_bytes(), value.as_bytes()).await?;
+    batch.commit().await?;
+    batch.close().await;
+    Ok(())
+}
+
+async fn read(view: &db::ReadView, key: &str) -> Result<Option<String>> {
+    let value = view.tree("t1").read(key.as_bytes()).await?;
+    Ok(value.map(|v| String::from_utf8(v).expect("utf8")))
+}
+
+async fn keys(view: &db::ReadView) -> Result<(Vec<String>, Vec<String>)> {
+    let tree = view.tree("t1");
+    let mut cursor = tree.cursor()?;
+    let mut forward = vec![];
+    cursor.seek_first();
+    while cursor.valid() {
+        forward.push(String::from_utf8(cursor.key()).expect("utf8"));
+        cursor.next();
+    }
+    let mut backward = vec![];
+    cursor.seek_last();
+    while cursor.valid() {
+        backward.push(String::from_utf8(cursor.key()).expect("utf8"));
+        cursor.prev();
+    }
+    Ok((forward, backward))
+}
+
+#[test]
+fn compact_keeps_old_views() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        write(&db, "k1", "v1").await?;
+        write(&db, "k2", "v2").await?;
+        let old_view = db.read_view();
+        write(&db, "k1", "v3").await?;
+        assert!(db.compact("t1").await?);
+        write(&db, "k2", "v4").await?;
+        assert!(db.compact("t1").await?);
+
+        assert_eq!(read(&old_view, "k1").await?, Some("v1".to_string()));
+        assert_eq!(read(&old_view, "k2").await?, Some("v2".to_string()));
+        let view = db.read_view();
+        assert_eq!(read(&view, "k1").await?, Some("v3".to_string()));
+        assert_eq!(read(&view, "k2").await?, Some("v4".to_string()));
+        Ok(())
+    })
+}
+
+#[test]
+fn compact_cursor_merges_trees() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        for key in &["a", "b", "c", "d", "e"] {
+            write(&db, key, "v").await?;
+        }
+        assert!(db.compact("t1").await?);
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1").delete(b"b").await?;
+        batch.tree("t1").delete_range(b"d", b"e").await?;
+        batch.tree("t1").write(b"bb", b"v").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let view = db.read_view();
+        let expected = vec!["a", "bb", "c", "e"];
+        let (forward, mut backward) = keys(&view).await?;
+        backward.reverse();
+        assert_eq!(forward, expected);
+        assert_eq!(backward, expected);
+
+        let tree = view.tree("t1");
+        let mut cursor = tree.cursor()?;
+        cursor.seek_key(b"b");
+        assert_eq!(cursor.key(), b"bb");
+        cursor.prev();
+        assert_eq!(cursor.key(), b"a");
+        cursor.seek_key_rev(b"d");
+        assert_eq!(cursor.key(), b"c");
+        cursor.next();
+        assert_eq!(cursor.key(), b"e");
+        assert_eq!(cursor.value().await?, b"v");
+        Ok(())
+    })
+}
+
+#[test]
+fn compact_waits_for_open_batches() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        write(&db, "k1", "v1").await?;
+
+        let batch = db.write_batch().await?;
+        let (compacted, _) = futures::join!(db.compact("t1"), async {
+            batch.tree("t1").write(b"k1", b"v2").await?;
+            batch.commit().await?;
+            batch.close().await;
+            Ok::<_, anyhow::Error>(())
+        });
+        assert!(compacted?);
+
+        let view = db.read_view();
+        assert_eq!(read(&view, "k1").await?, Some("v2".to_string()));
+        Ok(())
+    })
+}
+
+#[test]
+fn compact_not_held_off_by_new_batches() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        let old = db.write_batch().await?;
+        old.tree("t1").write(b"k1", b"v1").await?;
+        let new = db.write_batch().await?;
+        let new_tree = new.tree("t1");
+
+        // The new batch is still open when compaction finishes
+        let (compacted, written, _) = futures::join!(
+            db.compact("t1"),
+            new_tree.write(b"k2", b"v2"),
+            async {
+                old.commit().await?;
+                old.close().await;
+                Ok::<_, anyhow::Error>(())
+            },
+        );
+        assert!(compacted?);
+        written?;
+        drop(new_tree);
+        new.commit().await?;
+        new.close().await;
+
+        let view = db.read_view();
+        assert_eq!(read(&view, "k1").await?, Some("v1".to_string()));
+        assert_eq!(read(&view, "k2").await?, Some("v2".to_string()));
+        Ok(())
+    })
+}
+
+#[test]
+fn compact_and_reopen() -> Result<()> {
+    let dir = temp_dir("compact_and_reopen");
+    block_on(async {
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            write(&db, "k1", "v1").await?;
+            write(&db, "k2", "v2").await?;
+            write(&db, "k1", "v3").await?;
+            assert!(db.compact("t1").await?);
+            write(&db, "k2", "v4").await?;
+            db.sync().await?;
+        }
+
+        assert!(!dir.join("t1").join("0.0.toml").exists());
+
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("v3".to_string()));
+            assert_eq!(read(&view, "k2").await?, Some("v4".to_string()));
+            write(&db, "k3", "v5").await?;
+            assert!(db.compact("t1").await?);
+        }
+
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("v3".to_string()));
+            assert_eq!(read(&view, "k2").await?, Some("v4".to_string()));
+            assert_eq!(read(&view, "k3").await?, Some("v5".to_string()));
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn binary_format_reopen() -> Result<()> {
+    let dir = temp_dir("binary_format_reopen");
+    let binary_config = || db::DbConfig {
+        log_format: db::LogFormat::Binary,
+        .. config(Some(dir.clone()))
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(binary_config()).await?;
+            write(&db, "k1", "v1").await?;
+            write(&db, "k2", "v2").await?;
+            assert!(db.compact("t1").await?);
+            write(&db, "k1", "v3").await?;
+            db.sync().await?;
+        }
+
+        assert!(dir.join("commits.0.bin").exists());
+        assert!(!dir.join("commits.0.toml").exists());
+
+        {
+            let db = db::Db::open(binary_config()).await?;
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("v3".to_string()));
+            assert_eq!(read(&view, "k2").await?, Some("v2".to_string()));
+        }
+
+        assert!(db::Db::open(config(Some(dir.clone()))).await.is_err());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn old_layout_rejected() -> Result<()> {
+    let dir = temp_dir("old_layout_rejected");
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("t1.toml"), b"")?;
+    block_on(async {
+        let r = db::Db::open(config(Some(dir.clone()))).await;
+        assert!(matches!(r, Err(e) if e.to_string().contains("unsegmented log layout")));
+        assert!(dir.join("t1.toml").exists());
+        Ok(())
+    })
+}
+
+fn last_segment(dir: &std::path::Path, name: &str, ext: &str) -> Result<std::path::PathBuf> {
+    let mut last = None;
+    for entry in std::fs::read_dir(dir)? {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        let segment = file_name.strip_prefix(&format!("{}.", name))
+            .and_then(|f| f.strip_suffix(&format!(".{}", ext)))
+            .and_then(|s| s.parse::<u64>().ok());
+        last = last.max(segment);
+    }
+    let last = last.expect("segment");
+    Ok(dir.join(format!("{}.{}.{}", name, last, ext)))
+}
+
+fn append_bytes(path: &std::path::Path, bytes: &[u8]) -> Result<()> {
+    use std::io::Write;
+    let mut file = std::fs::OpenOptions::new().append(true).open(path)?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+#[test]
+fn torn_write_truncated() -> Result<()> {
+    let dir = temp_dir("torn_write_truncated");
+    let truncating_config = || db::DbConfig {
+        truncate_torn_writes: true,
+        .. config(Some(dir.clone()))
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            write(&db, "k1", "v1").await?;
+            write(&db, "k2", "v2").await?;
+            db.sync().await?;
+        }
+
+        append_bytes(&last_segment(&dir.join("t1"), "0", "toml")?, b"[[frames]] # HEADER\nlength = 1")?;
+        append_bytes(&last_segment(&dir, "commits", "toml")?, b"[[frames]] # HEA")?;
+
+        assert!(db::Db::open(config(Some(dir.clone()))).await.is_err());
+
+        {
+            let db = db::Db::open(truncating_config()).await?;
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("v1".to_string()));
+            assert_eq!(read(&view, "k2").await?, Some("v2".to_string()));
+            write(&db, "k3", "v3").await?;
+        }
+
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            let view = db.read_view();
+            assert_eq!(read(&view, "k3").await?, Some("v3".to_string()));
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn torn_commit_discarded() -> Result<()> {
+    let dir = temp_dir("torn_commit_discarded");
+    let binary_config = || db::DbConfig {
+        log_format: db::LogFormat::Binary,
+        truncate_torn_writes: true,
+        .. config(Some(dir.clone()))
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(binary_config()).await?;
+            write(&db, "k1", "v1").await?;
+            write(&db, "k1", "v2").await?;
+            db.sync().await?;
+        }
+
+        // Tear the last commit record
+        let commits = last_segment(&dir, "commits", "bin")?;
+        let len = std::fs::metadata(&commits)?.len();
+        std::fs::OpenOptions::new().write(true).open(&commits)?.set_len(len - 1)?;
+
+        {
+            let db = db::Db::open(binary_config()).await?;
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("v1".to_string()));
+            write(&db, "k2", "v3").await?;
+        }
+
+        {
+            let db = db::Db::open(binary_config()).await?;
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("v1".to_string()));
+            assert_eq!(read(&view, "k2").await?, Some("v3".to_string()));
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn corrupt_frame_not_truncated() -> Result<()> {
+    let dir = temp_dir("corrupt_frame_not_truncated");
+    let binary_config = || db::DbConfig {
+        log_format: db::LogFormat::Binary,
+        truncate_torn_writes: true,
+        .. config(Some(dir.clone()))
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(binary_config()).await?;
+            write(&db, "k1", "v1").await?;
+            write(&db, "k2", "v2").await?;
+            db.sync().await?;
+        }
+
+        // Flip a bit in the body of the first commit record
+        let commits = dir.join("commits.0.bin");
+        let mut bytes = std::fs::read(&commits)?;
+        bytes[12] ^= 1;
+        std::fs::write(&commits, &bytes)?;
+
+        assert!(db::Db::open(binary_config()).await.is_err());
+        assert_eq!(std::fs::read(&commits)?, bytes);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn corrupt_frame_length_not_truncated() -> Result<()> {
+    let dir = temp_dir("corrupt_frame_length_not_truncated");
+    let binary_config = || db::DbConfig {
+        log_format: db::LogFormat::Binary,
+        truncate_torn_writes: true,
+        .. config(Some(dir.clone()))
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(binary_config()).await?;
+            write(&db, "k1", "v1").await?;
+            write(&db, "k2", "v2").await?;
+            db.sync().await?;
+        }
+
+        // Make the first commit record claim to run past the end of the log
+        let commits = dir.join("commits.0.bin");
+        let mut bytes = std::fs::read(&commits)?;
+        bytes[1] ^= 1;
+        std::fs::write(&commits, &bytes)?;
+
+        assert!(db::Db::open(binary_config()).await.is_err());
+        assert_eq!(std::fs::read(&commits)?, bytes);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn write_after_reopen() -> Result<()> {
+    let dir = temp_dir("write_after_reopen");
+    block_on(async {
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            write(&db, "k1", "v1").await?;
+        }
+
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            write(&db, "k2", "v2").await?;
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("v1".to_string()));
+            assert_eq!(read(&view, "k2").await?, Some("v2".to_string()));
+        }
+
+        Ok(())
+    })
+}
+
+fn segments(dir: &std::path::Path, name: &str, ext: &str) -> Result<usize> {
+    let mut count = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        if file_name.starts_with(&format!("{}.", name)) && file_name.ends_with(&format!(".{}", ext)) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[test]
+fn segments_roll_over() -> Result<()> {
+    let dir = temp_dir("segments_roll_over");
+    let keys: Vec<_> = (0..50).map(|i| format!("k{}", i)).collect();
+    block_on(async {
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            for key in &keys {
+                write(&db, key, key).await?;
+            }
+            db.sync().await?;
+        }
+
+        assert!(segments(&dir.join("t1"), "0", "toml")? > 1);
+        assert!(segments(&dir, "commits", "toml")? > 1);
+
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            let view = db.read_view();
+            for key in &keys {
+                assert_eq!(read(&view, key).await?, Some(key.to_string()));
+            }
+            assert!(db.compact("t1").await?);
+        }
+
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            let view = db.read_view();
+            for key in &keys {
+                assert_eq!(read(&view, key).await?, Some(key.to_string()));
+            }
+        }
+
+        // Whole segments of the compacted log are deleted
+        assert_eq!(segments(&dir.join("t1"), "0", "toml")?, 0);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn torn_segment_before_later_segments_not_truncated() -> Result<()> {
+    let dir = temp_dir("torn_segment_before_later_segments_not_truncated");
+    let truncating_config = || db::DbConfig {
+        truncate_torn_writes: true,
+        .. config(Some(dir.clone()))
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            for i in 0..50 {
+                write(&db, "k", &format!("v{}", i)).await?;
+            }
+            db.sync().await?;
+        }
+
+        // Tear the last commit record of the first segment
+        let commits = dir.join("commits.0.toml");
+        let len = std::fs::metadata(&commits)?.len();
+        std::fs::OpenOptions::new().write(true).open(&commits)?.set_len(len - 1)?;
+        let before = segments(&dir, "commits", "toml")?;
+        assert!(before > 1);
+
+        assert!(db::Db::open(truncating_config()).await.is_err());
+        assert_eq!(segments(&dir, "commits", "toml")?, before);
+        assert_eq!(std::fs::metadata(&commits)?.len(), len - 1);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn corrupt_middle_frame_not_truncated() -> Result<()> {
+    let dir = temp_dir("corrupt_middle_frame_not_truncated");
+    let truncating_config = || db::DbConfig {
+        truncate_torn_writes: true,
+        log_segment_size: 1 << 20,
+        .. config(Some(dir.clone()))
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(truncating_config()).await?;
+            write(&db, "k1", "v1").await?;
+            write(&db, "k2", "v2").await?;
+            db.sync().await?;
+        }
+
+        // Make the first frame claim to run past the end of the log
+        let log = last_segment(&dir.join("t1"), "0", "toml")?;
+        let text = std::fs::read_to_string(&log)?;
+        let corrupt = text.replacen("length = ", "length = 9999", 1);
+        std::fs::write(&log, &corrupt)?;
+
+        assert!(db::Db::open(truncating_config()).await.is_err());
+        assert_eq!(std::fs::read_to_string(&log)?, corrupt);
+
+        Ok(())
+    })
+}
+
+/// Sync one commit, make another, crash,
+/// and return which of the two survived.
+fn crash_after_commits(name: &str, durability: db::Durability, wait: std::time::Duration) -> Result<(bool, bool)> {
+    let dir = temp_dir(name);
+    let durable_config = || db::DbConfig {
+        durability: durability.clone(),
+        .. config(Some(dir.clone()))
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(durable_config()).await?;
+            write(&db, "k1", "v1").await?;
+            db.sync().await?;
+            write(&db, "k2", "v2").await?;
+            std::thread::sleep(wait);
+            db.simulate_crash().await?;
+        }
+
+        let db = db::Db::open(durable_config()).await?;
+        let view = db.read_view();
+        Ok((read(&view, "k1").await?.is_some(),
+            read(&view, "k2").await?.is_some()))
+    })
+}
+
+#[test]
+fn crash_durability_manual() -> Result<()> {
+    let survived = crash_after_commits("crash_durability_manual",
+                                       db::Durability::Manual,
+                                       std::time::Duration::from_millis(0))?;
+    assert_eq!(survived, (true, false));
+    Ok(())
+}
+
+#[test]
+fn crash_durability_sync_on_commit() -> Result<()> {
+    let survived = crash_after_commits("crash_durability_sync_on_commit",
+                                       db::Durability::SyncOnCommit,
+                                       std::time::Duration::from_millis(0))?;
+    assert_eq!(survived, (true, true));
+    Ok(())
+}
+
+#[test]
+fn crash_durability_periodic() -> Result<()> {
+    let hour = std::time::Duration::from_secs(60 * 60);
+    let wait = std::time::Duration::from_millis(500);
+
+    let survived = crash_after_commits("crash_durability_periodic_interval",
+                                       db::Durability::Periodic {
+                                           interval: std::time::Duration::from_millis(10),
+                                           bytes: u64::MAX,
+                                       }, wait)?;
+    assert_eq!(survived, (true, true));
+
+    let survived = crash_after_commits("crash_durability_periodic_bytes",
+                                       db::Durability::Periodic {
+                                           interval: hour,
+                                           bytes: 1,
+                                       }, wait)?;
+    assert_eq!(survived, (true, true));
+
+    let survived = crash_after_commits("crash_durability_periodic_none",
+                                       db::Durability::Periodic {
+                                           interval: hour,
+                                           bytes: u64::MAX,
+                                       }, wait)?;
+    assert_eq!(survived, (true, false));
+
+    Ok(())
+}
+
+#[test]
+fn crash_durability_periodic_mem_disk() -> Result<()> {
+    let hour = std::time::Duration::from_secs(60 * 60);
+    let durabilities = vec![
+        db::Durability::Periodic { interval: std::time::Duration::from_millis(10), bytes: u64::MAX },
+        db::Durability::Periodic { interval: hour, bytes: 1 },
+    ];
+    for durability in durabilities {
+        let disk = db::MemDisk::new();
+        let mem_config = || db::DbConfig {
+            mem_disk: Some(disk.clone()),
+            durability: durability.clone(),
+            .. config(None)
+        };
+        block_on(async {
+            {
+                let db = db::Db::open(mem_config()).await?;
+                write(&db, "k1", "v1").await?;
+                std::thread::sleep(std::time::Duration::from_millis(500));
+                db.simulate_crash().await?;
+            }
+
+            let db = db::Db::open(mem_config()).await?;
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("v1".to_string()));
+            Ok::<_, anyhow::Error>(())
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_discards_unsynced_commits() -> Result<()> {
+    let dir = temp_dir("crash_discards_unsynced_commits");
+    run(&format!("
+path {}
+write t1 k1 v1
+sync
+write t1 k2 v2
+crash
+read-assert t1 k1 v1
+read-assert t1 k2 <none>
+write t1 k3 v3
+sync
+crash
+read-assert t1 k3 v3
+", dir.display()))
+}
+
+#[test]
+fn failed_append_recovers_prefix() -> Result<()> {
+    let sync_config = |dir| db::DbConfig {
+        durability: db::Durability::SyncOnCommit,
+        .. config(Some(dir))
+    };
+    block_on(async {
+        for fail in 0..40 {
+            let dir = temp_dir(&format!("failed_append_recovers_prefix_{}", fail));
+            let faults = db::raw::faulty_log_file::Faults::new();
+            let mut acked = 0;
+            {
+                let db = db::Db::open_with_faults(sync_config(dir.clone()), faults.clone()).await?;
+                faults.fail_append(faults.appends() + fail);
+                for i in 0..10 {
+                    let r = write(&db, &format!("k{}", i), "v").await;
+                    if r.is_err() {
+                        break;
+                    }
+                    acked += 1;
+                }
+                faults.crash().await?;
+            }
+
+            let db = db::Db::open_with_faults(sync_config(dir), faults).await?;
+            let (recovered, _) = keys(&db.read_view()).await?;
+            let expected: Vec<_> = (0..recovered.len()).map(|i| format!("k{}", i)).collect();
+            assert_eq!(recovered, expected);
+            assert!(recovered.len() >= acked);
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn corrupt_commit_fails_open() -> Result<()> {
+    let dir = temp_dir("corrupt_commit_fails_open");
+    block_on(async {
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            write(&db, "k1", "v1").await?;
+            db.sync().await?;
+        }
+
+        let faults = db::raw::faulty_log_file::Faults::new();
+        faults.corrupt("commits", db::raw::types::Address { segment: 0, offset: 0 });
+        let r = db::Db::open_with_faults(config(Some(dir)), faults).await;
+        assert!(r.is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn mem_crash_discards_unsynced_commits() -> Result<()> {
+    run("
+mem
+write t1 k1 v1
+sync
+write t1 k2 v2
+crash
+read-assert t1 k1 v1
+read-assert t1 k2 <none>
+")
+}
+
+#[test]
+fn mem_reopen() -> Result<()> {
+    let disk = db::MemDisk::new();
+    let mem_config = || db::DbConfig {
+        mem_disk: Some(disk.clone()),
+        .. config(None)
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(mem_config()).await?;
+            write(&db, "k1", "v1").await?;
+            write(&db, "k2", "v2").await?;
+            assert!(db.compact("t1").await?);
+            write(&db, "k1", "v3").await?;
+        }
+
+        let db = db::Db::open(mem_config()).await?;
+        let view = db.read_view();
+        assert_eq!(read(&view, "k1").await?, Some("v3".to_string()));
+        assert_eq!(read(&view, "k2").await?, Some("v2".to_string()));
+        Ok(())
+    })
+}
+
+/// Run random writes, syncs, and compactions,
+/// then lose power and check that the database recovers
+/// to its state after some commit no earlier than the last sync.
+///
+/// Compaction syncs the live data, so may keep unsynced commits.
+#[test]
+fn mem_random_crashes() -> Result<()> {
+    let mut rng: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move |n: u64| {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        rng % n
+    };
+
+    block_on(async {
+        for _ in 0..200 {
+            let disk = db::MemDisk::new();
+            let mem_config = |disk: &db::MemDisk| db::DbConfig {
+                mem_disk: Some(disk.clone()),
+                .. config(None)
+            };
+            let db = db::Db::open(mem_config(&disk)).await?;
+
+            let mut model = std::collections::BTreeMap::new();
+            // The states since the last sync
+            let mut states = vec![model.clone()];
+            for _ in 0..next(40) {
+                let key = format!("k{}", next(8));
+                match next(10) {
+                    0..=5 => {
+                        let value = format!("v{}", next(100));
+                        write(&db, &key, &value).await?;
+                        model.insert(key, value);
+                        states.push(model.clone());
+                    },
+                    6 => {
+                        let batch = db.write_batch().await?;
+                        batch.tree("t1").delete(key.as_bytes()).await?;
+                        batch.commit().await?;
+                        batch.close().await;
+                        model.remove(&key);
+                        states.push(model.clone());
+                    },
+                    7 | 8 => {
+                        db.sync().await?;
+                        states = vec![model.clone()];
+                    },
+                    _ => {
+                        db.compact("t1").await?;
+                    },
+                }
+            }
+
+            let db = db::Db::open(mem_config(&disk.durable_snapshot())).await?;
+            let view = db.read_view();
+            let (recovered_keys, _) = keys(&view).await?;
+            let mut recovered = std::collections::BTreeMap::new();
+            for key in recovered_keys {
+                let value = read(&view, &key).await?.expect("value");
+                recovered.insert(key, value);
+            }
+            assert!(states.contains(&recovered), "recovered {:?}", recovered);
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn overlapping_range_deletes() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        for key in &["k1", "k2", "k3", "k4", "k5", "k6"] {
+            write(&db, key, "v1").await?;
+        }
+        let delete_range = |start: &'static str, end: &'static str| {
+            let db = db.clone();
+            async move {
+                let batch = db.write_batch().await?;
+                batch.tree("t1").delete_range(start.as_bytes(), end.as_bytes()).await?;
+                batch.commit().await?;
+                batch.close().await;
+                Ok::<_, anyhow::Error>(())
+            }
+        };
+
+        delete_range("k2", "k4").await?;
+        let view1 = db.read_view();
+        write(&db, "k3", "v2").await?;
+        delete_range("k3", "k6").await?;
+        let view2 = db.read_view();
+        write(&db, "k4", "v2").await?;
+        delete_range("k1", "k1").await?;
+        delete_range("k0", "k2").await?;
+        let view3 = db.read_view();
+
+        assert_eq!(keys(&view1).await?.0, ["k1", "k4", "k5", "k6"]);
+        assert_eq!(keys(&view2).await?.0, ["k1", "k6"]);
+        assert_eq!(keys(&view3).await?.0, ["k4", "k6"]);
+        assert_eq!(read(&view3, "k4").await?, Some("v2".to_string()));
+        assert_eq!(read(&view3, "k3").await?, None);
+        Ok(())
+    })
+}
+
+#[test]
+fn collect_garbage_keeps_live_views() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        write(&db, "k1", "v1").await?;
+        let view = db.read_view();
+        write(&db, "k1", "v2").await?;
+        write(&db, "k1", "v3").await?;
+
+        // Nothing is older than the view
+        assert_eq!(db.collect_garbage(), 0);
+        assert_eq!(read(&view, "k1").await?, Some("v1".to_string()));
+        assert_eq!(read(&db.read_view(), "k1").await?, Some("v3".to_string()));
+
+        // A cursor keeps history after its view is dropped
+        let mut cursor = view.tree("t1").cursor()?;
+        drop(view);
+        assert_eq!(db.collect_garbage(), 0);
+        cursor.seek_first();
+        assert_eq!(cursor.value().await?, b"v1");
+        drop(cursor);
+
+        assert_eq!(db.collect_garbage(), 2);
+        assert_eq!(db.collect_garbage(), 0);
+        assert_eq!(read(&db.read_view(), "k1").await?, Some("v3".to_string()));
+        Ok(())
+    })
+}
+
+#[test]
+fn collect_garbage_range_deletes() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        for key in &["k1", "k2", "k3"] {
+            write(&db, key, "v1").await?;
+        }
+        for (start, end) in &[("k1", "k3"), ("k2", "k4"), ("k0", "k5")] {
+            let batch = db.write_batch().await?;
+            batch.tree("t1").delete_range(start.as_bytes(), end.as_bytes()).await?;
+            batch.commit().await?;
+            batch.close().await;
+        }
+        write(&db, "k2", "v2").await?;
+
+        // The three versions of v1 are hidden by the range deletes,
+        // which with nothing left to hide are all discarded
+        assert_eq!(db.collect_garbage(), 6);
+        assert_eq!(keys(&db.read_view()).await?.0, ["k2"]);
+        Ok(())
+    })
+}
+
+#[test]
+fn collect_garbage_after_deleting_everything() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        for key in &["k1", "k2", "k3"] {
+            write(&db, key, "v1").await?;
+        }
+        let batch = db.write_batch().await?;
+        batch.tree("t1").delete(b"k1").await?;
+        batch.tree("t1").delete_range(b"k2", b"k4").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        db.collect_garbage();
+        assert_eq!(db.index_entries("t1")?, 0);
+        assert_eq!(keys(&db.read_view()).await?.0, Vec::<String>::new());
+
+        // Deletes still hide the compacted tree
+        write(&db, "k1", "v1").await?;
+        write(&db, "k2", "v1").await?;
+        assert!(db.compact("t1").await?);
+        let batch = db.write_batch().await?;
+        batch.tree("t1").delete(b"k1").await?;
+        batch.tree("t1").delete_range(b"k2", b"k3").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        db.collect_garbage();
+        let view = db.read_view();
+        assert_eq!(read(&view, "k1").await?, None);
+        assert_eq!(read(&view, "k2").await?, None);
+        Ok(())
+    })
+}
+
+#[test]
+fn nested_range_deletes_at_past_views() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        let delete_range = |start: &'static str, end: &'static str| {
+            let db = &db;
+            async move {
+                let batch = db.write_batch().await?;
+                batch.tree("t1").delete_range(start.as_bytes(), end.as_bytes()).await?;
+                batch.commit().await?;
+                batch.close().await;
+                Ok::<_, anyhow::Error>(())
+            }
+        };
+        for key in &["k1", "k2", "k3"] {
+            write(&db, key, "v1").await?;
+        }
+        let mut views = vec![db.read_view()];
+        delete_range("k0", "k5").await?;
+        views.push(db.read_view());
+        write(&db, "k2", "v2").await?;
+        views.push(db.read_view());
+        delete_range("k2", "k3").await?;
+        views.push(db.read_view());
+        write(&db, "k3", "v3").await?;
+        delete_range("k1", "k4").await?;
+        views.push(db.read_view());
+
+        let expected = [
+            vec!["k1", "k2", "k3"],
+            vec![],
+            vec!["k2"],
+            vec![],
+            vec![],
+        ];
+        for _ in 0..2 {
+            for (view, expected) in views.iter().zip(&expected) {
+                assert_eq!(&keys(view).await?.0, expected);
+            }
+            assert_eq!(read(&views[2], "k2").await?, Some("v2".to_string()));
+            db.collect_garbage();
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn create_and_drop_trees() -> Result<()> {
+    let dir = temp_dir("create_and_drop_trees");
+    run(&format!("
+path {}
+create-tree t3
+write t3 k1 v1
+write t2 k1 v1
+drop-tree t2
+create-tree t2
+read-assert t2 k1 <none>
+read-assert t3 k1 v1
+", dir.display()))
+}
+
+#[test]
+fn created_trees_reopen() -> Result<()> {
+    let dir = temp_dir("created_trees_reopen");
+    let no_trees = || db::DbConfig {
+        trees: vec![],
+        .. config(Some(dir.clone()))
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+
+            // A batch from before the tree was created doesn't write to it
+            let batch = db.write_batch().await?;
+            batch.tree("t1").write(b"k1", b"v1").await?;
+            db.create_tree("t3").await?;
+            assert!(batch.tree("t3").write(b"k1", b"v1").await.is_err());
+            batch.commit().await?;
+            batch.close().await;
+
+            let batch = db.write_batch().await?;
+            batch.tree("t3").write(b"k2", b"v2").await?;
+            batch.commit().await?;
+            batch.close().await;
+
+            assert!(dir.join("t2").exists());
+            db.drop_tree("t2").await?;
+            assert!(!dir.join("t2").exists());
+            assert!(db.create_tree("t3").await.is_err());
+            assert!(db.create_tree("commits").await.is_err());
+            db.sync().await?;
+        }
+
+        let db = db::Db::open(no_trees()).await?;
+        assert_eq!(db.tree_names(), ["t1", "t3"]);
+        let view = db.read_view();
+        assert_eq!(read(&view, "k1").await?, Some("v1".to_string()));
+        assert_eq!(view.tree("t3").read(b"k2").await?, Some(b"v2".to_vec()));
+        assert!(view.tree("t2").read(b"k1").await.is_err());
+        assert!(view.tree("t2").cursor().is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn dropped_tree_batches_fail() -> Result<()> {
+    let dir = temp_dir("dropped_tree_batches_fail");
+    block_on(async {
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+
+            // A batch that wrote to a dropped tree doesn't write to its successor
+            let stale = db.write_batch().await?;
+            stale.tree("t1").write(b"k1", b"v1").await?;
+            let only_dropped = db.write_batch().await?;
+            only_dropped.tree("t2").write(b"k1", b"v1").await?;
+            db.drop_tree("t1").await?;
+            db.drop_tree("t2").await?;
+            db.create_tree("t1").await?;
+            // Its logs are apart from the dropped tree's
+            assert!(!dir.join("t1").exists());
+            assert!(stale.tree("t1").write(b"k2", b"v2").await.is_err());
+            assert!(stale.commit().await.is_err());
+            stale.close().await;
+
+            // Nor commits when it only wrote to the dropped tree
+            assert!(only_dropped.commit().await.is_err());
+            only_dropped.close().await;
+
+            let batch = db.write_batch().await?;
+            batch.tree("t1").write(b"k3", b"v3").await?;
+            batch.commit().await?;
+            batch.close().await;
+            db.sync().await?;
+        }
+
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        let view = db.read_view();
+        assert_eq!(keys(&view).await?.0, ["k3"]);
+        assert_eq!(view.tree("t2").read(b"k1").await?, None);
+        Ok(())
+    })
+}
+
+#[test]
+fn batches_open_only_written_trees() -> Result<()> {
+    let dir = temp_dir("batches_open_only_written_trees");
+    let log_bytes = |tree: &str| -> Result<u64> {
+        let mut bytes = 0;
+        for entry in std::fs::read_dir(dir.join(tree))? {
+            bytes += entry?.metadata()?.len();
+        }
+        Ok(bytes)
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            write(&db, "k1", "v1").await?;
+            db.sync().await?;
+            assert!(log_bytes("t1")? > 0);
+            assert_eq!(log_bytes("t2")?, 0);
+
+            // A tree opened after a save point is rolled back with it
+            let batch = db.write_batch().await?;
+            batch.tree("t1").write(b"k2", b"v2").await?;
+            batch.push_save_point().await?;
+            batch.tree("t2").write(b"k3", b"v3").await?;
+            batch.rollback_save_point().await?;
+            batch.tree("t2").write(b"k4", b"v4").await?;
+            assert!(batch.rollback_save_point().await.is_err());
+            batch.commit().await?;
+            batch.close().await;
+            db.sync().await?;
+        }
+
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        let view = db.read_view();
+        assert_eq!(keys(&view).await?.0, ["k1", "k2"]);
+        assert_eq!(view.tree("t2").read(b"k3").await?, None);
+        assert_eq!(view.tree("t2").read(b"k4").await?, Some(b"v4".to_vec()));
+        Ok(())
+    })
+}
+
+#[test]
+fn transaction_conflicts() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        write(&db, "k1", "v1").await?;
+        write(&db, "k5", "v5").await?;
+
+        let is_conflict = |r: Result<db::Commit>| {
+            matches!(r.err(), Some(e) if e.downcast_ref::<db::Conflict>().is_some())
+        };
+
+        // Reads of keys written since the snapshot conflict
+        let tx = db.transaction().await?;
+        assert_eq!(tx.tree("t1").read(b"k1").await?, Some(b"v1".to_vec()));
+        write(&db, "k1", "v2").await?;
+        tx.tree("t1").write(b"k2", b"v2").await?;
+        assert!(is_conflict(tx.commit().await));
+        tx.abort().await;
+        tx.close().await;
+        assert_eq!(read(&db.read_view(), "k2").await?, None);
+
+        // Including keys that didn't exist
+        let tx = db.transaction().await?;
+        assert_eq!(tx.tree("t1").read(b"k3").await?, None);
+        write(&db, "k3", "v3").await?;
+        assert!(is_conflict(tx.commit().await));
+        tx.abort().await;
+        tx.close().await;
+
+        // But not writes to other keys
+        let tx = db.transaction().await?;
+        tx.tree("t1").read(b"k1").await?;
+        write(&db, "k4", "v4").await?;
+        let value = tx.tree("t1").read(b"k1").await?.expect("value");
+        tx.tree("t1").write(b"k1", &[&value[..], b"!"].concat()).await?;
+        tx.commit().await?;
+        assert!(tx.commit().await.is_err());
+        tx.close().await;
+        assert_eq!(read(&db.read_view(), "k1").await?, Some("v2!".to_string()));
+
+        // Cursors conflict with writes anywhere in the range they moved over
+        let tx = db.transaction().await?;
+        let mut cursor = tx.tree("t1").cursor().await?;
+        cursor.seek_key(b"k2");
+        assert_eq!(cursor.key(), b"k3");
+        cursor.next();
+        assert_eq!(cursor.key(), b"k4");
+        write(&db, "k5", "v6").await?;
+        write(&db, "k0", "v0").await?;
+        tx.tree("t1").write(b"k6", b"v6").await?;
+        tx.commit().await?;
+        tx.close().await;
+
+        let tx = db.transaction().await?;
+        let mut cursor = tx.tree("t1").cursor().await?;
+        cursor.seek_key(b"k2");
+        cursor.next();
+        write(&db, "k35", "v35").await?;
+        assert!(is_conflict(tx.commit().await));
+        tx.abort().await;
+        tx.close().await;
+
+        // And with range deletes over what they read
+        let tx = db.transaction().await?;
+        tx.tree("t1").read(b"k4").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1").delete_range(b"k3", b"k5").await?;
+        batch.commit().await?;
+        batch.close().await;
+        assert!(is_conflict(tx.commit().await));
+        tx.abort().await;
+        tx.close().await;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn transaction_conflict_aborts() -> Result<()> {
+    let dir = temp_dir("transaction_conflict_aborts");
+    block_on(async {
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            write(&db, "k1", "v1").await?;
+
+            let tx = db.transaction().await?;
+            tx.tree("t1").read(b"k1").await?;
+            tx.tree("t1").write(b"k2", b"v2").await?;
+            write(&db, "k1", "v3").await?;
+            let r = tx.commit().await;
+            assert!(r.err().and_then(|e| e.downcast::<db::Conflict>().ok()).is_some());
+            // Already aborted, so it can't be committed
+            assert!(tx.commit().await.is_err());
+            tx.close().await;
+            assert_eq!(read(&db.read_view(), "k2").await?, None);
+
+            // Later batches aren't held up by it
+            write(&db, "k3", "v3").await?;
+            db.sync().await?;
+        }
+
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("v3".to_string()));
+            assert_eq!(read(&view, "k2").await?, None);
+            assert_eq!(read(&view, "k3").await?, Some("v3".to_string()));
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn transaction_reads_own_writes() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        write(&db, "k1", "v1").await?;
+        write(&db, "k2", "v2").await?;
+
+        let tx = db.transaction().await?;
+        let tree = tx.tree("t1");
+        tree.write(b"k1", b"v1!").await?;
+        tree.delete(b"k2").await?;
+        tree.write(b"k3", b"v3").await?;
+        assert_eq!(tree.read(b"k1").await?, Some(b"v1!".to_vec()));
+        assert_eq!(tree.read(b"k2").await?, None);
+        assert_eq!(tree.read(b"k3").await?, Some(b"v3".to_vec()));
+
+        let mut cursor = tree.cursor().await?;
+        cursor.seek_first();
+        assert_eq!(cursor.key(), b"k1");
+        assert_eq!(cursor.value().await?, b"v1!");
+        cursor.next();
+        assert_eq!(cursor.key(), b"k3");
+        cursor.next();
+        assert!(!cursor.valid());
+
+        // Rolled back writes aren't seen
+        tx.push_save_point().await?;
+        tree.write(b"k1", b"v1?").await?;
+        tx.rollback_save_point().await?;
+        assert_eq!(tree.read(b"k1").await?, Some(b"v1!".to_vec()));
+
+        // Reads of its own writes are still recorded
+        write(&db, "k3", "v4").await?;
+        let r = tx.commit().await;
+        assert!(r.err().and_then(|e| e.downcast::<db::Conflict>().ok()).is_some());
+        tx.abort().await;
+        tx.close().await;
+
+        let view = db.read_view();
+        assert_eq!(read(&view, "k1").await?, Some("v1".to_string()));
+        assert_eq!(read(&view, "k2").await?, Some("v2".to_string()));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn locks_wait_for_release() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        let b1 = db.write_batch().await?;
+        let b2 = db.write_batch().await?;
+        let b3 = db.write_batch().await?;
+        b1.tree("t1").lock(b"k1", db::LockMode::Exclusive).await?;
+        // Other trees and keys aren't locked
+        b2.tree("t2").lock(b"k1", db::LockMode::Exclusive).await?;
+        b2.tree("t1").lock_range(b"k2", b"k3", db::LockMode::Exclusive).await?;
+
+        let released = std::cell::Cell::new(false);
+        let wait = async {
+            b2.tree("t1").lock(b"k1", db::LockMode::Shared).await?;
+            assert!(released.get());
+            // Shared locks don't exclude each other
+            b3.tree("t1").lock_range(b"k0", b"k2", db::LockMode::Shared).await?;
+            Ok::<_, anyhow::Error>(())
+        };
+        let release = async {
+            b1.tree("t1").write(b"k1", b"v1").await?;
+            b1.commit().await?;
+            released.set(true);
+            Ok::<_, anyhow::Error>(())
+        };
+        let (waited, released) = futures::join!(wait, release);
+        waited?;
+        released?;
+
+        b1.close().await;
+        b2.close().await;
+        b3.close().await;
+        Ok(())
+    })
+}
+
+#[test]
+fn lock_deadlock_and_timeout() -> Result<()> {
+    let is_lock_error = |r: Result<()>| {
+        r.err().and_then(|e| e.downcast::<db::LockError>().ok())
+    };
+    block_on(async {
+        let db = db::Db::open(db::DbConfig {
+            lock_timeout: Some(std::time::Duration::from_millis(10)),
+            .. config(None)
+        }).await?;
+
+        let b1 = db.write_batch().await?;
+        let b2 = db.write_batch().await?;
+        b1.tree("t1").lock(b"k1", db::LockMode::Exclusive).await?;
+        b2.tree("t1").lock(b"k2", db::LockMode::Exclusive).await?;
+
+        // The second waiter of the cycle fails, releasing its locks
+        let first = async {
+            b1.tree("t1").lock(b"k2", db::LockMode::Exclusive).await
+        };
+        let second = async {
+            let r = b2.tree("t1").lock(b"k1", db::LockMode::Exclusive).await;
+            assert!(matches!(is_lock_error(r), Some(db::LockError::Deadlock)));
+            b2.close().await;
+        };
+        let (first, ()) = futures::join!(first, second);
+        first?;
+
+        let b3 = db.write_batch().await?;
+        let r = b3.tree("t1").lock_range(b"k0", b"k9", db::LockMode::Exclusive).await;
+        assert!(matches!(is_lock_error(r), Some(db::LockError::Timeout)));
+        b1.abort().await;
+        b3.tree("t1").lock_range(b"k0", b"k9", db::LockMode::Exclusive).await?;
+
+        b1.close().await;
+        b3.close().await;
+        Ok(())
+    })
+}
+
+#[test]
+fn batch_reads_own_writes() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        for key in &["k1", "k2", "k3", "k4", "k5"] {
+            write(&db, key, "v").await?;
+        }
+
+        let view = db.read_view();
+        let batch = db.write_batch().await?;
+        let tree = batch.tree("t1");
+        tree.write(b"k2", b"v2").await?;
+        tree.delete(b"k3").await?;
+        tree.delete_range(b"k4", b"k5").await?;
+        tree.write(b"k45", b"v45").await?;
+        batch.push_save_point().await?;
+        tree.write(b"k6", b"v6").await?;
+        tree.delete(b"k1").await?;
+        batch.rollback_save_point().await?;
+        tree.write(b"k0", b"v0").await?;
+
+        assert_eq!(tree.read(&view, b"k2").await?, Some(b"v2".to_vec()));
+        assert_eq!(tree.read(&view, b"k3").await?, None);
+        assert_eq!(tree.read(&view, b"k4").await?, None);
+        assert_eq!(tree.read(&view, b"k5").await?, Some(b"v".to_vec()));
+        assert_eq!(tree.read(&view, b"k6").await?, None);
+        assert_eq!(tree.read(&view, b"k1").await?, Some(b"v".to_vec()));
+        assert_eq!(read(&view, "k2").await?, Some("v".to_string()));
+
+        let mut cursor = tree.cursor(&view).await?;
+        let mut forward = vec![];
+        cursor.seek_first();
+        while cursor.valid() {
+            forward.push((String::from_utf8(cursor.key())?, String::from_utf8(cursor.value().await?)?));
+            cursor.next();
+        }
+        let mut backward = vec![];
+        cursor.seek_last();
+        while cursor.valid() {
+            backward.push(String::from_utf8(cursor.key())?);
+            cursor.prev();
+        }
+        cursor.seek_key(b"k3");
+        assert_eq!(cursor.key(), b"k45");
+        cursor.seek_key_rev(b"k44");
+        assert_eq!(cursor.key(), b"k2");
+
+        let expected = [("k0", "v0"), ("k1", "v"), ("k2", "v2"), ("k45", "v45"), ("k5", "v")];
+        let expected: Vec<_> = expected.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(forward, expected);
+        backward.reverse();
+        assert_eq!(backward, ["k0", "k1", "k2", "k45", "k5"]);
+
+        // Trees the batch hasn't written read from the view
+        assert_eq!(batch.tree("t2").read(&view, b"k1").await?, None);
+
+        batch.commit().await?;
+        batch.close().await;
+        let (keys, _) = keys(&db.read_view()).await?;
+        assert_eq!(keys, ["k0", "k1", "k2", "k45", "k5"]);
+        Ok(())
+    })
+}
+
+#[test]
+fn merge_operands_fold() -> Result<()> {
+    let dir = temp_dir("merge_operands_fold");
+    let append = db::MergeOperator::new(|_key, existing, operands| {
+        let mut values: Vec<&[u8]> = existing.into_iter().collect();
+        values.extend_from_slice(operands);
+        values.join(&b","[..])
+    });
+    let merge_config = || {
+        let mut config = config(Some(dir.clone()));
+        config.merge_operators.insert("t1".to_string(), append.clone());
+        config
+    };
+    let merge = |db: &db::Db, key: &'static str, operand: &'static str| {
+        let db = db.clone();
+        async move {
+            let batch = db.write_batch().await?;
+            batch.tree("t1").merge(key.as_bytes(), operand.as_bytes()).await?;
+            batch.commit().await?;
+            batch.close().await;
+            Ok::<_, anyhow::Error>(())
+        }
+    };
+    block_on(async {
+        {
+            let db = db::Db::open(merge_config()).await?;
+            write(&db, "k1", "a").await?;
+            merge(&db, "k1", "b").await?;
+            merge(&db, "k2", "x").await?;
+            merge(&db, "k2", "y").await?;
+            write(&db, "k3", "a").await?;
+            let batch = db.write_batch().await?;
+            batch.tree("t1").delete_range(b"k3", b"k4").await?;
+            batch.tree("t1").merge(b"k3", b"c").await?;
+            batch.commit().await?;
+            batch.close().await;
+
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("a,b".to_string()));
+            assert_eq!(read(&view, "k2").await?, Some("x,y".to_string()));
+            assert_eq!(read(&view, "k3").await?, Some("c".to_string()));
+            assert_eq!(keys(&view).await?.0, ["k1", "k2", "k3"]);
+
+            // Merges fold over a batch's own writes and the view beneath them
+            let batch = db.write_batch().await?;
+            let tree = batch.tree("t1");
+            tree.merge(b"k2", b"z").await?;
+            tree.merge(b"k4", b"d").await?;
+            tree.write(b"k5", b"e").await?;
+            tree.merge(b"k5", b"f").await?;
+            assert_eq!(tree.read(&view, b"k2").await?, Some(b"x,y,z".to_vec()));
+            let mut cursor = tree.cursor(&view).await?;
+            let mut values = vec![];
+            cursor.seek_first();
+            while cursor.valid() {
+                values.push(String::from_utf8(cursor.value().await?)?);
+                cursor.next();
+            }
+            assert_eq!(values, ["a,b", "x,y,z", "c", "d", "e,f"]);
+            drop(cursor);
+            assert!(batch.tree("t2").merge(b"k1", b"a").await.is_err());
+            batch.commit().await?;
+            batch.close().await;
+
+            // Operands are kept until compaction folds them
+            db.collect_garbage();
+            assert_eq!(read(&db.read_view(), "k5").await?, Some("e,f".to_string()));
+            assert!(db.compact("t1").await?);
+            merge(&db, "k1", "c").await?;
+            db.sync().await?;
+        }
+
+        let db = db::Db::open(merge_config()).await?;
+        let view = db.read_view();
+        assert_eq!(read(&view, "k1").await?, Some("a,b,c".to_string()));
+        assert_eq!(read(&view, "k2").await?, Some("x,y,z".to_string()));
+        assert_eq!(keys(&view).await?.0, ["k1", "k2", "k3", "k4", "k5"]);
+
+        // Without its merge operator a merged tree can't be read
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        assert!(read(&db.read_view(), "k1").await.is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn conditional_writes_check_on_commit() -> Result<()> {
+    let is_precondition = |r: Result<db::Commit>| {
+        r.err().and_then(|e| e.downcast::<db::Precondition>().ok())
+    };
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        write(&db, "k1", "v1").await?;
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1").write_if(b"k1", Some(b"v1"), b"v2").await?;
+        batch.tree("t1").write_if(b"k2", None, b"v2").await?;
+        batch.commit().await?;
+        batch.close().await;
+        let view = db.read_view();
+        assert_eq!(read(&view, "k1").await?, Some("v2".to_string()));
+        assert_eq!(read(&view, "k2").await?, Some("v2".to_string()));
+
+        // Checked against the value at commit, not when written
+        let b1 = db.write_batch().await?;
+        b1.tree("t1").write_if(b"k1", Some(b"v2"), b"v3").await?;
+        b1.tree("t1").write(b"k3", b"v3").await?;
+        write(&db, "k1", "v4").await?;
+        let precondition = is_precondition(b1.commit().await).expect("precondition");
+        assert_eq!(precondition.tree(), "t1");
+        assert_eq!(precondition.key(), b"k1");
+        b1.close().await;
+        let view = db.read_view();
+        assert_eq!(read(&view, "k1").await?, Some("v4".to_string()));
+        assert_eq!(read(&view, "k3").await?, None);
+
+        // Rolled back conditions aren't checked
+        let batch = db.write_batch().await?;
+        batch.tree("t1").delete_if(b"k2", b"v2").await?;
+        batch.push_save_point().await?;
+        batch.tree("t1").write_if(b"k1", None, b"v5").await?;
+        batch.rollback_save_point().await?;
+        batch.commit().await?;
+        batch.close().await;
+        let view = db.read_view();
+        assert_eq!(read(&view, "k1").await?, Some("v4".to_string()));
+        assert_eq!(read(&view, "k2").await?, None);
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1").delete_if(b"k2", b"v2").await?;
+        assert!(is_precondition(batch.commit().await).is_some());
+        batch.close().await;
+        Ok(())
+    })
+}
+
+#[test]
+fn read_views_at_past_commits() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        assert_eq!(db.read_view().commit(), db::Commit(0));
+
+        let mut commits = vec![];
+        for value in &["v1", "v2", "v3"] {
+            let batch = db.write_batch().await?;
+            batch.tree("t1").write(b"k1", value.as_bytes()).await?;
+            commits.push(batch.commit().await?);
+            batch.close().await;
+        }
+        assert_eq!(commits, [db::Commit(0), db::Commit(1), db::Commit(2)]);
+        assert_eq!(db.read_view().commit(), db::Commit(3));
+
+        let view = db.read_view_at(db::Commit(0))?;
+        assert_eq!(view.commit(), db::Commit(0));
+        assert_eq!(read(&view, "k1").await?, None);
+        drop(view);
+        let view = db.read_view_at(db::Commit(2))?;
+        assert_eq!(read(&view, "k1").await?, Some("v2".to_string()));
+        assert!(db.read_view_at(db::Commit(4)).is_err());
+
+        // Live views keep their history from garbage collection
+        db.collect_garbage();
+        assert_eq!(read(&view, "k1").await?, Some("v2".to_string()));
+        assert!(db.read_view_at(db::Commit(1)).is_err());
+        drop(view);
+        db.collect_garbage();
+        assert!(db.read_view_at(db::Commit(2)).is_err());
+        assert!(db.read_view_at(db::Commit(3)).is_ok());
+
+        write(&db, "k1", "v4").await?;
+        assert!(db.compact("t1").await?);
+        assert!(db.read_view_at(db::Commit(3)).is_err());
+        let view = db.read_view_at(db::Commit(4))?;
+        assert_eq!(read(&view, "k1").await?, Some("v4".to_string()));
+        Ok(())
+    })
+}
+
+#[test]
+fn key_history() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        write(&db, "k1", "a").await?;
+        write(&db, "k2", "a").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1").delete(b"k1").await?;
+        batch.commit().await?;
+        batch.close().await;
+        write(&db, "k1", "b").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1").delete_range(b"k0", b"k2").await?;
+        batch.commit().await?;
+        batch.close().await;
+        write(&db, "k1", "c").await?;
+
+        let history = |values: &[(u64, Option<&str>)]| -> Vec<(db::Commit, Option<Vec<u8>>)> {
+            values.iter().map(|(commit, value)| {
+                (db::Commit(*commit), value.map(|v| v.as_bytes().to_vec()))
+            }).collect()
+        };
+        let view = db.read_view();
+        assert_eq!(view.tree("t1").history(b"k1").await?,
+                   history(&[(0, Some("a")), (2, None), (3, Some("b")), (4, None), (5, Some("c"))]));
+        let view = db.read_view_at(db::Commit(4))?;
+        assert_eq!(view.tree("t1").history(b"k1").await?,
+                   history(&[(0, Some("a")), (2, None), (3, Some("b"))]));
+        assert_eq!(view.tree("t1").history(b"k3").await?, history(&[]));
+        drop(view);
+
+        assert!(db.compact("t1").await?);
+        write(&db, "k1", "d").await?;
+        let view = db.read_view();
+        assert_eq!(view.tree("t1").history(b"k1").await?,
+                   history(&[(5, Some("c")), (6, Some("d"))]));
+        Ok(())
+    })
+}
+
+#[test]
+fn subscriptions_receive_and_replay_commits() -> Result<()> {
+    use futures::StreamExt;
+
+    block_on(async {
+        let dir = temp_dir("subscriptions");
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        let mut changes = db.subscribe().await?;
+
+        write(&db, "k1", "a").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1").delete(b"k1").await?;
+        batch.push_save_point().await?;
+        batch.tree("t1").write(b"k2", b"b").await?;
+        batch.rollback_save_point().await?;
+        batch.tree("t2").delete_range(b"k0", b"k9").await?;
+        batch.tree("t2").write(b"k3", b"c").await?;
+        batch.commit().await?;
+        batch.close().await;
+
+        let first = changes.next().await.expect("change");
+        assert_eq!(first.commit, db::Commit(0));
+        assert_eq!(first.trees.len(), 1);
+        assert_eq!(first.trees["t1"], vec![db::Change::Write {
+            key: b"k1".to_vec(), value: b"a".to_vec(),
+        }]);
+        let second = changes.next().await.expect("change");
+        assert_eq!(second.commit, db::Commit(1));
+        assert_eq!(second.trees["t1"], vec![db::Change::Delete {
+            key: b"k1".to_vec(),
+        }]);
+        assert_eq!(second.trees["t2"], vec![db::Change::DeleteRange {
+            start_key: b"k0".to_vec(), end_key: b"k9".to_vec(),
+        }, db::Change::Write {
+            key: b"k3".to_vec(), value: b"c".to_vec(),
+        }]);
+        drop(changes);
+
+        // Resuming replays the logs, then continues with new commits
+        let mut resumed = db.subscribe_from(db::Commit(1)).await?;
+        write(&db, "k4", "d").await?;
+        assert_eq!(resumed.next().await.expect("change"), second);
+        let third = resumed.next().await.expect("change");
+        assert_eq!(third.commit, db::Commit(2));
+        drop(resumed);
+        assert!(db.subscribe_from(db::Commit(4)).await.is_err());
+
+        db.sync().await?;
+        drop(db);
+        let db = db::Db::open(config(Some(dir))).await?;
+        let resumed = db.subscribe_from(db::Commit(0)).await?;
+        let replayed: Vec<_> = resumed.take(3).collect().await;
+        assert_eq!(replayed, vec![first, second, third]);
+
+        // Compaction discards the changes
+        assert!(db.compact("t1").await?);
+        assert!(db.subscribe_from(db::Commit(0)).await.is_err());
+        assert!(db.subscribe_from(db::Commit(3)).await.is_ok());
+        Ok(())
+    })
+}
+
+#[test]
+fn lagging_subscriptions_end() -> Result<()> {
+    use futures::StreamExt;
+
+    block_on(async {
+        let db = db::Db::open(db::DbConfig {
+            subscription_capacity: 2,
+            .. config(None)
+        }).await?;
+        let mut lagging = db.subscribe().await?;
+        let mut prompt = db.subscribe().await?;
+
+        for i in 0..4 {
+            write(&db, "k1", &i.to_string()).await?;
+            let batch = prompt.next().await.expect("change");
+            assert_eq!(batch.commit, db::Commit(i));
+        }
+
+        // The buffered commits are received, then the stream ends
+        let received: Vec<_> = lagging.by_ref().map(|batch| batch.commit).collect().await;
+        assert_eq!(received, vec![db::Commit(0), db::Commit(1)]);
+        let mut resumed = db.subscribe_from(db::Commit(2)).await?;
+        write(&db, "k1", "4").await?;
+        for i in 2..5 {
+            assert_eq!(resumed.next().await.expect("change").commit, db::Commit(i));
+        }
+
+        assert!(db::Db::open(db::DbConfig {
+            subscription_capacity: 0,
+            .. config(None)
+        }).await.is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn checkpoints_open_at_their_commit() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        let dir = temp_dir("checkpoint");
+
+        write(&db, "k1", "a").await?;
+        write(&db, "k2", "a").await?;
+        assert!(db.compact("t1").await?);
+        write(&db, "k1", "b").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1").write(b"k3", b"a").await?;
+
+        let commit = db.checkpoint(&dir).await?;
+        assert_eq!(commit, db::Commit(3));
+        batch.commit().await?;
+        batch.close().await;
+        write(&db, "k2", "b").await?;
+        assert!(db.checkpoint(&dir).await.is_err());
+
+        let copy = db::Db::open(config(Some(dir))).await?;
+        let view = copy.read_view();
+        assert_eq!(view.commit(), commit);
+        assert_eq!(read(&view, "k1").await?, Some("b".to_string()));
+        assert_eq!(read(&view, "k2").await?, Some("a".to_string()));
+        assert_eq!(read(&view, "k3").await?, None);
+        drop(view);
+
+        // The copy carries on from its commit
+        assert_eq!(copy.write_batch().await?.commit().await?, commit);
+        write(&copy, "k3", "c").await?;
+        let view = copy.read_view();
+        assert_eq!(read(&view, "k3").await?, Some("c".to_string()));
+        Ok(())
+    })
+}
+
+#[test]
+fn incremental_backup_and_restore() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        write(&db, "k1", "a").await?;
+        write(&db, "k2", "a").await?;
+
+        let mut full = vec![];
+        let commit = db.backup_since(db::Commit(0), &mut full).await?;
+        assert_eq!(commit, db::Commit(2));
+        assert_eq!(full.len(), 2);
+
+        let batch = db.write_batch().await?;
+        batch.tree("t1").delete(b"k1").await?;
+        batch.tree("t2").write(b"k3", b"b").await?;
+        batch.commit().await?;
+        batch.close().await;
+        let mut delta = vec![];
+        let commit = db.backup_since(commit, &mut delta).await?;
+        assert_eq!(commit, db::Commit(3));
+        assert_eq!(delta.len(), 1);
+        assert_eq!(delta[0].commit, db::Commit(2));
+        assert!(db.backup_since(db::Commit(4), &mut vec![]).await.is_err());
+
+        let copy = db::Db::open(config(None)).await?;
+        let restored = copy.restore(futures::stream::iter(full)).await?;
+        assert_eq!(restored, db::Commit(2));
+        let view = copy.read_view();
+        assert_eq!(read(&view, "k1").await?, Some("a".to_string()));
+        drop(view);
+
+        // The delta applies on top of the full backup, and only once
+        assert_eq!(copy.restore(futures::stream::iter(delta.clone())).await?, commit);
+        assert!(copy.restore(futures::stream::iter(delta)).await.is_err());
+        let view = copy.read_view();
+        assert_eq!(view.commit(), commit);
+        assert_eq!(read(&view, "k1").await?, None);
+        assert_eq!(view.tree("t2").read(b"k3").await?, Some(b"b".to_vec()));
+        Ok(())
+    })
+}
+
+#[test]
+fn restore_excludes_write_batches() -> Result<()> {
+    use futures::{SinkExt, StreamExt};
+
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        write(&db, "k1", "a").await?;
+        let mut backup = vec![];
+        db.backup_since(db::Commit(0), &mut backup).await?;
+
+        let copy = db::Db::open(config(None)).await?;
+        let batch = copy.write_batch().await?;
+        assert!(copy.restore(futures::stream::iter(backup.clone())).await.is_err());
+        batch.close().await;
+
+        // No batch can take the restored commits' numbers
+        let (mut tx, rx) = futures::channel::mpsc::unbounded();
+        let restore = copy.restore(rx.boxed());
+        let send = async {
+            assert!(copy.write_batch().await.is_err());
+            assert!(copy.transaction().await.is_err());
+            for batch in backup {
+                tx.send(batch).await?;
+            }
+            drop(tx);
+            Ok::<_, anyhow::Error>(())
+        };
+        let (restored, sent) = futures::join!(restore, send);
+        sent?;
+        assert_eq!(restored?, db::Commit(1));
+        assert_eq!(read(&copy.read_view(), "k1").await?, Some("a".to_string()));
+        write(&copy, "k2", "b").await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn backup_fails_for_dropped_trees() -> Result<()> {
+    block_on(async {
+        let db = db::Db::open(config(None)).await?;
+        write(&db, "k1", "a").await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t2").write(b"k2", b"a").await?;
+        batch.commit().await?;
+        batch.close().await;
+        write(&db, "k3", "a").await?;
+
+        db.drop_tree("t2").await?;
+        db.create_tree("t2").await?;
+        let mut backup = vec![];
+        assert!(db.backup_since(db::Commit(0), &mut backup).await.is_err());
+        assert!(backup.is_empty());
+
+        // Until the backup starts after the tree's commits
+        assert_eq!(db.backup_since(db::Commit(2), &mut backup).await?, db::Commit(3));
+        assert_eq!(backup.len(), 1);
+        assert_eq!(backup[0].commit, db::Commit(2));
+        Ok(())
+    })
+}
+
+#[test]
+fn restore_continues_after_reopen() -> Result<()> {
+    let dir = temp_dir("restore_continues_after_reopen");
+    let change_batch = |commit, change| db::ChangeBatch {
+        commit: db::Commit(commit),
+        trees: std::iter::once(("t1".to_string(), vec![change])).collect(),
+    };
+    let write_change = |key: &str| db::Change::Write { key: key.into(), value: b"v".to_vec() };
+    block_on(async {
+        {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            // A merge into a tree with no merge operator fails
+            let backup = vec![
+                change_batch(5, write_change("k5")),
+                change_batch(9, write_change("k9")),
+                change_batch(12, db::Change::Merge { key: b"k12".to_vec(), operand: b"v".to_vec() }),
+            ];
+            assert!(db.restore(futures::stream::iter(backup)).await.is_err());
+            // The failed batch's number isn't skipped
+            assert_eq!(db.read_view().commit(), db::Commit(10));
+            db.sync().await?;
+        }
+
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        assert_eq!(db.read_view().commit(), db::Commit(10));
+        let backup = vec![change_batch(12, write_change("k12"))];
+        assert_eq!(db.restore(futures::stream::iter(backup)).await?, db::Commit(13));
+        let view = db.read_view();
+        assert_eq!(read(&view, "k9").await?, Some("v".to_string()));
+        assert_eq!(read(&view, "k12").await?, Some("v".to_string()));
+        assert_eq!(read(&db.read_view_at(db::Commit(12))?, "k12").await?, None);
+        drop(view);
+        write(&db, "k13", "v").await?;
+        assert_eq!(db.read_view().commit(), db::Commit(14));
+        Ok(())
+    })
+}
+
+#[test]
+fn rollback_to_earlier_commit() -> Result<()> {
+    block_on(async {
+        let dir = temp_dir("rollback");
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        write(&db, "k1", "a").await?;
+        write(&db, "k1", "b").await?;
+        assert!(db.compact("t1").await?);
+        let batch = db.write_batch().await?;
+        batch.tree("t1").write(b"k3", b"a").await?;
+        write(&db, "k2", "a").await?;
+        write(&db, "k1", "c").await?;
+        assert_eq!(batch.commit().await?, db::Commit(4));
+        batch.close().await;
+        db.sync().await?;
+        drop(db);
+
+        let open_at = |commit, truncate| {
+            let mut config = config(Some(dir.clone()));
+            config.rollback_to = Some(db::Commit(commit));
+            config.truncate_rollback = truncate;
+            db::Db::open(config)
+        };
+
+        // Without truncation the later commits are kept, read-only
+        let db = open_at(4, false).await?;
+        let view = db.read_view();
+        assert_eq!(view.commit(), db::Commit(4));
+        assert_eq!(read(&view, "k1").await?, Some("c".to_string()));
+        assert_eq!(read(&view, "k3").await?, None);
+        assert!(db.write_batch().await.is_err());
+        drop(view);
+        drop(db);
+
+        assert!(open_at(1, true).await.is_err());
+
+        let db = open_at(3, true).await?;
+        let view = db.read_view();
+        assert_eq!(view.commit(), db::Commit(3));
+        assert_eq!(read(&view, "k1").await?, Some("b".to_string()));
+        assert_eq!(read(&view, "k2").await?, Some("a".to_string()));
+        drop(view);
+        write(&db, "k3", "b").await?;
+        db.sync().await?;
+        drop(db);
+
+        let db = db::Db::open(config(Some(dir))).await?;
+        let view = db.read_view();
+        assert_eq!(view.commit(), db::Commit(4));
+        assert_eq!(read(&view, "k1").await?, Some("b".to_string()));
+        assert_eq!(read(&view, "k3").await?, Some("b".to_string()));
+        Ok(())
+    })
+}
+
+#[test]
+fn verify_reports_log_problems() -> Result<()> {
+    block_on(async {
+        let dir = temp_dir("verify");
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        write(&db, "k1", "a").await?;
+        let batch = db.write_batch().await?;
+        batch.push_save_point().await?;
+        batch.tree("t2").write(b"k2", b"a").await?;
+        batch.rollback_save_point().await?;
+        batch.commit().await?;
+        batch.close().await;
+        db.sync().await?;
+        drop(db);
+
+        let report = db::verify(&config(Some(dir.clone()))).await?;
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.commits, 2);
+        assert_eq!(report.logs, 2);
+
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        let batch = db.write_batch().await?;
+        batch.tree("t1").write(b"k3", b"a").await?;
+        db.sync().await?;
+        drop(batch);
+        drop(db);
+        std::fs::remove_file(dir.join("t2").join("0.0.toml"))?;
+
+        let report = db::verify(&config(Some(dir.clone()))).await?;
+        assert_eq!(report.problems, vec![
+            db::VerifyProblem::UnclosedBatch { log: "t1/0".to_string(), batch: 3 },
+            db::VerifyProblem::CommitPastLogEnd { commit: db::Commit(1), tree: "t2".to_string() },
+        ]);
+
+        let mut log = std::fs::OpenOptions::new().append(true).open(dir.join("t1").join("0.0.toml"))?;
+        std::io::Write::write_all(&mut log, b"junk")?;
+        drop(log);
+        let report = db::verify(&config(Some(dir))).await?;
+        assert!(matches!(report.problems[0],
+                         db::VerifyProblem::Unreadable { records: 6, .. }),
+                "{}", report);
+        Ok(())
+    })
+}
+
+#[test]
+fn verify_reads_logs_only() -> Result<()> {
+    block_on(async {
+        let dir = temp_dir("verify-read-only");
+        assert!(db::verify(&config(Some(dir.clone()))).await.is_err());
+        assert!(!dir.exists());
+
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        write(&db, "k1", "a").await?;
+        db.sync().await?;
+        let t1_log = dir.join("t1").join("0.0.toml");
+        let len = std::fs::metadata(&t1_log)?.len();
+        write(&db, "k2", "a").await?;
+        write(&db, "k3", "a").await?;
+        db.sync().await?;
+        drop(db);
+
+        // Commits the tree's log ends before
+        std::fs::OpenOptions::new().write(true).open(&t1_log)?.set_len(len)?;
+        let report = db::verify(&config(Some(dir.clone()))).await?;
+        assert_eq!(report.problems, vec![
+            db::VerifyProblem::CommitPastLogEnd { commit: db::Commit(1), tree: "t1".to_string() },
+            db::VerifyProblem::CommitPastLogEnd { commit: db::Commit(2), tree: "t1".to_string() },
+        ]);
+
+        // Missing segments and trees fail rather than being skipped or created
+        let dir = temp_dir("verify-missing-logs");
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        for i in 0..20 {
+            write(&db, "k1", &i.to_string()).await?;
+        }
+        db.sync().await?;
+        drop(db);
+        assert!(db::verify(&config(Some(dir.clone()))).await?.is_ok());
+        assert!(dir.join("commits.1.toml").exists());
+        std::fs::rename(dir.join("commits.0.toml"), dir.join("commits.bak"))?;
+        assert!(db::verify(&config(Some(dir.clone()))).await.is_err());
+        assert!(!dir.join("commits.0.toml").exists());
+        std::fs::rename(dir.join("commits.bak"), dir.join("commits.0.toml"))?;
+        std::fs::rename(dir.join("t2"), dir.join("t2.bak"))?;
+        assert!(db::verify(&config(Some(dir.clone()))).await.is_err());
+        assert!(!dir.join("t2").exists());
+        Ok(())
+    })
+}
+
+#[test]
+fn index_checkpoints_resume_replay() -> Result<()> {
+    block_on(async {
+        let dir = temp_dir("index-checkpoints");
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        write(&db, "k1", "a").await?;
+        let open_batch = db.write_batch().await?;
+        open_batch.tree("t1").write(b"k2", b"a").await?;
+        open_batch.tree("t2").delete_range(b"a", b"z").await?;
+        open_batch.commit().await?;
+        let uncommitted = db.write_batch().await?;
+        uncommitted.tree("t1").write(b"k3", b"a").await?;
+        assert_eq!(db.checkpoint_indexes().await?, db::Commit(2));
+
+        // Batches open across the checkpoint keep committing
+        open_batch.tree("t1").write(b"k2", b"b").await?;
+        open_batch.commit().await?;
+        open_batch.close().await;
+        uncommitted.commit().await?;
+        uncommitted.close().await;
+        write(&db, "k1", "b").await?;
+        db.sync().await?;
+        drop(db);
+
+        let check = |db: db::Db| async move {
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("b".to_string()));
+            assert_eq!(read(&view, "k2").await?, Some("b".to_string()));
+            assert_eq!(read(&view, "k3").await?, Some("a".to_string()));
+            let view = db.read_view_at(db::Commit(2))?;
+            assert_eq!(read(&view, "k1").await?, Some("a".to_string()));
+            assert_eq!(read(&view, "k2").await?, Some("a".to_string()));
+            assert_eq!(read(&view, "k3").await?, None);
+            Ok::<_, anyhow::Error>(db)
+        };
+
+        let db = check(db::Db::open(config(Some(dir.clone()))).await?).await?;
+        write(&db, "k4", "a").await?;
+        db.sync().await?;
+        drop(db);
+
+        let mut periodic = config(Some(dir.clone()));
+        periodic.index_checkpoint_interval = Some(7);
+        let db = db::Db::open(periodic.clone()).await?;
+        assert_eq!(read(&db.read_view(), "k4").await?, Some("a".to_string()));
+        write(&db, "k4", "b").await?;
+        db.sync().await?;
+        drop(db);
+        let db = db::Db::open(periodic).await?;
+        assert_eq!(read(&db.read_view(), "k4").await?, Some("b".to_string()));
+        drop(db);
+
+        // A corrupt checkpoint falls back to replaying everything
+        std::fs::write(dir.join("indexes.0.toml"), b"junk")?;
+        let db = check(db::Db::open(config(Some(dir))).await?).await?;
+        assert_eq!(read(&db.read_view(), "k4").await?, Some("b".to_string()));
+        Ok(())
+    })
+}
+
+#[test]
+fn unloadable_index_checkpoint_replays_logs() -> Result<()> {
+    block_on(async {
+        let dir = temp_dir("unloadable-index-checkpoint");
+        let db = db::Db::open(config(Some(dir.clone()))).await?;
+        write(&db, "k1", "a").await?;
+        write(&db, "k2", "a").await?;
+        db.checkpoint_indexes().await?;
+        write(&db, "k3", "a").await?;
+        db.sync().await?;
+        drop(db);
+
+        // Readable, but resuming the tree log from the middle of a record
+        let path = dir.join("indexes.0.toml");
+        let checkpoint = std::fs::read_to_string(&path)?;
+        let last = "[trees.checkpoint.last]\nsegment = 0\noffset = ";
+        let start = checkpoint.find(last).expect("last") + last.len();
+        let end = start + checkpoint[start..].find('\n').expect("newline");
+        let offset: u64 = checkpoint[start..end].parse()?;
+        let bad_offset = (offset - 1).to_string();
+        assert_eq!(bad_offset.len(), end - start);
+        std::fs::write(&path, format!("{}{}{}", &checkpoint[..start], bad_offset, &checkpoint[end..]))?;
+
+        for _ in 0..2 {
+            let db = db::Db::open(config(Some(dir.clone()))).await?;
+            let view = db.read_view();
+            assert_eq!(read(&view, "k1").await?, Some("a".to_string()));
+            assert_eq!(read(&view, "k3").await?, Some("a".to_string()));
+            assert_eq!(std::fs::metadata(&path)?.len(), 0);
+        }
+        Ok(())
+    })
+}